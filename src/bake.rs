@@ -0,0 +1,230 @@
+use depth;
+use image;
+use model;
+use pipeline;
+use shadow;
+use vec;
+
+use std::f64;
+
+// Where the occlusion is computed before it is written to the texture
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+	// for each texel of the UV map
+	Texel,
+	// for each vertex, and interpolated across the faces
+	Vertex,
+}
+
+impl Mode {
+	pub fn from_name(name: &str) -> Option<Mode> {
+		match name {
+			"texel" => Some(Mode::Texel),
+			"vertex" => Some(Mode::Vertex),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+	pub mode: Mode,
+	// width and height of the AO texture
+	pub size: usize,
+	// number of directions on the sphere to render the depth from
+	pub directions: usize,
+	// width and height of the depth map for each direction
+	pub depth_size: usize,
+	// texels to extend the result past the edges of the UV islands, to hide seams
+	pub padding: usize,
+}
+
+impl Default for Settings {
+	fn default() -> Self {
+		Settings {
+			mode: Mode::Texel,
+			size: 512,
+			directions: 64,
+			depth_size: 512,
+			padding: 4,
+		}
+	}
+}
+
+// Bake ambient occlusion for a model into a grayscale texture in its UV space.
+// The model is rendered with the depth pass from directions spread evenly over a sphere,
+// and each point accumulates the cosine weighted visibility of the directions above it.
+pub fn ambient_occlusion(settings: &Settings, model: &model::Model) -> image::Image {
+	let size = settings.size;
+	let directions = sphere_directions(settings.directions);
+
+	let mut image = image::Image::new(size, size, image::Format::Gray);
+	image.set_color_space(image::ColorSpace::Linear);
+	let viewport = &vec::viewport(0f64, 0f64, 0f64, size as f64, size as f64, 1f64);
+	match settings.mode {
+		Mode::Texel => {
+			let positions = &uv_render(model, viewport, size, |face, idx| vec::Vec3(model.vert(face, idx).0));
+			let normals = &uv_render(model, viewport, size, |face, idx| vec::Vec3(model.normal(face, idx).0));
+			let points: Vec<(vec::Vec3<f64>, vec::Vec3<f64>)> = (0 .. size * size).filter_map(|i| {
+				let (x, y) = (i % size, i / size);
+				let p = positions.get_f(x, y);
+				if p.a == 0f32 {
+					return None;
+				}
+				let n = normals.get_f(x, y);
+				Some((vec::Vec3::new(p.r as f64, p.g as f64, p.b as f64), vec::Vec3::new(n.r as f64, n.g as f64, n.b as f64)))
+			}).collect();
+			let ao = occlusion(settings, model, &directions, &points);
+			let mut ao = ao.iter();
+			for y in 0 .. size {
+				for x in 0 .. size {
+					if positions.get_f(x, y).a != 0f32 {
+						let a = *ao.next().unwrap();
+						image.set_f(x, y, &image::ColorF::new(a, a, a, 1f32));
+					}
+				}
+			}
+			dilate(&mut image, &|x, y| positions.get_f(x, y).a != 0f32, settings.padding);
+		},
+		Mode::Vertex => {
+			let points: Vec<(vec::Vec3<f64>, vec::Vec3<f64>)> = (0 .. model.nfaces() * 3).map(|i| {
+				(vec::Vec3(model.vert(i / 3, i % 3).0), vec::Vec3(model.normal(i / 3, i % 3).0))
+			}).collect();
+			let ao = &occlusion(settings, model, &directions, &points);
+			let colors = &uv_render(model, viewport, size, |face, idx| {
+				let a = ao[face * 3 + idx] as f64;
+				vec::Vec3::new(a, a, a)
+			});
+			let covered = |x, y| colors.get_f(x, y).a != 0f32;
+			for y in 0 .. size {
+				for x in 0 .. size {
+					if covered(x, y) {
+						image.set_f(x, y, &colors.get_f(x, y));
+					}
+				}
+			}
+			dilate(&mut image, &covered, settings.padding);
+		},
+	}
+	image
+}
+
+// Visibility of each point, given as a position and normal in world space
+fn occlusion(settings: &Settings, model: &model::Model, directions: &[vec::Vec3<f64>],
+	     points: &[(vec::Vec3<f64>, vec::Vec3<f64>)]) -> Vec<f32> {
+	let mut visible = vec![0f64; points.len()];
+	let mut total = vec![0f64; points.len()];
+	for direction in directions {
+		let mut shadow = shadow::ShadowMap::new(settings.depth_size, direction, &[(model, None)]);
+		shadow.pcf = 1;
+		for (i, (p, n)) in points.iter().enumerate() {
+			let mut n = vec::Vec3(n.0);
+			let n = n.normalize();
+			let cos = n.dot(direction);
+			if cos <= 0f64 {
+				continue;
+			}
+			// A small triangle in the tangent plane, for the slope scaled bias
+			let t = &tangent(&n).scale(1e-3);
+			let b = &n.cross(t);
+			let triangle = &mut vec::Mat3::default();
+			triangle.set_row(0, &p.transform_pt(&shadow.transform));
+			triangle.set_row(1, &p.add(t).transform_pt(&shadow.transform));
+			triangle.set_row(2, &p.add(b).transform_pt(&shadow.transform));
+			visible[i] += cos * shadow.visibility(&vec::Vec3(triangle.0[0]), triangle);
+			total[i] += cos;
+		}
+	}
+	visible.iter().zip(total.iter()).map(|(&visible, &total)| {
+		if total > 0f64 { (visible / total) as f32 } else { 1f32 }
+	}).collect()
+}
+
+// Render the model into its UV space, storing a value interpolated from the vertices.
+// The value function is given the face and the vertex index within the face.
+// Alpha is zero for texels which are not covered.
+fn uv_render<F>(model: &model::Model, viewport: &vec::Transform4<f64>, size: usize, value: F) -> image::Image
+		where F: Fn(usize, usize) -> vec::Vec3<f64> {
+	let mut image = image::Image::new(size, size, image::Format::RgbaF32);
+	let mut zbuffer = depth::DepthBuffer::new(size, size, 1, depth::Format::F32);
+	let shader = UvShader;
+	let mut pipeline = pipeline::Pipeline::new(&mut image, &mut zbuffer, viewport);
+	for face in 0 .. model.nfaces() {
+		let v: Vec<image::Vertex<vec::Vec3<f64>>> = (0 .. 3).map(|idx| {
+			// the value is per face vertex, so it replaces the varyings
+			let (position, _) = image::Shader::vertex(&shader, model.vert(face, idx), model.uv(face, idx), model.normal(face, idx));
+			(position, value(face, idx))
+		}).collect();
+		pipeline.draw_triangle(&shader, face, &v[0], &v[1], &v[2]);
+	}
+	image
+}
+
+// Positions vertices by their texture coordinates, and outputs the interpolated value
+struct UvShader;
+
+impl image::Shader for UvShader {
+	type Varyings = vec::Vec3<f64>;
+	type Output = image::ColorF;
+
+	fn vertex(&self, _vert: &vec::Vec3<f64>, uv: &vec::Vec3<f64>, _normal: &vec::Vec3<f64>) -> image::Vertex<vec::Vec3<f64>> {
+		(vec::Vec4([ uv.0[0] * 2f64 - 1f64, uv.0[1] * 2f64 - 1f64, 0f64, 1f64 ]), vec::Vec3::default())
+	}
+
+	fn fragment(&self, fragment: &image::Fragment<vec::Vec3<f64>>) -> Option<image::ColorF> {
+		let (r, g, b) = fragment.varyings.as_tuple();
+		Some(image::ColorF::new(r as f32, g as f32, b as f32, 1f32))
+	}
+}
+
+// Directions spread evenly over the sphere, using a Fibonacci lattice
+fn sphere_directions(n: usize) -> Vec<vec::Vec3<f64>> {
+	let golden = f64::consts::PI * (3f64 - 5f64.sqrt());
+	(0 .. n).map(|i| {
+		let y = 1f64 - 2f64 * (i as f64 + 0.5f64) / n as f64;
+		let r = (1f64 - y * y).sqrt();
+		let phi = golden * i as f64;
+		vec::Vec3::new(r * phi.cos(), y, r * phi.sin())
+	}).collect()
+}
+
+fn tangent(n: &vec::Vec3<f64>) -> vec::Vec3<f64> {
+	let axis = if n.0[0].abs() < 0.9f64 {
+		vec::Vec3::new(1f64, 0f64, 0f64)
+	} else {
+		vec::Vec3::new(0f64, 1f64, 0f64)
+	};
+	n.cross(&axis).normalize()
+}
+
+// Copy covered texels into uncovered neighbours, one texel per iteration
+fn dilate<F: Fn(usize, usize) -> bool>(image: &mut image::Image, covered: &F, iterations: usize) {
+	let width = image.get_width();
+	let height = image.get_height();
+	let mut mask: Vec<bool> = (0 .. width * height).map(|i| covered(i % width, i / width)).collect();
+	for _ in 0 .. iterations {
+		let mut next = mask.clone();
+		for y in 0 .. height {
+			for x in 0 .. width {
+				if mask[x + y * width] {
+					continue;
+				}
+				let mut sum = 0f32;
+				let mut count = 0;
+				for (dx, dy) in [(-1i64, 0i64), (1, 0), (0, -1), (0, 1)] {
+					let (sx, sy) = (x as i64 + dx, y as i64 + dy);
+					if sx >= 0 && sy >= 0 && sx < width as i64 && sy < height as i64 && mask[sx as usize + sy as usize * width] {
+						sum += image.get_f(sx as usize, sy as usize).r;
+						count += 1;
+					}
+				}
+				if count > 0 {
+					let a = sum / count as f32;
+					image.set_f(x, y, &image::ColorF::new(a, a, a, 1f32));
+					next[x + y * width] = true;
+				}
+			}
+		}
+		mask = next;
+	}
+}
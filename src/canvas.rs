@@ -0,0 +1,514 @@
+use image;
+use vec;
+
+use std::cmp;
+use std::f64;
+
+// Sample rows per pixel row when filling polygons
+const FILL_SAMPLES: usize = 4;
+// Largest distance in pixels between an ellipse and the polygon which approximates it
+const ELLIPSE_TOLERANCE: f64 = 0.1f64;
+
+// Size of the glyphs in the font, in pixels at a scale of 1
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+// Distance between the origins of glyphs and lines
+const ADVANCE: usize = GLYPH_WIDTH + 1;
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 2;
+
+// 2D drawing of anti-aliased shapes and bitmap text over an image.
+// Pixel centers are at integer coordinates, and y is up.
+// Colors are alpha blended, and each shape covers a pixel at most once.
+pub struct Canvas<'a> {
+	image: &'a mut image::Image,
+}
+
+impl<'a> Canvas<'a> {
+	pub fn new(image: &'a mut image::Image) -> Self {
+		Canvas {
+			image,
+		}
+	}
+
+	// Fill the inside of a polygon, using the nonzero winding rule so that
+	// overlapping parts are filled once
+	pub fn fill_polygon(&mut self, points: &[vec::Vec2<f64>], color: &image::ColorF) {
+		let mut mask = match Mask::new(self.image, points, 0f64) {
+			Some(mask) => mask,
+			None => return,
+		};
+		let mut crossings = Vec::new();
+		for y in mask.y .. mask.y + mask.height {
+			for sample in 0 .. FILL_SAMPLES {
+				let sy = y as f64 - 0.5f64 + (sample as f64 + 0.5f64) / FILL_SAMPLES as f64;
+				// the x and direction of each edge crossing the sample row
+				crossings.clear();
+				for i in 0 .. points.len() {
+					let (x0, y0) = points[i].as_tuple();
+					let (x1, y1) = points[(i + 1) % points.len()].as_tuple();
+					if (y0 <= sy) != (y1 <= sy) {
+						let x = x0 + (sy - y0) * (x1 - x0) / (y1 - y0);
+						crossings.push((x, if y1 > y0 { 1 } else { -1 }));
+					}
+				}
+				crossings.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(cmp::Ordering::Equal));
+				let mut winding = 0;
+				for i in 0 .. crossings.len() {
+					winding += crossings[i].1;
+					if winding != 0 && i + 1 < crossings.len() {
+						mask.span(y, crossings[i].0, crossings[i + 1].0, 1f32 / FILL_SAMPLES as f32);
+					}
+				}
+			}
+		}
+		mask.blend(self.image, color);
+	}
+
+	// Stroke the edges of a polygon, including the edge from the last point to the first.
+	// The edges meet with round joins, and the dashes continue around the polygon.
+	#[allow(dead_code)]
+	pub fn stroke_polygon(&mut self, points: &[vec::Vec2<f64>], color: &image::ColorF, style: &image::LineStyle) {
+		self.stroke_path(points, true, color, style);
+	}
+
+	// Stroke a line through the points, with the caps of the style at the ends
+	// and round joins between the segments
+	#[allow(dead_code)]
+	pub fn stroke_polyline(&mut self, points: &[vec::Vec2<f64>], color: &image::ColorF, style: &image::LineStyle) {
+		self.stroke_path(points, false, color, style);
+	}
+
+	// Fill the rectangle between two corners
+	pub fn fill_rect(&mut self, min: &vec::Vec2<f64>, max: &vec::Vec2<f64>, color: &image::ColorF) {
+		self.fill_polygon(&rect_points(min, max), color);
+	}
+
+	#[allow(dead_code)]
+	pub fn stroke_rect(&mut self, min: &vec::Vec2<f64>, max: &vec::Vec2<f64>, color: &image::ColorF, style: &image::LineStyle) {
+		self.stroke_polygon(&rect_points(min, max), color, style);
+	}
+
+	#[allow(dead_code)]
+	pub fn fill_circle(&mut self, center: &vec::Vec2<f64>, radius: f64, color: &image::ColorF) {
+		self.fill_ellipse(center, radius, radius, color);
+	}
+
+	#[allow(dead_code)]
+	pub fn stroke_circle(&mut self, center: &vec::Vec2<f64>, radius: f64, color: &image::ColorF, style: &image::LineStyle) {
+		self.stroke_ellipse(center, radius, radius, color, style);
+	}
+
+	// Fill an axis aligned ellipse with the radii in x and y
+	#[allow(dead_code)]
+	pub fn fill_ellipse(&mut self, center: &vec::Vec2<f64>, rx: f64, ry: f64, color: &image::ColorF) {
+		self.fill_polygon(&ellipse_points(center, rx, ry), color);
+	}
+
+	#[allow(dead_code)]
+	pub fn stroke_ellipse(&mut self, center: &vec::Vec2<f64>, rx: f64, ry: f64, color: &image::ColorF, style: &image::LineStyle) {
+		self.stroke_polygon(&ellipse_points(center, rx, ry), color, style);
+	}
+
+	// Draw text with the top left pixel of the first glyph at the position.
+	// Each pixel of the font is drawn as a square of scale pixels, and lines are separated by newlines.
+	// Characters which are not in the font are drawn as '?'.
+	#[allow(clippy::needless_range_loop)]
+	pub fn text(&mut self, position: &vec::Vec2<f64>, text: &str, color: &image::ColorF, scale: usize) {
+		let (x0, y0) = (position.0[0].round(), position.0[1].round());
+		for (line, chars) in text.split('\n').enumerate() {
+			let y = y0 - (line * LINE_HEIGHT * scale) as f64;
+			for (i, c) in chars.chars().enumerate() {
+				let x = x0 + (i * ADVANCE * scale) as f64;
+				let glyph = glyph(c);
+				for col in 0 .. GLYPH_WIDTH {
+					for row in 0 .. GLYPH_HEIGHT {
+						if glyph[col] & (1 << row) == 0 {
+							continue;
+						}
+						let px = x + (col * scale) as f64;
+						let py = y - (row * scale) as f64;
+						for dy in 0 .. scale {
+							for dx in 0 .. scale {
+								self.image.blend_coverage(px + dx as f64, py - dy as f64, color, 1f32);
+							}
+						}
+					}
+				}
+			}
+		}
+	}
+
+	fn stroke_path(&mut self, points: &[vec::Vec2<f64>], closed: bool, color: &image::ColorF, style: &image::LineStyle) {
+		let mut mask = match Mask::new(self.image, points, style.width) {
+			Some(mask) => mask,
+			None => return,
+		};
+		let size = (self.image.get_width(), self.image.get_height());
+		image::dash_path(points, closed, &style.dashes, |a, b, start_join, end_join| {
+			// a round cap at a join overlaps the cap of the other piece, so that there is no gap
+			let cap = |join: bool| if join { image::LineCap::Round } else { style.cap };
+			let caps = (cap(start_join), cap(end_join));
+			image::stroke_coverage(a, b, style.width, caps, size, |x, y, coverage| mask.max(x, y, coverage));
+		});
+		mask.blend(self.image, color);
+	}
+}
+
+// Size in pixels of text drawn at the scale
+pub fn text_size(text: &str, scale: usize) -> (usize, usize) {
+	let lines = text.split('\n').count();
+	let columns = text.split('\n').map(|line| line.chars().count()).max().unwrap_or(0);
+	let width = if columns == 0 { 0 } else { columns * ADVANCE - 1 };
+	(width * scale, ((lines - 1) * LINE_HEIGHT + GLYPH_HEIGHT) * scale)
+}
+
+fn rect_points(min: &vec::Vec2<f64>, max: &vec::Vec2<f64>) -> [vec::Vec2<f64>; 4] {
+	[
+		vec::Vec2::new(min.0[0], min.0[1]),
+		vec::Vec2::new(max.0[0], min.0[1]),
+		vec::Vec2::new(max.0[0], max.0[1]),
+		vec::Vec2::new(min.0[0], max.0[1]),
+	]
+}
+
+// Polygon with enough points to be within the tolerance of the ellipse
+fn ellipse_points(center: &vec::Vec2<f64>, rx: f64, ry: f64) -> Vec<vec::Vec2<f64>> {
+	let radius = rx.abs().max(ry.abs());
+	let n = if radius <= ELLIPSE_TOLERANCE {
+		8
+	} else {
+		// the distance from the middle of each edge to the circle is r (1 - cos(angle / 2))
+		let angle = 2f64 * (1f64 - ELLIPSE_TOLERANCE / radius).acos();
+		((2f64 * f64::consts::PI / angle).ceil() as usize).clamp(8, 1024)
+	};
+	(0 .. n).map(|i| {
+		let angle = 2f64 * f64::consts::PI * i as f64 / n as f64;
+		vec::Vec2::new(center.0[0] + rx * angle.cos(), center.0[1] + ry * angle.sin())
+	}).collect()
+}
+
+// Coverage of the pixels inside a rectangle of the image, which is accumulated
+// for a shape and then blended once
+struct Mask {
+	x: usize,
+	y: usize,
+	width: usize,
+	height: usize,
+	coverage: Vec<f32>,
+}
+
+impl Mask {
+	// Covers the points and the pixels within the margin of them, inside the image
+	fn new(image: &image::Image, points: &[vec::Vec2<f64>], margin: f64) -> Option<Mask> {
+		if points.is_empty() {
+			return None;
+		}
+		let (mut min, mut max) = ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN));
+		for point in points {
+			let (x, y) = point.as_tuple();
+			min = (min.0.min(x), min.1.min(y));
+			max = (max.0.max(x), max.1.max(y));
+		}
+		let extent = margin / 2f64 + 1f64;
+		let x0 = (min.0 - extent).floor().max(0f64);
+		let y0 = (min.1 - extent).floor().max(0f64);
+		let x1 = (max.0 + extent).ceil().min(image.get_width() as f64 - 1f64);
+		let y1 = (max.1 + extent).ceil().min(image.get_height() as f64 - 1f64);
+		if !(x0 <= x1 && y0 <= y1) {
+			return None;
+		}
+		let (width, height) = ((x1 - x0) as usize + 1, (y1 - y0) as usize + 1);
+		Some(Mask {
+			x: x0 as usize,
+			y: y0 as usize,
+			width,
+			height,
+			coverage: vec![0f32; width * height],
+		})
+	}
+
+	fn max(&mut self, x: usize, y: usize, coverage: f32) {
+		if x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height {
+			let i = (x - self.x) + (y - self.y) * self.width;
+			self.coverage[i] = self.coverage[i].max(coverage);
+		}
+	}
+
+	// Add the weighted overlap of each pixel in a row with the span from x0 to x1
+	fn span(&mut self, y: usize, x0: f64, x1: f64, weight: f32) {
+		let start = (x0 + 0.5f64).floor().max(self.x as f64);
+		let end = (x1 + 0.5f64).ceil().min((self.x + self.width) as f64);
+		let mut x = start;
+		while x < end {
+			let overlap = (x1.min(x + 0.5f64) - x0.max(x - 0.5f64)).max(0f64);
+			let i = (x as usize - self.x) + (y - self.y) * self.width;
+			self.coverage[i] = (self.coverage[i] + overlap as f32 * weight).min(1f32);
+			x += 1f64;
+		}
+	}
+
+	fn blend(&self, image: &mut image::Image, color: &image::ColorF) {
+		for y in 0 .. self.height {
+			for x in 0 .. self.width {
+				let coverage = self.coverage[x + y * self.width];
+				image.blend_coverage((self.x + x) as f64, (self.y + y) as f64, color, coverage);
+			}
+		}
+	}
+}
+
+// Columns of a 5x7 glyph, with the top row in the lowest bit
+fn glyph(c: char) -> &'static [u8; GLYPH_WIDTH] {
+	let c = if (' ' ..= '~').contains(&c) { c } else { '?' };
+	&FONT[c as usize - ' ' as usize]
+}
+
+// Printable ASCII characters, from space to tilde
+const FONT: [[u8; GLYPH_WIDTH]; 95] = [
+	[0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+	[0x00, 0x00, 0x5f, 0x00, 0x00], // '!'
+	[0x00, 0x07, 0x00, 0x07, 0x00], // '"'
+	[0x14, 0x7f, 0x14, 0x7f, 0x14], // '#'
+	[0x24, 0x2a, 0x7f, 0x2a, 0x12], // '$'
+	[0x23, 0x13, 0x08, 0x64, 0x62], // '%'
+	[0x36, 0x49, 0x55, 0x22, 0x50], // '&'
+	[0x00, 0x05, 0x03, 0x00, 0x00], // '''
+	[0x00, 0x1c, 0x22, 0x41, 0x00], // '('
+	[0x00, 0x41, 0x22, 0x1c, 0x00], // ')'
+	[0x14, 0x08, 0x3e, 0x08, 0x14], // '*'
+	[0x08, 0x08, 0x3e, 0x08, 0x08], // '+'
+	[0x00, 0x50, 0x30, 0x00, 0x00], // ','
+	[0x08, 0x08, 0x08, 0x08, 0x08], // '-'
+	[0x00, 0x60, 0x60, 0x00, 0x00], // '.'
+	[0x20, 0x10, 0x08, 0x04, 0x02], // '/'
+	[0x3e, 0x51, 0x49, 0x45, 0x3e], // '0'
+	[0x00, 0x42, 0x7f, 0x40, 0x00], // '1'
+	[0x42, 0x61, 0x51, 0x49, 0x46], // '2'
+	[0x21, 0x41, 0x45, 0x4b, 0x31], // '3'
+	[0x18, 0x14, 0x12, 0x7f, 0x10], // '4'
+	[0x27, 0x45, 0x45, 0x45, 0x39], // '5'
+	[0x3c, 0x4a, 0x49, 0x49, 0x30], // '6'
+	[0x01, 0x71, 0x09, 0x05, 0x03], // '7'
+	[0x36, 0x49, 0x49, 0x49, 0x36], // '8'
+	[0x06, 0x49, 0x49, 0x29, 0x1e], // '9'
+	[0x00, 0x36, 0x36, 0x00, 0x00], // ':'
+	[0x00, 0x56, 0x36, 0x00, 0x00], // ';'
+	[0x08, 0x14, 0x22, 0x41, 0x00], // '<'
+	[0x14, 0x14, 0x14, 0x14, 0x14], // '='
+	[0x00, 0x41, 0x22, 0x14, 0x08], // '>'
+	[0x02, 0x01, 0x51, 0x09, 0x06], // '?'
+	[0x32, 0x49, 0x79, 0x41, 0x3e], // '@'
+	[0x7e, 0x11, 0x11, 0x11, 0x7e], // 'A'
+	[0x7f, 0x49, 0x49, 0x49, 0x36], // 'B'
+	[0x3e, 0x41, 0x41, 0x41, 0x22], // 'C'
+	[0x7f, 0x41, 0x41, 0x22, 0x1c], // 'D'
+	[0x7f, 0x49, 0x49, 0x49, 0x41], // 'E'
+	[0x7f, 0x09, 0x09, 0x09, 0x01], // 'F'
+	[0x3e, 0x41, 0x49, 0x49, 0x7a], // 'G'
+	[0x7f, 0x08, 0x08, 0x08, 0x7f], // 'H'
+	[0x00, 0x41, 0x7f, 0x41, 0x00], // 'I'
+	[0x20, 0x40, 0x41, 0x3f, 0x01], // 'J'
+	[0x7f, 0x08, 0x14, 0x22, 0x41], // 'K'
+	[0x7f, 0x40, 0x40, 0x40, 0x40], // 'L'
+	[0x7f, 0x02, 0x0c, 0x02, 0x7f], // 'M'
+	[0x7f, 0x04, 0x08, 0x10, 0x7f], // 'N'
+	[0x3e, 0x41, 0x41, 0x41, 0x3e], // 'O'
+	[0x7f, 0x09, 0x09, 0x09, 0x06], // 'P'
+	[0x3e, 0x41, 0x51, 0x21, 0x5e], // 'Q'
+	[0x7f, 0x09, 0x19, 0x29, 0x46], // 'R'
+	[0x46, 0x49, 0x49, 0x49, 0x31], // 'S'
+	[0x01, 0x01, 0x7f, 0x01, 0x01], // 'T'
+	[0x3f, 0x40, 0x40, 0x40, 0x3f], // 'U'
+	[0x1f, 0x20, 0x40, 0x20, 0x1f], // 'V'
+	[0x3f, 0x40, 0x38, 0x40, 0x3f], // 'W'
+	[0x63, 0x14, 0x08, 0x14, 0x63], // 'X'
+	[0x07, 0x08, 0x70, 0x08, 0x07], // 'Y'
+	[0x61, 0x51, 0x49, 0x45, 0x43], // 'Z'
+	[0x00, 0x7f, 0x41, 0x41, 0x00], // '['
+	[0x02, 0x04, 0x08, 0x10, 0x20], // '\'
+	[0x00, 0x41, 0x41, 0x7f, 0x00], // ']'
+	[0x04, 0x02, 0x01, 0x02, 0x04], // '^'
+	[0x40, 0x40, 0x40, 0x40, 0x40], // '_'
+	[0x00, 0x01, 0x02, 0x04, 0x00], // '`'
+	[0x20, 0x54, 0x54, 0x54, 0x78], // 'a'
+	[0x7f, 0x48, 0x44, 0x44, 0x38], // 'b'
+	[0x38, 0x44, 0x44, 0x44, 0x20], // 'c'
+	[0x38, 0x44, 0x44, 0x48, 0x7f], // 'd'
+	[0x38, 0x54, 0x54, 0x54, 0x18], // 'e'
+	[0x08, 0x7e, 0x09, 0x01, 0x02], // 'f'
+	[0x0c, 0x52, 0x52, 0x52, 0x3e], // 'g'
+	[0x7f, 0x08, 0x04, 0x04, 0x78], // 'h'
+	[0x00, 0x44, 0x7d, 0x40, 0x00], // 'i'
+	[0x20, 0x40, 0x44, 0x3d, 0x00], // 'j'
+	[0x7f, 0x10, 0x28, 0x44, 0x00], // 'k'
+	[0x00, 0x41, 0x7f, 0x40, 0x00], // 'l'
+	[0x7c, 0x04, 0x18, 0x04, 0x78], // 'm'
+	[0x7c, 0x08, 0x04, 0x04, 0x78], // 'n'
+	[0x38, 0x44, 0x44, 0x44, 0x38], // 'o'
+	[0x7c, 0x14, 0x14, 0x14, 0x08], // 'p'
+	[0x08, 0x14, 0x14, 0x18, 0x7c], // 'q'
+	[0x7c, 0x08, 0x04, 0x04, 0x08], // 'r'
+	[0x48, 0x54, 0x54, 0x54, 0x20], // 's'
+	[0x04, 0x3f, 0x44, 0x40, 0x20], // 't'
+	[0x3c, 0x40, 0x40, 0x20, 0x7c], // 'u'
+	[0x1c, 0x20, 0x40, 0x20, 0x1c], // 'v'
+	[0x3c, 0x40, 0x30, 0x40, 0x3c], // 'w'
+	[0x44, 0x28, 0x10, 0x28, 0x44], // 'x'
+	[0x0c, 0x50, 0x50, 0x50, 0x3c], // 'y'
+	[0x44, 0x64, 0x54, 0x4c, 0x44], // 'z'
+	[0x00, 0x08, 0x36, 0x41, 0x00], // '{'
+	[0x00, 0x00, 0x7f, 0x00, 0x00], // '|'
+	[0x00, 0x41, 0x36, 0x08, 0x00], // '}'
+	[0x08, 0x04, 0x08, 0x10, 0x08], // '~'
+];
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn white(alpha: f32) -> image::ColorF {
+		image::ColorF::new(1f32, 1f32, 1f32, alpha)
+	}
+
+	fn point(x: f64, y: f64) -> vec::Vec2<f64> {
+		vec::Vec2::new(x, y)
+	}
+
+	// Sum of the alpha of the pixels, which is their coverage when drawing an opaque color
+	fn area(image: &image::Image) -> f32 {
+		let mut sum = 0f32;
+		for y in 0 .. image.get_height() {
+			for x in 0 .. image.get_width() {
+				sum += image.get_f(x, y).a;
+			}
+		}
+		sum
+	}
+
+	fn close(a: f32, b: f32) -> bool {
+		(a - b).abs() < 1e-4f32
+	}
+
+	#[test]
+	fn nonzero_winding() {
+		// the pentagon in the middle of a star has a winding number of two, and is filled once
+		let mut image = image::Image::new(32, 32, image::Format::RgbaF32);
+		let star: Vec<vec::Vec2<f64>> = (0 .. 5).map(|i| {
+			let angle = (90f64 + 144f64 * i as f64).to_radians();
+			point(16f64 + 14f64 * angle.cos(), 16f64 + 14f64 * angle.sin())
+		}).collect();
+		Canvas::new(&mut image).fill_polygon(&star, &white(0.5f32));
+		assert!(close(image.get_f(16, 16).a, 0.5f32));
+		// a point of the star has a winding number of one
+		assert!(close(image.get_f(16, 26).a, 0.5f32));
+		assert_eq!(image.get_f(2, 2).a, 0f32);
+		assert_eq!(image.get_f(16, 1).a, 0f32);
+	}
+
+	#[test]
+	fn rect_edges() {
+		let mut image = image::Image::new(8, 8, image::Format::RgbaF32);
+		Canvas::new(&mut image).fill_rect(&point(-0.5f64, -0.5f64), &point(2f64, 3.25f64), &white(1f32));
+		assert!(close(image.get_f(1, 1).a, 1f32));
+		// half of the column and three of the four sample rows are covered
+		assert!(close(image.get_f(2, 1).a, 0.5f32));
+		assert!(close(image.get_f(1, 3).a, 0.75f32));
+		assert!(close(image.get_f(2, 3).a, 0.375f32));
+		assert_eq!(image.get_f(3, 1).a, 0f32);
+		assert_eq!(image.get_f(1, 4).a, 0f32);
+		assert!(close(area(&image), 2.5f32 * 3.75f32));
+	}
+
+	// The approximating polygon is inside the ellipse, and at most the tolerance from it
+	fn assert_ellipse_area(image: &image::Image, rx: f64, ry: f64) {
+		let expected = (f64::consts::PI * rx * ry) as f32;
+		let deficit = expected - area(image);
+		let max_deficit = (2f64 * f64::consts::PI * rx.max(ry) * ELLIPSE_TOLERANCE) as f32;
+		assert!(deficit > -0.25f32 && deficit < max_deficit, "{} != {}", area(image), expected);
+	}
+
+	#[test]
+	fn ellipse_area() {
+		let mut image = image::Image::new(32, 32, image::Format::RgbaF32);
+		Canvas::new(&mut image).fill_ellipse(&point(16f64, 15.5f64), 10f64, 6f64, &white(1f32));
+		assert_ellipse_area(&image, 10f64, 6f64);
+
+		let mut image = image::Image::new(32, 32, image::Format::RgbaF32);
+		Canvas::new(&mut image).fill_circle(&point(16.2f64, 15.7f64), 5f64, &white(1f32));
+		assert_ellipse_area(&image, 5f64, 5f64);
+	}
+
+	#[test]
+	fn strokes() {
+		let style = &image::LineStyle { width: 2f64, ..Default::default() };
+		let mut image = image::Image::new(32, 32, image::Format::RgbaF32);
+		Canvas::new(&mut image).stroke_rect(&point(4f64, 4f64), &point(12f64, 12f64), &white(1f32), style);
+		for &(x, y) in [(4, 8), (8, 4), (12, 8), (8, 12), (4, 4), (12, 12)].iter() {
+			assert!(close(image.get_f(x, y).a, 1f32), "({}, {})", x, y);
+		}
+		assert_eq!(image.get_f(8, 8).a, 0f32);
+
+		let mut image = image::Image::new(32, 32, image::Format::RgbaF32);
+		Canvas::new(&mut image).stroke_circle(&point(16f64, 16f64), 8f64, &white(1f32), style);
+		assert!(close(image.get_f(24, 16).a, 1f32) && close(image.get_f(16, 8).a, 1f32));
+		assert_eq!(image.get_f(16, 16).a, 0f32);
+		assert_eq!(image.get_f(28, 16).a, 0f32);
+
+		let mut image = image::Image::new(32, 32, image::Format::RgbaF32);
+		Canvas::new(&mut image).stroke_ellipse(&point(16f64, 16f64), 12f64, 4f64, &white(1f32), style);
+		assert!(close(image.get_f(28, 16).a, 1f32) && close(image.get_f(16, 20).a, 1f32));
+		assert_eq!(image.get_f(16, 16).a, 0f32);
+	}
+
+	#[test]
+	fn strokes_cover_pixels_once() {
+		// the segments overlap at the joins, where the color is still blended once
+		let style = &image::LineStyle { width: 3f64, ..Default::default() };
+		let mut image = image::Image::new(16, 16, image::Format::RgbaF32);
+		let triangle = [point(2f64, 2f64), point(12f64, 2f64), point(7f64, 12f64)];
+		Canvas::new(&mut image).stroke_polygon(&triangle, &white(0.5f32), style);
+		for &(x, y) in [(2, 2), (12, 2), (7, 12), (7, 2)].iter() {
+			assert!(close(image.get_f(x, y).a, 0.5f32), "({}, {}): {}", x, y, image.get_f(x, y).a);
+		}
+		assert_eq!(image.get_f(7, 6).a, 0f32);
+	}
+
+	#[test]
+	fn dashed_polyline() {
+		let style = &image::LineStyle { width: 1f64, dashes: vec![4f64, 4f64], ..Default::default() };
+		let mut image = image::Image::new(24, 8, image::Format::RgbaF32);
+		Canvas::new(&mut image).stroke_polyline(&[point(2f64, 2f64), point(20f64, 2f64)], &white(1f32), style);
+		// dashes from 2 to 6, 10 to 14 and 18 to 20
+		assert!(close(image.get_f(4, 2).a, 1f32) && close(image.get_f(12, 2).a, 1f32) && close(image.get_f(19, 2).a, 1f32));
+		assert_eq!(image.get_f(8, 2).a, 0f32);
+		assert_eq!(image.get_f(16, 2).a, 0f32);
+		assert!(close(area(&image), 10f32));
+	}
+
+	#[test]
+	fn text_bounds() {
+		assert_eq!(text_size("", 1), (0, GLYPH_HEIGHT));
+		assert_eq!(text_size("ab", 1), (2 * ADVANCE - 1, GLYPH_HEIGHT));
+		assert_eq!(text_size("ab\nc", 2), ((2 * ADVANCE - 1) * 2, (LINE_HEIGHT + GLYPH_HEIGHT) * 2));
+
+		// the drawn pixels are inside the size below and to the right of the position, and touch each side
+		for &(text, scale) in [("ab\nc", 2), ("T|", 1), ("W~\n\u{e9}", 3)].iter() {
+			let mut image = image::Image::new(64, 64, image::Format::RgbaF32);
+			let (x0, y0) = (3usize, 50usize);
+			Canvas::new(&mut image).text(&point(x0 as f64, y0 as f64), text, &white(1f32), scale);
+			let (width, height) = text_size(text, scale);
+			let (mut min, mut max) = ((usize::MAX, usize::MAX), (0, 0));
+			for y in 0 .. 64 {
+				for x in 0 .. 64 {
+					if image.get_f(x, y).a > 0f32 {
+						min = (min.0.min(x), min.1.min(y));
+						max = (max.0.max(x), max.1.max(y));
+					}
+				}
+			}
+			assert!(min.0 >= x0 && max.0 < x0 + width, "{:?}: {:?} {:?}", text, min, max);
+			assert!(max.1 <= y0 && min.1 > y0 - height, "{:?}: {:?} {:?}", text, min, max);
+			assert_eq!(max.1, y0, "{:?}", text);
+		}
+	}
+}
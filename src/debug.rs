@@ -0,0 +1,175 @@
+use image;
+use model;
+use pipeline;
+use vec;
+
+use std::f64;
+
+// Squares of the UV checker per unit of texture coordinates
+const UV_CHECKS: f64 = 8f64;
+// Fragment count which is the top of the overdraw heatmap, so that images are comparable
+const OVERDRAW_MAX: f32 = 8f32;
+
+// Attributes of the models which are drawn instead of lighting them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+	// model space normals, mapped from [-1, 1] to [0, 1] in each channel
+	Normals,
+	// view space normals, so that blue faces the eye
+	ViewNormals,
+	// the fraction of u and v in red and green, with a checker, and blue outside [0, 1]
+	Uv,
+	// distance along the view direction, which is brighter for closer fragments
+	Depth,
+	// a color for each triangle
+	TriangleId,
+	// the number of fragments drawn at each pixel, without a depth test
+	Overdraw,
+}
+
+impl Mode {
+	pub fn from_name(name: &str) -> Option<Mode> {
+		match name {
+			"normals" => Some(Mode::Normals),
+			"view-normals" => Some(Mode::ViewNormals),
+			"uv" => Some(Mode::Uv),
+			"depth" => Some(Mode::Depth),
+			"triangle-id" => Some(Mode::TriangleId),
+			"overdraw" => Some(Mode::Overdraw),
+			_ => None,
+		}
+	}
+}
+
+// Colors fragments by an attribute of the model.
+// Colors are sRGB values, so that 8 bit output images store them directly.
+pub struct DebugShader<'a> {
+	pub mode: Mode,
+	transform: &'a vec::Transform4<f64>,
+	modelview: &'a vec::Transform4<f64>,
+	// view depths of the closest and farthest vertices of the models
+	depth_range: (f64, f64),
+	// Fragment counts in the red channel for the overdraw heatmap.
+	// If this is None, each fragment adds one to all channels.
+	pub counts: Option<&'a image::Image>,
+}
+
+impl<'a> DebugShader<'a> {
+	// The depth range covers the models
+	pub fn new(mode: Mode, transform: &'a vec::Transform4<f64>, modelview: &'a vec::Transform4<f64>,
+		   models: &[&model::Model]) -> Self {
+		let (mut near, mut far) = (f64::MAX, f64::MIN);
+		for model in models {
+			// the eye looks along negative z
+			if let Some((min, max)) = model.bounds(modelview) {
+				near = near.min(-max.0[2]);
+				far = far.max(-min.0[2]);
+			}
+		}
+		DebugShader {
+			mode,
+			transform,
+			modelview,
+			depth_range: (near, far),
+			counts: None,
+		}
+	}
+}
+
+pub struct Varyings {
+	uv: vec::Vec2<f64>,
+	normal: vec::Vec3<f64>,
+	// view depth
+	depth: f64,
+}
+
+impl image::Varyings for Varyings {
+	fn interpolate(v0: &Self, v1: &Self, v2: &Self, bc: &vec::Vec3<f64>) -> Self {
+		Varyings {
+			uv: image::Varyings::interpolate(&v0.uv, &v1.uv, &v2.uv, bc),
+			normal: image::Varyings::interpolate(&v0.normal, &v1.normal, &v2.normal, bc),
+			depth: image::Varyings::interpolate(&v0.depth, &v1.depth, &v2.depth, bc),
+		}
+	}
+}
+
+impl<'a> image::Shader for DebugShader<'a> {
+	type Varyings = Varyings;
+	type Output = image::ColorF;
+
+	fn vertex(&self, vert: &vec::Vec3<f64>, uv: &vec::Vec3<f64>, normal: &vec::Vec3<f64>) -> image::Vertex<Varyings> {
+		let normal = match self.mode {
+			Mode::ViewNormals => normal.transform_vec(self.modelview),
+			_ => vec::Vec3(normal.0),
+		};
+		let varyings = Varyings {
+			uv: vec::Vec2::new(uv.0[0], uv.0[1]),
+			normal,
+			depth: -vert.transform_pt(self.modelview).0[2],
+		};
+		(vert.to_pt4().transform(self.transform), varyings)
+	}
+
+	fn fragment(&self, fragment: &image::Fragment<Varyings>) -> Option<image::ColorF> {
+		let varyings = &fragment.varyings;
+		let color = match self.mode {
+			Mode::Normals
+			| Mode::ViewNormals => {
+				if varyings.normal.norm() == 0f64 {
+					return None;
+				}
+				let n = vec::Vec3(varyings.normal.0).normalize().scale(0.5f64).add(&vec::Vec3::new(0.5f64, 0.5f64, 0.5f64));
+				image::ColorF::new(n.0[0] as f32, n.0[1] as f32, n.0[2] as f32, 1f32)
+			},
+			Mode::Uv => {
+				let (u, v) = varyings.uv.as_tuple();
+				let check = ((u * UV_CHECKS).floor() + (v * UV_CHECKS).floor()).rem_euclid(2f64) == 0f64;
+				let shade = if check { 1f32 } else { 0.6f32 };
+				let outside = !(0f64 ..= 1f64).contains(&u) || !(0f64 ..= 1f64).contains(&v);
+				image::ColorF::new((u - u.floor()) as f32 * shade, (v - v.floor()) as f32 * shade,
+						   if outside { shade } else { 0f32 }, 1f32)
+			},
+			Mode::Depth => {
+				let (near, far) = self.depth_range;
+				let d = if far > near { 1f64 - (varyings.depth - near) / (far - near) } else { 1f64 };
+				let d = d.clamp(0f64, 1f64) as f32;
+				image::ColorF::new(d, d, d, 1f32)
+			},
+			Mode::TriangleId => image::id_color(fragment.primitive),
+			Mode::Overdraw => {
+				match self.counts {
+					Some(counts) => heatmap(counts.get_f(fragment.x, fragment.y).r),
+					// counted with additive blending
+					None => return Some(image::ColorF::new(1f32, 1f32, 1f32, 1f32)),
+				}
+			},
+		};
+		Some(color.map(image::srgb_to_linear))
+	}
+}
+
+// Draw the models in order, numbering their triangles in sequence
+pub fn draw<T>(pipeline: &mut pipeline::Pipeline<T>, models: &[&model::Model], shader: &DebugShader)
+		where T: pipeline::Target<image::ColorF> {
+	let first_primitive = pipeline.first_primitive;
+	for model in models {
+		pipeline.draw(model, shader);
+		pipeline.first_primitive += model.nfaces();
+	}
+	pipeline.first_primitive = first_primitive;
+}
+
+// Blue for one fragment, through cyan, green and yellow, to red at the maximum count
+fn heatmap(count: f32) -> image::ColorF {
+	let stops = [
+		image::ColorF::new(0f32, 0f32, 1f32, 1f32),
+		image::ColorF::new(0f32, 1f32, 1f32, 1f32),
+		image::ColorF::new(0f32, 1f32, 0f32, 1f32),
+		image::ColorF::new(1f32, 1f32, 0f32, 1f32),
+		image::ColorF::new(1f32, 0f32, 0f32, 1f32),
+	];
+	let t = ((count - 1f32) / (OVERDRAW_MAX - 1f32)).clamp(0f32, 1f32) * (stops.len() - 1) as f32;
+	let i = (t.floor() as usize).min(stops.len() - 2);
+	let f = t - i as f32;
+	stops[i].scale(1f32 - f).add(&stops[i + 1].scale(f))
+}
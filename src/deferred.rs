@@ -0,0 +1,110 @@
+use depth;
+use environment;
+use image;
+use light;
+use pbr;
+use ssao;
+use vec;
+
+// Targets of the G-buffer, in the order of the colors output by the shader
+pub const ALBEDO: usize = 0;
+// world space normal, and the unshadowed fraction of the first light in alpha
+pub const NORMAL: usize = 1;
+// metallic, roughness, ambient occlusion, and the material ID in alpha
+pub const MATERIAL: usize = 2;
+
+// Surface attributes of the closest fragment for each pixel, so that lighting
+// is computed once per pixel after all of the geometry is rasterized.
+pub struct GBuffer {
+	pub targets: image::RenderTargets,
+	// the depth of the fragments, which is used to reconstruct their position
+	pub zbuffer: depth::DepthBuffer,
+}
+
+impl GBuffer {
+	// The targets have the size of the zbuffer, which must have one sample per pixel.
+	pub fn new(zbuffer: depth::DepthBuffer) -> Self {
+		let (width, height) = (zbuffer.get_width(), zbuffer.get_height());
+		GBuffer {
+			targets: image::RenderTargets::new(width, height, &[image::Format::RgbaF32; 3]),
+			zbuffer,
+		}
+	}
+
+	pub fn get_width(&self) -> usize {
+		self.targets.get_width()
+	}
+
+	pub fn get_height(&self) -> usize {
+		self.targets.get_height()
+	}
+
+	// Viewable images of the albedo, normals, depth and material IDs
+	pub fn to_images(&self) -> Vec<(&'static str, image::Image)> {
+		let (width, height) = (self.get_width(), self.get_height());
+		let mut albedo = image::Image::new(width, height, image::Format::Rgb);
+		let mut normal = image::Image::new(width, height, image::Format::Rgb);
+		let mut material = image::Image::new(width, height, image::Format::Rgb);
+		for image in [&mut normal, &mut material] {
+			image.set_color_space(image::ColorSpace::Linear);
+		}
+		for y in 0 .. height {
+			for x in 0 .. width {
+				if self.zbuffer.is_clear(x, y, 0) {
+					continue;
+				}
+				albedo.set_f(x, y, &self.targets.images[ALBEDO].get_f(x, y));
+				let n = self.targets.images[NORMAL].get_f(x, y);
+				normal.set_f(x, y, &n.map(|c| c * 0.5f32 + 0.5f32));
+				material.set_f(x, y, &image::id_color(self.targets.images[MATERIAL].get_f(x, y).a as usize));
+			}
+		}
+		vec![
+			("albedo", albedo),
+			("normal", normal),
+			("depth", self.zbuffer.to_image()),
+			("material", material),
+		]
+	}
+}
+
+// Uniforms for the lighting pass
+pub struct Lighting<'a> {
+	pub lights: &'a [light::Light],
+	pub ambient: f32,
+	pub environment: Option<&'a environment::Environment>,
+	pub occlusion: Option<&'a ssao::AmbientOcclusion>,
+}
+
+// Shade each pixel of the G-buffer which is covered by geometry, using the metallic-roughness BRDF.
+// The screen transform maps world space to the zbuffer, and the eye is in world space.
+// Pixels which are not covered have zero alpha.
+pub fn shade(gbuffer: &GBuffer, lighting: &Lighting, screen: &vec::Transform4<f64>, eye: &vec::Vec3<f64>) -> image::Image {
+	let (width, height) = (gbuffer.get_width(), gbuffer.get_height());
+	let inverse = screen.inverse();
+	let mut image = image::Image::new(width, height, image::Format::RgbaF32);
+	for y in 0 .. height {
+		for x in 0 .. width {
+			if gbuffer.zbuffer.is_clear(x, y, 0) {
+				continue;
+			}
+			let z = gbuffer.zbuffer.get(x, y, 0);
+			let albedo = gbuffer.targets.images[ALBEDO].get_f(x, y);
+			let n = gbuffer.targets.images[NORMAL].get_f(x, y);
+			let m = gbuffer.targets.images[MATERIAL].get_f(x, y);
+			let p = &vec::Vec3::new(x as f64, y as f64, z).transform_pt(&inverse);
+			let view = &eye.sub(p).normalize();
+			let normal = &vec::Vec3::new(n.r as f64, n.g as f64, n.b as f64);
+			let material = pbr::Material {
+				base_color: albedo,
+				metallic: m.r as f64,
+				roughness: m.g as f64,
+			};
+			let occlusion = lighting.occlusion.map_or(1f32, |occlusion| occlusion.get(x, y));
+			let radiance = material.indirect(lighting.environment, lighting.ambient, normal, view).scale(m.b * occlusion)
+				.add(&material.direct(lighting.lights, p, normal, view, n.a as f64));
+			image.set_f(x, y, &image::ColorF { a: 1f32, ..radiance });
+		}
+	}
+	image
+}
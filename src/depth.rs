@@ -0,0 +1,499 @@
+use image;
+
+use std::f64;
+
+// Storage for each depth value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+	F32,
+	// integers normalized over the depth range
+	U16,
+	U24,
+}
+
+impl Format {
+	pub fn from_name(name: &str) -> Option<Format> {
+		match name {
+			"f32" => Some(Format::F32),
+			"u16" => Some(Format::U16),
+			"u24" => Some(Format::U24),
+			_ => None,
+		}
+	}
+
+	fn bytes_per_sample(&self) -> usize {
+		match *self {
+			Format::F32 => 4,
+			Format::U16 => 2,
+			Format::U24 => 3,
+		}
+	}
+
+	fn max_int(&self) -> f64 {
+		match *self {
+			Format::F32 => 1f64,
+			Format::U16 => 65535f64,
+			Format::U24 => 16777215f64,
+		}
+	}
+}
+
+// Test for whether a fragment is drawn, comparing its depth or stencil reference with the stored value.
+// Closer fragments have larger depths, which is the reverse of OpenGL, so Greater passes
+// fragments in front of the stored depth and Less passes fragments behind it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compare {
+	Never,
+	Less,
+	LessEqual,
+	Equal,
+	NotEqual,
+	Greater,
+	GreaterEqual,
+	Always,
+}
+
+impl Compare {
+	pub fn from_name(name: &str) -> Option<Compare> {
+		match name {
+			"never" => Some(Compare::Never),
+			"less" => Some(Compare::Less),
+			"less-equal" => Some(Compare::LessEqual),
+			"equal" => Some(Compare::Equal),
+			"not-equal" => Some(Compare::NotEqual),
+			"greater" => Some(Compare::Greater),
+			"greater-equal" => Some(Compare::GreaterEqual),
+			"always" => Some(Compare::Always),
+			_ => None,
+		}
+	}
+
+	pub fn test(&self, z: f64, stored: f64) -> bool {
+		match *self {
+			Compare::Never => false,
+			Compare::Less => z < stored,
+			Compare::LessEqual => z <= stored,
+			Compare::Equal => z == stored,
+			Compare::NotEqual => z != stored,
+			Compare::Greater => z > stored,
+			Compare::GreaterEqual => z >= stored,
+			Compare::Always => true,
+		}
+	}
+
+	// A clear value which any depth passes
+	pub fn clear_value(&self) -> f64 {
+		match *self {
+			Compare::Less | Compare::LessEqual => f64::MAX,
+			_ => f64::MIN,
+		}
+	}
+}
+
+// Change to the stored stencil value
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StencilOp {
+	Keep,
+	// set to the reference value
+	Replace,
+	// increment or decrement, clamping to the 8 bit range
+	Incr,
+	Decr,
+	Invert,
+}
+
+impl StencilOp {
+	fn apply(&self, stored: u8, reference: u8) -> u8 {
+		match *self {
+			StencilOp::Keep => stored,
+			StencilOp::Replace => reference,
+			StencilOp::Incr => stored.saturating_add(1),
+			StencilOp::Decr => stored.saturating_sub(1),
+			StencilOp::Invert => !stored,
+		}
+	}
+}
+
+// Stencil test and operations for one face orientation
+#[derive(Clone, Copy, Debug)]
+pub struct StencilFace {
+	// compares the reference with the stored value
+	pub compare: Compare,
+	// operation when the stencil test fails
+	pub fail: StencilOp,
+	// operation when the stencil test passes but the depth test fails
+	pub depth_fail: StencilOp,
+	// operation when both tests pass
+	pub pass: StencilOp,
+}
+
+impl StencilFace {
+	// Keeps the stored value for all outcomes
+	pub fn new(compare: Compare) -> Self {
+		StencilFace {
+			compare,
+			fail: StencilOp::Keep,
+			depth_fail: StencilOp::Keep,
+			pass: StencilOp::Keep,
+		}
+	}
+}
+
+// Stencil state, with separate tests and operations for front and back faces
+#[derive(Clone, Copy, Debug)]
+pub struct Stencil {
+	pub front: StencilFace,
+	pub back: StencilFace,
+	pub reference: u8,
+	// bits of the reference and stored value which are compared
+	pub read_mask: u8,
+	// bits of the stored value which are changed by the operations
+	pub write_mask: u8,
+}
+
+impl Stencil {
+	// The same test and operations for both orientations, using all bits
+	pub fn new(face: StencilFace, reference: u8) -> Self {
+		Stencil {
+			front: face,
+			back: face,
+			reference,
+			read_mask: 0xff,
+			write_mask: 0xff,
+		}
+	}
+
+	fn face(&self, front_facing: bool) -> &StencilFace {
+		if front_facing { &self.front } else { &self.back }
+	}
+}
+
+// Depth of the closest fragment for each sample of each pixel, and an 8 bit stencil value.
+// Samples for a pixel are adjacent.
+pub struct DepthBuffer {
+	width: usize,
+	height: usize,
+	samples: usize,
+	format: Format,
+	data: Vec<u8>,
+	stencil_data: Vec<u8>,
+
+	pub compare: Compare,
+	// Depths are only written if this is set
+	pub write: bool,
+	pub clear_value: f64,
+	// Depths which are stored as integers are clamped to this range.
+	// The clear value is usually outside of the range, so the range must be wider
+	// than the drawn depths, or depths at the ends tie with the clear value.
+	pub range: (f64, f64),
+	// The stencil test is disabled if this is None
+	pub stencil: Option<Stencil>,
+	pub stencil_clear: u8,
+}
+
+impl DepthBuffer {
+	// The buffer is cleared to the smallest depth, and uses the greater compare function.
+	pub fn new(width: usize, height: usize, samples: usize, format: Format) -> Self {
+		let mut depth = DepthBuffer {
+			width,
+			height,
+			samples,
+			format,
+			data: vec![0; width * height * samples * format.bytes_per_sample()],
+			stencil_data: vec![0; width * height * samples],
+			compare: Compare::Greater,
+			write: true,
+			clear_value: f64::MIN,
+			range: (0f64, 1f64),
+			stencil: None,
+			stencil_clear: 0,
+		};
+		depth.clear();
+		depth
+	}
+
+	pub fn get_width(&self) -> usize {
+		self.width
+	}
+
+	pub fn get_height(&self) -> usize {
+		self.height
+	}
+
+	pub fn get_samples(&self) -> usize {
+		self.samples
+	}
+
+	// Clear both the depth and stencil values
+	pub fn clear(&mut self) {
+		for stencil in &mut self.stencil_data {
+			*stencil = self.stencil_clear;
+		}
+		let bytes = self.format.bytes_per_sample();
+		let mut value = [0u8; 4];
+		self.encode(self.clear_value, &mut value[.. bytes]);
+		for sample in self.data.chunks_mut(bytes) {
+			sample.copy_from_slice(&value[.. bytes]);
+		}
+	}
+
+	pub fn get(&self, x: usize, y: usize, sample: usize) -> f64 {
+		let bytes = self.format.bytes_per_sample();
+		let offset = self.index(x, y, sample) * bytes;
+		self.decode(&self.data[offset .. offset + bytes])
+	}
+
+	pub fn set(&mut self, x: usize, y: usize, sample: usize, z: f64) {
+		let bytes = self.format.bytes_per_sample();
+		let offset = self.index(x, y, sample) * bytes;
+		let mut value = [0u8; 4];
+		self.encode(z, &mut value[.. bytes]);
+		self.data[offset .. offset + bytes].copy_from_slice(&value[.. bytes]);
+	}
+
+	// Whether a fragment with depth z passes the stencil and depth tests for the sample.
+	// The stencil fail or depth fail operation is applied if a test fails.
+	pub fn test(&mut self, x: usize, y: usize, sample: usize, z: f64, front_facing: bool) -> bool {
+		let depth_pass = self.compare.test(self.quantize(z), self.get(x, y, sample));
+		let stencil = match self.stencil {
+			Some(stencil) => stencil,
+			None => return depth_pass,
+		};
+		let face = stencil.face(front_facing);
+		let i = self.index(x, y, sample);
+		let stored = self.stencil_data[i];
+		let stencil_pass = face.compare.test((stencil.reference & stencil.read_mask) as f64,
+						     (stored & stencil.read_mask) as f64);
+		if !stencil_pass {
+			self.apply_stencil(i, &stencil, face.fail);
+		} else if !depth_pass {
+			self.apply_stencil(i, &stencil, face.depth_fail);
+		}
+		stencil_pass && depth_pass
+	}
+
+	// Write the depth of a sample which passed the tests, if depth writes are enabled,
+	// and apply the stencil pass operation
+	pub fn pass(&mut self, x: usize, y: usize, sample: usize, z: f64, front_facing: bool) {
+		if self.write {
+			self.set(x, y, sample, z);
+		}
+		if let Some(stencil) = self.stencil {
+			let i = self.index(x, y, sample);
+			self.apply_stencil(i, &stencil, stencil.face(front_facing).pass);
+		}
+	}
+
+	// Whether nothing has been written to the sample since it was cleared
+	pub fn is_clear(&self, x: usize, y: usize, sample: usize) -> bool {
+		self.get(x, y, sample) == self.quantize(self.clear_value)
+	}
+
+	// The first sample of each pixel, with the covered depths mapped to [0, 1]
+	// so that closer samples are brighter
+	pub fn to_image(&self) -> image::Image {
+		let mut image = image::Image::new(self.width, self.height, image::Format::Gray);
+		image.set_color_space(image::ColorSpace::Linear);
+		let (mut min, mut max) = (f64::MAX, f64::MIN);
+		for y in 0 .. self.height {
+			for x in 0 .. self.width {
+				if !self.is_clear(x, y, 0) {
+					let z = self.get(x, y, 0);
+					min = min.min(z);
+					max = max.max(z);
+				}
+			}
+		}
+		for y in 0 .. self.height {
+			for x in 0 .. self.width {
+				if !self.is_clear(x, y, 0) {
+					let d = if max > min { ((self.get(x, y, 0) - min) / (max - min)) as f32 } else { 1f32 };
+					image.set_f(x, y, &image::ColorF::new(d, d, d, 1f32));
+				}
+			}
+		}
+		image
+	}
+
+	// The first sample of each pixel, with the stored depth in each channel.
+	// Samples which are clear have zero alpha.
+	pub fn to_float_image(&self) -> image::Image {
+		let mut image = image::Image::new(self.width, self.height, image::Format::RgbaF32);
+		for y in 0 .. self.height {
+			for x in 0 .. self.width {
+				if !self.is_clear(x, y, 0) {
+					let z = self.get(x, y, 0) as f32;
+					image.set_f(x, y, &image::ColorF::new(z, z, z, 1f32));
+				}
+			}
+		}
+		image
+	}
+
+	fn apply_stencil(&mut self, i: usize, stencil: &Stencil, op: StencilOp) {
+		let stored = self.stencil_data[i];
+		let value = op.apply(stored, stencil.reference);
+		self.stencil_data[i] = (stored & !stencil.write_mask) | (value & stencil.write_mask);
+	}
+
+	fn index(&self, x: usize, y: usize, sample: usize) -> usize {
+		(x + y * self.width) * self.samples + sample
+	}
+
+	// The depth after it is stored and loaded
+	fn quantize(&self, z: f64) -> f64 {
+		let mut value = [0u8; 4];
+		let bytes = self.format.bytes_per_sample();
+		self.encode(z, &mut value[.. bytes]);
+		self.decode(&value[.. bytes])
+	}
+
+	fn encode(&self, z: f64, buf: &mut [u8]) {
+		match self.format {
+			Format::F32 => buf.copy_from_slice(&(z as f32).to_le_bytes()),
+			Format::U16 | Format::U24 => {
+				let (near, far) = self.range;
+				let t = ((z - near) / (far - near)).clamp(0f64, 1f64);
+				let value = (t * self.format.max_int()).round() as u32;
+				buf.copy_from_slice(&value.to_le_bytes()[.. buf.len()]);
+			},
+		}
+	}
+
+	fn decode(&self, buf: &[u8]) -> f64 {
+		match self.format {
+			Format::F32 => f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+			Format::U16 | Format::U24 => {
+				let mut value = [0u8; 4];
+				value[.. buf.len()].copy_from_slice(buf);
+				let (near, far) = self.range;
+				near + (far - near) * u32::from_le_bytes(value) as f64 / self.format.max_int()
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn compare() {
+		let cases = [
+			(Compare::Never, [false, false, false]),
+			(Compare::Less, [true, false, false]),
+			(Compare::LessEqual, [true, true, false]),
+			(Compare::Equal, [false, true, false]),
+			(Compare::NotEqual, [true, false, true]),
+			(Compare::Greater, [false, false, true]),
+			(Compare::GreaterEqual, [false, true, true]),
+			(Compare::Always, [true, true, true]),
+		];
+		for &(compare, expected) in cases.iter() {
+			let results = [compare.test(1f64, 2f64), compare.test(2f64, 2f64), compare.test(3f64, 2f64)];
+			assert_eq!(results, expected, "{:?}", compare);
+		}
+	}
+
+	#[test]
+	fn closest_depth_is_kept() {
+		for &format in [Format::F32, Format::U16, Format::U24].iter() {
+			let mut depth = DepthBuffer::new(2, 1, 1, format);
+			depth.range = (0f64, 10f64);
+			depth.clear();
+			assert!(depth.is_clear(0, 0, 0));
+			for &z in [3f64, 7f64, 5f64].iter() {
+				if depth.test(0, 0, 0, z, true) {
+					depth.pass(0, 0, 0, z, true);
+				}
+			}
+			assert!((depth.get(0, 0, 0) - 7f64).abs() < 1e-3, "{:?}", format);
+			assert!(!depth.is_clear(0, 0, 0));
+			assert!(depth.is_clear(1, 0, 0));
+		}
+	}
+
+	#[test]
+	fn write_disabled() {
+		let mut depth = DepthBuffer::new(1, 1, 1, Format::F32);
+		depth.write = false;
+		assert!(depth.test(0, 0, 0, 1f64, true));
+		depth.pass(0, 0, 0, 1f64, true);
+		assert!(depth.is_clear(0, 0, 0));
+	}
+
+	#[test]
+	fn integer_range() {
+		let mut depth = DepthBuffer::new(1, 1, 1, Format::U16);
+		depth.range = (100f64, 200f64);
+		depth.clear();
+		// the quantization step is the range divided by 65535
+		depth.set(0, 0, 0, 150f64);
+		assert!((depth.get(0, 0, 0) - 150f64).abs() <= 100f64 / 65535f64);
+		// depths outside of the range saturate
+		depth.set(0, 0, 0, 1000f64);
+		assert_eq!(depth.get(0, 0, 0), 200f64);
+		assert!(!depth.test(0, 0, 0, 2000f64, true));
+		// the clear value saturates at the far end, so depths inside the range pass
+		depth.clear();
+		assert!(depth.test(0, 0, 0, 100.5f64, true));
+	}
+
+	#[test]
+	fn stencil_ops() {
+		assert_eq!(StencilOp::Keep.apply(5, 9), 5);
+		assert_eq!(StencilOp::Replace.apply(5, 9), 9);
+		assert_eq!(StencilOp::Incr.apply(5, 9), 6);
+		assert_eq!(StencilOp::Incr.apply(255, 9), 255);
+		assert_eq!(StencilOp::Decr.apply(5, 9), 4);
+		assert_eq!(StencilOp::Decr.apply(0, 9), 0);
+		assert_eq!(StencilOp::Invert.apply(0x0f, 9), 0xf0);
+	}
+
+	#[test]
+	fn stencil_masks() {
+		let mut depth = DepthBuffer::new(1, 1, 1, Format::F32);
+		depth.stencil_clear = 0x35;
+		depth.clear();
+		let mut face = StencilFace::new(Compare::Equal);
+		face.pass = StencilOp::Invert;
+		let mut stencil = Stencil::new(face, 0x05);
+		// only the low bits are compared and changed
+		stencil.read_mask = 0x0f;
+		stencil.write_mask = 0x0f;
+		depth.stencil = Some(stencil);
+		assert!(depth.test(0, 0, 0, 1f64, true));
+		depth.pass(0, 0, 0, 1f64, true);
+		assert_eq!(depth.stencil_data[0], 0x3a);
+		// the low bits no longer match the reference
+		assert!(!depth.test(0, 0, 0, 2f64, true));
+	}
+
+	// Shadow volumes with the depth fail method: back faces behind the scene increment the count,
+	// and front faces behind it decrement it, leaving a non-zero count inside the volume.
+	#[test]
+	fn stencil_depth_fail_per_face() {
+		let mut depth = DepthBuffer::new(2, 1, 1, Format::F32);
+		depth.set(0, 0, 0, 5f64);
+		depth.set(1, 0, 0, 5f64);
+		depth.write = false;
+		let mut front = StencilFace::new(Compare::Always);
+		front.depth_fail = StencilOp::Decr;
+		let mut back = StencilFace::new(Compare::Always);
+		back.depth_fail = StencilOp::Incr;
+		depth.stencil = Some(Stencil {
+			front,
+			back,
+			..Stencil::new(StencilFace::new(Compare::Always), 0)
+		});
+		// the volume contains the point at x = 0, and is in front of the point at x = 1
+		for &(x, z, front_facing) in [(0, 6f64, true), (0, 4f64, false), (1, 7f64, true), (1, 6f64, false)].iter() {
+			if depth.test(x, 0, 0, z, front_facing) {
+				depth.pass(x, 0, 0, z, front_facing);
+			}
+		}
+		assert_eq!(depth.stencil_data[0], 1);
+		assert_eq!(depth.stencil_data[1], 0);
+	}
+}
@@ -0,0 +1,227 @@
+use image;
+use pbr;
+use texture;
+use vec;
+
+use std::f64;
+
+// Number of specular levels, with roughness increasing linearly from 0 to 1
+const SPECULAR_LEVELS: usize = 6;
+// Maximum width of the first prefiltered specular level
+const SPECULAR_WIDTH: usize = 256;
+// Samples of the GGX lobe for each texel of the prefiltered specular levels
+const PREFILTER_SAMPLES: usize = 64;
+// Maximum width of the image used to project onto spherical harmonics
+const SH_WIDTH: usize = 128;
+
+// Image based lighting from an equirectangular environment map.
+// The top row of the image is straight up (+y), and the center is in the -z direction.
+pub struct Environment {
+	// radiance, used for the background and for perfectly smooth surfaces
+	radiance: texture::Texture,
+	// radiance convolved with lobes of increasing roughness, starting at level 1
+	specular: Vec<texture::Texture>,
+	// spherical harmonics coefficients of the radiance, for each color channel
+	sh: [[f64; 3]; 9],
+	pub intensity: f32,
+}
+
+impl Environment {
+	pub fn new(image: image::Image) -> Self {
+		let mut radiance = texture::Texture::new(image);
+		radiance.sampler = sampler(texture::Filter::Trilinear);
+		let sh = project_sh(&radiance);
+		let specular = (1 .. SPECULAR_LEVELS).map(|level| {
+			let roughness = level as f64 / (SPECULAR_LEVELS - 1) as f64;
+			prefilter(&radiance, level, roughness)
+		}).collect();
+		Environment {
+			radiance,
+			specular,
+			sh,
+			intensity: 1f32,
+		}
+	}
+
+	// Radiance seen in the direction d
+	pub fn background(&self, d: &vec::Vec3<f64>) -> image::ColorF {
+		let (u, v) = to_uv(d);
+		self.radiance.sample(u, v, 0f64).scale(self.intensity)
+	}
+
+	// Irradiance arriving at a surface with normal n.
+	// Multiply by albedo / pi to get the diffuse radiance.
+	pub fn irradiance(&self, n: &vec::Vec3<f64>) -> image::ColorF {
+		const C1: f64 = 0.429043f64;
+		const C2: f64 = 0.511664f64;
+		const C3: f64 = 0.743125f64;
+		const C4: f64 = 0.886227f64;
+		const C5: f64 = 0.247708f64;
+		let (x, y, z) = n.as_tuple();
+		let l = &self.sh;
+		let channel = |c: usize| {
+			let e = C1 * l[8][c] * (x * x - y * y)
+				+ C3 * l[6][c] * z * z
+				+ C4 * l[0][c]
+				- C5 * l[6][c]
+				+ 2f64 * C1 * (l[4][c] * x * y + l[7][c] * x * z + l[5][c] * y * z)
+				+ 2f64 * C2 * (l[3][c] * x + l[1][c] * y + l[2][c] * z);
+			(e.max(0f64) as f32) * self.intensity
+		};
+		image::ColorF::new(channel(0), channel(1), channel(2), 1f32)
+	}
+
+	// Prefiltered radiance in the reflection direction r for a surface roughness
+	pub fn specular(&self, r: &vec::Vec3<f64>, roughness: f64) -> image::ColorF {
+		let (u, v) = to_uv(r);
+		let level = roughness.clamp(0f64, 1f64) * (SPECULAR_LEVELS - 1) as f64;
+		let sample = |level: usize| {
+			if level == 0 {
+				self.radiance.sample(u, v, 0f64)
+			} else {
+				self.specular[level - 1].sample(u, v, 0f64)
+			}
+		};
+		let l0 = level.floor();
+		let c0 = sample(l0 as usize);
+		let c = if l0 == level {
+			c0
+		} else {
+			let c1 = sample(l0 as usize + 1);
+			let t = (level - l0) as f32;
+			c0.add(&c1.add(&c0.scale(-1f32)).scale(t))
+		};
+		c.scale(self.intensity)
+	}
+}
+
+fn sampler(filter: texture::Filter) -> texture::Sampler {
+	texture::Sampler {
+		filter,
+		wrap_u: texture::Wrap::Repeat,
+		wrap_v: texture::Wrap::ClampToEdge,
+		border: Default::default(),
+	}
+}
+
+fn to_uv(d: &vec::Vec3<f64>) -> (f64, f64) {
+	let (x, y, z) = d.as_tuple();
+	let u = 0.5f64 + x.atan2(-z) / (2f64 * f64::consts::PI);
+	let v = 0.5f64 + y.clamp(-1f64, 1f64).asin() / f64::consts::PI;
+	(u, v)
+}
+
+fn from_uv(u: f64, v: f64) -> vec::Vec3<f64> {
+	let phi = (u - 0.5f64) * 2f64 * f64::consts::PI;
+	let lat = (v - 0.5f64) * f64::consts::PI;
+	vec::Vec3::new(lat.cos() * phi.sin(), lat.sin(), -lat.cos() * phi.cos())
+}
+
+// The texels of the smallest mipmap level which is at least the given width,
+// with their directions and solid angles.
+fn texels(texture: &texture::Texture, width: usize) -> Vec<(vec::Vec3<f64>, f64, image::ColorF)> {
+	let mut lod = 0;
+	let mut w = texture.get_width();
+	let mut h = texture.get_height();
+	while w / 2 >= width.max(1) {
+		w /= 2;
+		h = (h / 2).max(1);
+		lod += 1;
+	}
+	let mut texels = Vec::with_capacity(w * h);
+	for y in 0 .. h {
+		for x in 0 .. w {
+			let u = (x as f64 + 0.5f64) / w as f64;
+			let v = (y as f64 + 0.5f64) / h as f64;
+			let lat = (v - 0.5f64) * f64::consts::PI;
+			let solid_angle = (2f64 * f64::consts::PI / w as f64) * (f64::consts::PI / h as f64) * lat.cos();
+			texels.push((from_uv(u, v), solid_angle, texture.sample(u, v, lod as f64)));
+		}
+	}
+	texels
+}
+
+// Project the radiance onto the first nine spherical harmonics
+fn project_sh(texture: &texture::Texture) -> [[f64; 3]; 9] {
+	let mut sh = [[0f64; 3]; 9];
+	for (d, solid_angle, c) in texels(texture, SH_WIDTH) {
+		let (x, y, z) = d.as_tuple();
+		let basis = [
+			0.282095f64,
+			0.488603f64 * y,
+			0.488603f64 * z,
+			0.488603f64 * x,
+			1.092548f64 * x * y,
+			1.092548f64 * y * z,
+			0.315392f64 * (3f64 * z * z - 1f64),
+			1.092548f64 * x * z,
+			0.546274f64 * (x * x - y * y),
+		];
+		for i in 0 .. 9 {
+			let w = basis[i] * solid_angle;
+			sh[i][0] += c.r as f64 * w;
+			sh[i][1] += c.g as f64 * w;
+			sh[i][2] += c.b as f64 * w;
+		}
+	}
+	sh
+}
+
+// Convolve the radiance with the GGX lobe for the roughness, assuming that the
+// view direction is the reflection direction. The lobe is importance sampled, and
+// each sample reads a mipmap level covering its share of the lobe, so that a small
+// number of samples doesn't alias.
+fn prefilter(radiance: &texture::Texture, level: usize, roughness: f64) -> texture::Texture {
+	let width = (radiance.get_width().min(SPECULAR_WIDTH) >> level).max(8);
+	let height = (width / 2).max(1);
+	let alpha = roughness * roughness;
+	let texel_solid_angle = 4f64 * f64::consts::PI / (radiance.get_width() * radiance.get_height()).max(1) as f64;
+
+	let mut image = image::Image::new(width, height, image::Format::RgbaF32);
+	for y in 0 .. height {
+		for x in 0 .. width {
+			let n = from_uv((x as f64 + 0.5f64) / width as f64, (y as f64 + 0.5f64) / height as f64);
+			let up = if n.0[1].abs() < 0.999f64 { vec::Vec3::new(0f64, 1f64, 0f64) } else { vec::Vec3::new(1f64, 0f64, 0f64) };
+			let tangent = up.cross(&n).normalize();
+			let bitangent = n.cross(&tangent);
+			let (mut sum_r, mut sum_g, mut sum_b, mut sum_w) = (0f64, 0f64, 0f64, 0f64);
+			for i in 0 .. PREFILTER_SAMPLES {
+				// half vector with the GGX distribution, in tangent space
+				let (xi0, xi1) = hammersley(i, PREFILTER_SAMPLES);
+				let phi = 2f64 * f64::consts::PI * xi0;
+				let cos = ((1f64 - xi1) / (1f64 + (alpha * alpha - 1f64) * xi1)).sqrt();
+				let sin = (1f64 - cos * cos).sqrt();
+				let h = tangent.scale(sin * phi.cos()).add(&bitangent.scale(sin * phi.sin())).add(&n.scale(cos));
+				let l = h.scale(2f64 * cos).sub(&n);
+				let n_l = n.dot(&l);
+				if n_l <= 0f64 {
+					continue;
+				}
+				// with n = v, the pdf of l is D / 4
+				let pdf = pbr::distribution_ggx(cos, alpha) / 4f64;
+				let sample_solid_angle = 1f64 / (PREFILTER_SAMPLES as f64 * pdf).max(1e-12);
+				let lod = 0.5f64 * (sample_solid_angle / texel_solid_angle).log2() + 1f64;
+				let (u, v) = to_uv(&l);
+				let c = radiance.sample(u, v, lod);
+				sum_r += c.r as f64 * n_l;
+				sum_g += c.g as f64 * n_l;
+				sum_b += c.b as f64 * n_l;
+				sum_w += n_l;
+			}
+			let c = if sum_w > 0f64 {
+				image::ColorF::new((sum_r / sum_w) as f32, (sum_g / sum_w) as f32, (sum_b / sum_w) as f32, 1f32)
+			} else {
+				image::ColorF::new(0f32, 0f32, 0f32, 1f32)
+			};
+			image.set_f(x, y, &c);
+		}
+	}
+	let mut texture = texture::Texture::new(image);
+	texture.sampler = sampler(texture::Filter::Bilinear);
+	texture
+}
+
+// Point i of n in the Hammersley sequence, which covers the unit square evenly
+fn hammersley(i: usize, n: usize) -> (f64, f64) {
+	(i as f64 / n as f64, (i as u32).reverse_bits() as f64 / 4294967296f64)
+}
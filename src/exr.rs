@@ -0,0 +1,582 @@
+extern crate flate2;
+
+use image;
+
+use std::{ fs, io, path };
+use std::io::Read;
+use std::io::Write;
+
+// OpenEXR single part scanline images.
+// Scanlines are stored top to bottom, whereas image rows are bottom to top.
+
+const EXR_MAGIC: u32 = 20000630;
+// Largest number of pixels which is read, so that a corrupt data window can't allocate without limit
+const MAX_PIXELS: usize = 1 << 28;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+	None,
+	Rle,
+	Zips,
+	Zip,
+}
+
+impl Compression {
+	fn from_u8(n: u8) -> io::Result<Self> {
+		match n {
+			0 => Ok(Compression::None),
+			1 => Ok(Compression::Rle),
+			2 => Ok(Compression::Zips),
+			3 => Ok(Compression::Zip),
+			_ => Err(io::Error::other("unsupported exr compression")),
+		}
+	}
+
+	fn to_u8(self) -> u8 {
+		match self {
+			Compression::None => 0,
+			Compression::Rle => 1,
+			Compression::Zips => 2,
+			Compression::Zip => 3,
+		}
+	}
+
+	fn lines_per_chunk(self) -> usize {
+		match self {
+			Compression::None | Compression::Rle | Compression::Zips => 1,
+			Compression::Zip => 16,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PixelType {
+	Uint,
+	Half,
+	Float,
+}
+
+impl PixelType {
+	fn bytes(self) -> usize {
+		match self {
+			PixelType::Half => 2,
+			PixelType::Uint | PixelType::Float => 4,
+		}
+	}
+
+	fn read(self, buf: &[u8]) -> f32 {
+		match self {
+			PixelType::Uint => u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f32,
+			PixelType::Half => half_to_f32(u16::from_le_bytes([buf[0], buf[1]])),
+			PixelType::Float => f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+		}
+	}
+}
+
+struct Channel {
+	name: String,
+	pixel_type: PixelType,
+}
+
+fn half_to_f32(h: u16) -> f32 {
+	let sign = ((h >> 15) as u32) << 31;
+	let exponent = ((h >> 10) & 0x1f) as u32;
+	let mantissa = (h & 0x3ff) as u32;
+	let bits = if exponent == 0 {
+		if mantissa == 0 {
+			sign
+		} else {
+			// subnormal
+			let value = mantissa as f32 / 1024f32 / 16384f32;
+			return if sign != 0 { -value } else { value };
+		}
+	} else if exponent == 0x1f {
+		sign | 0x7f80_0000 | mantissa << 13
+	} else {
+		sign | (exponent + 127 - 15) << 23 | mantissa << 13
+	};
+	f32::from_bits(bits)
+}
+
+fn read_u8(file: &mut dyn io::Read) -> io::Result<u8> {
+	let mut buf = [0; 1];
+	file.read_exact(&mut buf)?;
+	Ok(buf[0])
+}
+
+fn read_i32(file: &mut dyn io::Read) -> io::Result<i32> {
+	let mut buf = [0; 4];
+	file.read_exact(&mut buf)?;
+	Ok(i32::from_le_bytes(buf))
+}
+
+fn read_u64(file: &mut dyn io::Read) -> io::Result<u64> {
+	let mut buf = [0; 8];
+	file.read_exact(&mut buf)?;
+	Ok(u64::from_le_bytes(buf))
+}
+
+fn read_str(file: &mut dyn io::Read) -> io::Result<String> {
+	let mut s = Vec::new();
+	loop {
+		match read_u8(file)? {
+			0 => break,
+			c => s.push(c),
+		}
+		if s.len() > 255 {
+			return Err(io::Error::other("exr string is too long"));
+		}
+	}
+	String::from_utf8(s).map_err(io::Error::other)
+}
+
+fn read_channels(value: &[u8]) -> io::Result<Vec<Channel>> {
+	let mut channels = Vec::new();
+	let mut value = value;
+	while !value.is_empty() && value[0] != 0 {
+		let name = read_str(&mut value)?;
+		let pixel_type = match read_i32(&mut value)? {
+			0 => PixelType::Uint,
+			1 => PixelType::Half,
+			2 => PixelType::Float,
+			_ => return Err(io::Error::other("invalid exr pixel type")),
+		};
+		// pLinear, reserved, xSampling, ySampling
+		let mut rest = [0; 12];
+		value.read_exact(&mut rest)?;
+		if i32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) != 1
+				|| i32::from_le_bytes([rest[8], rest[9], rest[10], rest[11]]) != 1 {
+			return Err(io::Error::other("unsupported exr subsampling"));
+		}
+		channels.push(Channel {
+			name,
+			pixel_type,
+		});
+	}
+	Ok(channels)
+}
+
+pub fn read(path: &path::Path) -> io::Result<image::Image> {
+	let mut file = io::BufReader::new(fs::File::open(path)?);
+	if read_i32(&mut file)? as u32 != EXR_MAGIC {
+		return Err(io::Error::other("invalid exr magic"));
+	}
+	let version = read_i32(&mut file)?;
+	if version & 0xff != 2 || version & !0xff & !0x400 != 0 {
+		// only single part scanline images, optionally with long names
+		return Err(io::Error::other("unsupported exr version"));
+	}
+
+	let mut channels = None;
+	let mut compression = None;
+	let mut data_window = None;
+	loop {
+		let name = read_str(&mut file)?;
+		if name.is_empty() {
+			break;
+		}
+		let _type_name = read_str(&mut file)?;
+		let size = read_i32(&mut file)?;
+		if size < 0 {
+			return Err(io::Error::other("invalid exr attribute size"));
+		}
+		let mut value = vec![0; size as usize];
+		file.read_exact(&mut value)?;
+		match name.as_str() {
+			"channels" => channels = Some(read_channels(&value)?),
+			"compression" if size == 1 => compression = Some(Compression::from_u8(value[0])?),
+			"dataWindow" if size == 16 => {
+				let mut value = &value[..];
+				let mut window = [0; 4];
+				for w in &mut window {
+					*w = read_i32(&mut value)?;
+				}
+				data_window = Some(window);
+			},
+			_ => (),
+		}
+	}
+	let (channels, compression, data_window) = match (channels, compression, data_window) {
+		(Some(channels), Some(compression), Some(data_window)) => (channels, compression, data_window),
+		_ => return Err(io::Error::other("missing exr attribute")),
+	};
+	let [xmin, ymin, xmax, ymax] = data_window;
+	let (width, height) = match (window_size(xmin, xmax), window_size(ymin, ymax)) {
+		(Some(width), Some(height)) => (width, height),
+		_ => return Err(io::Error::other("invalid exr data window")),
+	};
+	if width.checked_mul(height).is_none_or(|pixels| pixels > MAX_PIXELS) {
+		return Err(io::Error::other("exr data window is too large"));
+	}
+
+	let lines_per_chunk = compression.lines_per_chunk();
+	let chunks = height.div_ceil(lines_per_chunk);
+	// Chunks are read sequentially, using the y coordinate stored in each
+	// chunk instead of the offset table.
+	for _ in 0 .. chunks {
+		read_u64(&mut file)?;
+	}
+
+	let bytes_per_pixel: usize = channels.iter().map(|c| c.pixel_type.bytes()).sum();
+	let bytes_per_line = width * bytes_per_pixel;
+	let mut image = image::Image::new(width, height, image::Format::RgbaF32);
+	let mut data = Vec::new();
+	for _ in 0 .. chunks {
+		let chunk_y = read_i32(&mut file)?;
+		let size = read_i32(&mut file)?;
+		if chunk_y < ymin || chunk_y > ymax || size < 0 {
+			return Err(io::Error::other("invalid exr chunk"));
+		}
+		let line = (chunk_y - ymin) as usize;
+		let lines = lines_per_chunk.min(height - line);
+		let expected = lines * bytes_per_line;
+		data.resize(size as usize, 0);
+		file.read_exact(&mut data)?;
+		let decompressed;
+		let pixels = if data.len() == expected {
+			&data[..]
+		} else {
+			decompressed = decompress(compression, &data, expected)?;
+			&decompressed[..]
+		};
+
+		for l in 0 .. lines {
+			let y = height - 1 - (line + l);
+			let mut offset = l * bytes_per_line;
+			let mut colors = vec![image::ColorF::new(0f32, 0f32, 0f32, 1f32); width];
+			for channel in &channels {
+				let bytes = channel.pixel_type.bytes();
+				for color in &mut colors {
+					let value = channel.pixel_type.read(&pixels[offset ..][.. bytes]);
+					match channel.name.as_str() {
+						"R" => color.r = value,
+						"G" => color.g = value,
+						"B" => color.b = value,
+						"A" => color.a = value,
+						"Y" => {
+							color.r = value;
+							color.g = value;
+							color.b = value;
+						},
+						_ => (),
+					}
+					offset += bytes;
+				}
+			}
+			for (x, color) in colors.iter().enumerate() {
+				image.set_f(x, y, color);
+			}
+		}
+	}
+	Ok(image)
+}
+
+// Number of pixels from min to max inclusive, if max isn't less than min
+fn window_size(min: i32, max: i32) -> Option<usize> {
+	let size = max.checked_sub(min)?.checked_add(1)?;
+	if size > 0 { Some(size as usize) } else { None }
+}
+
+fn decompress(compression: Compression, data: &[u8], expected: usize) -> io::Result<Vec<u8>> {
+	let mut buf = Vec::with_capacity(expected);
+	match compression {
+		Compression::None => return Err(io::Error::other("invalid exr chunk size")),
+		Compression::Rle => rle_decompress(data, &mut buf)?,
+		Compression::Zips | Compression::Zip => {
+			flate2::read::ZlibDecoder::new(data).read_to_end(&mut buf)?;
+		},
+	}
+	if buf.len() != expected {
+		return Err(io::Error::other("invalid exr chunk size"));
+	}
+
+	// undo the predictor
+	for i in 1 .. buf.len() {
+		buf[i] = buf[i - 1].wrapping_add(buf[i]).wrapping_sub(128);
+	}
+
+	// undo the interleaving
+	let half = buf.len().div_ceil(2);
+	let mut out = Vec::with_capacity(buf.len());
+	for i in 0 .. half {
+		out.push(buf[i]);
+		if half + i < buf.len() {
+			out.push(buf[half + i]);
+		}
+	}
+	Ok(out)
+}
+
+fn rle_decompress(data: &[u8], buf: &mut Vec<u8>) -> io::Result<()> {
+	let mut i = 0;
+	while i < data.len() {
+		let count = data[i] as i8;
+		i += 1;
+		if count < 0 {
+			let count = -(count as isize) as usize;
+			if i + count > data.len() {
+				return Err(io::Error::other("invalid rle length"));
+			}
+			buf.extend_from_slice(&data[i ..][.. count]);
+			i += count;
+		} else {
+			if i >= data.len() {
+				return Err(io::Error::other("invalid rle length"));
+			}
+			for _ in 0 .. count as usize + 1 {
+				buf.push(data[i]);
+			}
+			i += 1;
+		}
+	}
+	Ok(())
+}
+
+fn compress(compression: Compression, pixels: &[u8]) -> io::Result<Vec<u8>> {
+	// interleave the bytes and apply a predictor
+	let half = pixels.len().div_ceil(2);
+	let mut buf = vec![0; pixels.len()];
+	for (i, p) in pixels.iter().enumerate() {
+		buf[i / 2 + (i % 2) * half] = *p;
+	}
+	let mut prev = buf.first().cloned().unwrap_or(0);
+	for b in buf.iter_mut().skip(1) {
+		let cur = *b;
+		*b = cur.wrapping_sub(prev).wrapping_add(128);
+		prev = cur;
+	}
+
+	let mut out = Vec::new();
+	match compression {
+		Compression::None => return Ok(pixels.to_vec()),
+		Compression::Rle => rle_compress(&buf, &mut out),
+		Compression::Zips | Compression::Zip => {
+			let mut encoder = flate2::write::ZlibEncoder::new(out, flate2::Compression::default());
+			encoder.write_all(&buf)?;
+			out = encoder.finish()?;
+		},
+	}
+	if out.len() >= pixels.len() {
+		// store uncompressed if compression doesn't help
+		return Ok(pixels.to_vec());
+	}
+	Ok(out)
+}
+
+fn rle_compress(data: &[u8], out: &mut Vec<u8>) {
+	let mut start = 0;
+	while start < data.len() {
+		let mut end = start + 1;
+		while end < data.len() && data[end] == data[start] && end - start < 128 {
+			end += 1;
+		}
+		if end - start >= 3 {
+			out.push((end - start - 1) as u8);
+			out.push(data[start]);
+			start = end;
+			continue;
+		}
+		// literals until the next run of at least 3
+		end = start;
+		while end < data.len() && end - start < 127 {
+			if end + 2 < data.len() && data[end] == data[end + 1] && data[end] == data[end + 2] {
+				break;
+			}
+			end += 1;
+		}
+		out.push((-((end - start) as isize)) as u8);
+		out.extend_from_slice(&data[start .. end]);
+		start = end;
+	}
+}
+
+fn write_attribute(file: &mut dyn io::Write, name: &str, type_name: &str, value: &[u8]) -> io::Result<()> {
+	file.write_all(name.as_bytes())?;
+	file.write_all(&[0])?;
+	file.write_all(type_name.as_bytes())?;
+	file.write_all(&[0])?;
+	file.write_all(&(value.len() as i32).to_le_bytes())?;
+	file.write_all(value)
+}
+
+fn box2i(xmax: i32, ymax: i32) -> Vec<u8> {
+	let mut value = Vec::new();
+	for n in &[0, 0, xmax, ymax] {
+		value.extend_from_slice(&n.to_le_bytes());
+	}
+	value
+}
+
+// Channels are written as 32-bit floats.
+pub fn write(image: &image::Image, path: &path::Path, compression: Compression) -> io::Result<()> {
+	let mut file = io::BufWriter::new(fs::File::create(path)?);
+	let width = image.get_width();
+	let height = image.get_height();
+	if width == 0 || height == 0 {
+		return Err(io::Error::other("empty exr image"));
+	}
+
+	let mut header = Vec::new();
+	header.write_all(&EXR_MAGIC.to_le_bytes())?;
+	header.write_all(&2u32.to_le_bytes())?;
+
+	// channels must be sorted by name
+	let names = ["A", "B", "G", "R"];
+	let mut channels = Vec::new();
+	for name in &names {
+		channels.extend_from_slice(name.as_bytes());
+		channels.push(0);
+		channels.extend_from_slice(&2i32.to_le_bytes());
+		channels.extend_from_slice(&[0, 0, 0, 0]);
+		channels.extend_from_slice(&1i32.to_le_bytes());
+		channels.extend_from_slice(&1i32.to_le_bytes());
+	}
+	channels.push(0);
+	write_attribute(&mut header, "channels", "chlist", &channels)?;
+	write_attribute(&mut header, "compression", "compression", &[compression.to_u8()])?;
+	let window = box2i(width as i32 - 1, height as i32 - 1);
+	write_attribute(&mut header, "dataWindow", "box2i", &window)?;
+	write_attribute(&mut header, "displayWindow", "box2i", &window)?;
+	write_attribute(&mut header, "lineOrder", "lineOrder", &[0])?;
+	write_attribute(&mut header, "pixelAspectRatio", "float", &1f32.to_le_bytes())?;
+	write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+	write_attribute(&mut header, "screenWindowWidth", "float", &1f32.to_le_bytes())?;
+	header.write_all(&[0])?;
+	file.write_all(&header)?;
+
+	let lines_per_chunk = compression.lines_per_chunk();
+	let mut chunks = Vec::new();
+	let mut line = 0;
+	while line < height {
+		let lines = lines_per_chunk.min(height - line);
+		let mut pixels = Vec::with_capacity(lines * width * 16);
+		for l in 0 .. lines {
+			let y = height - 1 - (line + l);
+			let colors: Vec<image::ColorF> = (0 .. width).map(|x| image.get_f(x, y)).collect();
+			for name in &names {
+				for color in &colors {
+					let value = match *name {
+						"A" => color.a,
+						"B" => color.b,
+						"G" => color.g,
+						_ => color.r,
+					};
+					pixels.extend_from_slice(&value.to_le_bytes());
+				}
+			}
+		}
+		chunks.push((line, compress(compression, &pixels)?));
+		line += lines;
+	}
+
+	// offset table, followed by the chunks
+	let mut offset = (header.len() + chunks.len() * 8) as u64;
+	for chunk in &chunks {
+		file.write_all(&offset.to_le_bytes())?;
+		offset += 8 + chunk.1.len() as u64;
+	}
+	for chunk in &chunks {
+		file.write_all(&(chunk.0 as i32).to_le_bytes())?;
+		file.write_all(&(chunk.1.len() as i32).to_le_bytes())?;
+		file.write_all(&chunk.1)?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{ env, process };
+
+	// Smooth gradients, runs of equal pixels and a wide dynamic range
+	fn test_image(width: usize, height: usize) -> image::Image {
+		let mut image = image::Image::new(width, height, image::Format::RgbaF32);
+		for y in 0 .. height {
+			for x in 0 .. width {
+				let r = x as f32 * 0.37f32 + y as f32 * 0.1f32;
+				let g = if x < width / 2 { 0.25f32 } else { 3f32 };
+				let b = 2f32.powi(x as i32 % 16 - 8);
+				let a = (x + y) as f32 / (width + height) as f32;
+				image.set_f(x, y, &image::ColorF::new(r, g, b, a));
+			}
+		}
+		image
+	}
+
+	fn round_trip(compression: Compression, width: usize, height: usize) {
+		let path = env::temp_dir().join(format!("tinyrenderer-{}-{:?}-{}x{}.exr",
+							process::id(), compression, width, height));
+		let image = test_image(width, height);
+		write(&image, &path, compression).unwrap();
+		let result = read(&path);
+		fs::remove_file(&path).unwrap();
+		let result = result.unwrap();
+		assert_eq!((result.get_width(), result.get_height()), (width, height));
+		for y in 0 .. height {
+			for x in 0 .. width {
+				let a = image.get_f(x, y);
+				let b = result.get_f(x, y);
+				assert_eq!((a.r, a.g, a.b, a.a), (b.r, b.g, b.b, b.a), "({}, {})", x, y);
+			}
+		}
+	}
+
+	#[test]
+	fn round_trip_all() {
+		// odd sizes, and heights which aren't a multiple of the zip chunk size
+		for compression in [Compression::None, Compression::Rle, Compression::Zips, Compression::Zip].iter() {
+			round_trip(*compression, 1, 1);
+			round_trip(*compression, 7, 3);
+			round_trip(*compression, 33, 17);
+			round_trip(*compression, 300, 40);
+		}
+	}
+
+	// Write a small image, then replace its data window
+	fn read_with_window(window: [i32; 4]) -> io::Result<image::Image> {
+		let path = env::temp_dir().join(format!("tinyrenderer-{}-window-{:?}.exr", process::id(), window));
+		write(&test_image(2, 2), &path, Compression::None).unwrap();
+		let mut data = fs::read(&path).unwrap();
+		let name = b"dataWindow\0box2i\0";
+		let start = data.windows(name.len()).position(|w| w == &name[..]).unwrap() + name.len() + 4;
+		for (i, w) in window.iter().enumerate() {
+			data[start + i * 4 ..][.. 4].clone_from_slice(&w.to_le_bytes());
+		}
+		fs::write(&path, &data).unwrap();
+		let result = read(&path);
+		fs::remove_file(&path).unwrap();
+		result
+	}
+
+	#[test]
+	fn data_window() {
+		assert!(read_with_window([0, 0, 1, 1]).is_ok());
+		assert!(read_with_window([1, 0, 0, 1]).is_err());
+		// the size overflows
+		assert!(read_with_window([i32::MIN, 0, i32::MAX, 1]).is_err());
+		assert!(read_with_window([i32::MIN, 0, 1, 1]).is_err());
+		// valid, but too large to allocate
+		assert!(read_with_window([0, 0, 1 << 20, 1 << 20]).is_err());
+	}
+
+	#[test]
+	fn rle() {
+		let data: Vec<u8> = (0 .. 300).map(|i| if i < 200 { 7 } else { (i * 13) as u8 }).collect();
+		let mut compressed = Vec::new();
+		rle_compress(&data, &mut compressed);
+		assert!(compressed.len() < data.len());
+		let mut decompressed = Vec::new();
+		rle_decompress(&compressed, &mut decompressed).unwrap();
+		assert_eq!(decompressed, data);
+	}
+
+	#[test]
+	fn half() {
+		assert_eq!(half_to_f32(0x0000), 0f32);
+		assert_eq!(half_to_f32(0x3c00), 1f32);
+		assert_eq!(half_to_f32(0xc000), -2f32);
+		assert_eq!(half_to_f32(0x7bff), 65504f32);
+		assert_eq!(half_to_f32(0x0001), 2f32.powi(-24));
+		assert!(half_to_f32(0x7c00).is_infinite());
+	}
+}
@@ -0,0 +1,314 @@
+use image;
+
+use std::{ fs, io, path };
+use std::io::BufRead;
+use std::io::Write;
+
+// Radiance RGBE images.
+// Scanlines are stored top to bottom, whereas image rows are bottom to top.
+
+fn rgbe_to_color(rgbe: &[u8]) -> image::ColorF {
+	if rgbe[3] == 0 {
+		return image::ColorF::new(0f32, 0f32, 0f32, 1f32);
+	}
+	let f = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+	image::ColorF::new(
+		(rgbe[0] as f32 + 0.5f32) * f,
+		(rgbe[1] as f32 + 0.5f32) * f,
+		(rgbe[2] as f32 + 0.5f32) * f,
+		1f32)
+}
+
+fn color_to_rgbe(color: &image::ColorF, rgbe: &mut [u8]) {
+	let r = color.r.max(0f32);
+	let g = color.g.max(0f32);
+	let b = color.b.max(0f32);
+	let v = r.max(g).max(b);
+	// infinite colors are stored as black
+	if v < 1e-32f32 || !v.is_finite() {
+		rgbe.clone_from_slice(&[0; 4]);
+		return;
+	}
+	// v = m * 2^e, with m in [0.5, 1)
+	let mut e = v.log2().floor() as i32 + 1;
+	if v / 2f32.powi(e) >= 1f32 {
+		e += 1;
+	}
+	// the largest exponent has a saturated mantissa for larger values
+	let e = e.min(127);
+	let scale = 256f32 / 2f32.powi(e);
+	rgbe[0] = (r * scale).min(255f32) as u8;
+	rgbe[1] = (g * scale).min(255f32) as u8;
+	rgbe[2] = (b * scale).min(255f32) as u8;
+	rgbe[3] = (e + 128) as u8;
+}
+
+pub fn read(path: &path::Path) -> io::Result<image::Image> {
+	let mut file = io::BufReader::new(fs::File::open(path)?);
+
+	let mut line = String::new();
+	file.read_line(&mut line)?;
+	if !line.starts_with("#?") {
+		return Err(io::Error::other("invalid radiance header"));
+	}
+	loop {
+		line.clear();
+		if file.read_line(&mut line)? == 0 {
+			return Err(io::Error::other("missing radiance resolution"));
+		}
+		let line = line.trim();
+		if line.is_empty() {
+			break;
+		}
+		if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+			return Err(io::Error::other("unsupported radiance format"));
+		}
+	}
+
+	line.clear();
+	file.read_line(&mut line)?;
+	let words: Vec<&str> = line.split_whitespace().collect();
+	let (flip, height, width) = match words.as_slice() {
+		["-Y", h, "+X", w] => (true, h, w),
+		["+Y", h, "+X", w] => (false, h, w),
+		_ => return Err(io::Error::other("unsupported radiance resolution")),
+	};
+	let height = height.parse::<usize>().map_err(io::Error::other)?;
+	let width = width.parse::<usize>().map_err(io::Error::other)?;
+
+	let mut image = image::Image::new(width, height, image::Format::RgbaF32);
+	let mut scanline = vec![0; width * 4];
+	for j in 0 .. height {
+		read_scanline(&mut file, &mut scanline)?;
+		let y = if flip { height - 1 - j } else { j };
+		for x in 0 .. width {
+			image.set_f(x, y, &rgbe_to_color(&scanline[x * 4 ..][.. 4]));
+		}
+	}
+	Ok(image)
+}
+
+fn read_scanline(file: &mut dyn io::Read, scanline: &mut [u8]) -> io::Result<()> {
+	let width = scanline.len() / 4;
+	if width == 0 {
+		return Ok(());
+	}
+	let mut rgbe = [0; 4];
+	file.read_exact(&mut rgbe)?;
+	if !(8 .. 0x8000).contains(&width) || rgbe[0] != 2 || rgbe[1] != 2 || rgbe[2] & 0x80 != 0 {
+		// flat scanline, possibly with old style run lengths
+		scanline[.. 4].clone_from_slice(&rgbe);
+		return read_flat(file, scanline, 1);
+	}
+	if ((rgbe[2] as usize) << 8 | rgbe[3] as usize) != width {
+		return Err(io::Error::other("invalid radiance scanline width"));
+	}
+
+	// each component is run length encoded separately
+	let mut data = vec![0; width];
+	for i in 0 .. 4 {
+		let mut x = 0;
+		while x < width {
+			let mut code = [0; 1];
+			file.read_exact(&mut code)?;
+			if code[0] > 128 {
+				let run_length = (code[0] - 128) as usize;
+				if x + run_length > width {
+					return Err(io::Error::other("invalid rle length"));
+				}
+				let mut value = [0; 1];
+				file.read_exact(&mut value)?;
+				for d in &mut data[x ..][.. run_length] {
+					*d = value[0];
+				}
+				x += run_length;
+			} else {
+				let run_length = code[0] as usize;
+				if run_length == 0 || x + run_length > width {
+					return Err(io::Error::other("invalid rle length"));
+				}
+				file.read_exact(&mut data[x ..][.. run_length])?;
+				x += run_length;
+			}
+		}
+		for x in 0 .. width {
+			scanline[x * 4 + i] = data[x];
+		}
+	}
+	Ok(())
+}
+
+fn read_flat(file: &mut dyn io::Read, scanline: &mut [u8], mut x: usize) -> io::Result<()> {
+	let width = scanline.len() / 4;
+	let mut shift = 0;
+	while x < width {
+		let mut rgbe = [0; 4];
+		file.read_exact(&mut rgbe)?;
+		if rgbe[0] == 1 && rgbe[1] == 1 && rgbe[2] == 1 {
+			// repeat the previous pixel
+			if x == 0 {
+				return Err(io::Error::other("invalid rle run"));
+			}
+			let run_length = (rgbe[3] as usize) << shift;
+			if x + run_length > width {
+				return Err(io::Error::other("invalid rle length"));
+			}
+			for _ in 0 .. run_length {
+				let (prev, next) = scanline.split_at_mut(x * 4);
+				next[.. 4].clone_from_slice(&prev[(x - 1) * 4 ..]);
+				x += 1;
+			}
+			shift += 8;
+		} else {
+			scanline[x * 4 ..][.. 4].clone_from_slice(&rgbe);
+			x += 1;
+			shift = 0;
+		}
+	}
+	Ok(())
+}
+
+pub fn write(image: &image::Image, path: &path::Path) -> io::Result<()> {
+	let mut file = io::BufWriter::new(fs::File::create(path)?);
+	let width = image.get_width();
+	let height = image.get_height();
+
+	file.write_all(b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n")?;
+	writeln!(file, "-Y {} +X {}", height, width)?;
+
+	let mut scanline = vec![0; width * 4];
+	for j in 0 .. height {
+		let y = height - 1 - j;
+		for x in 0 .. width {
+			color_to_rgbe(&image.get_f(x, y), &mut scanline[x * 4 ..][.. 4]);
+		}
+		if (8 .. 0x8000).contains(&width) {
+			write_rle(&mut file, &scanline)?;
+		} else {
+			file.write_all(&scanline)?;
+		}
+	}
+	Ok(())
+}
+
+fn write_rle(file: &mut dyn io::Write, scanline: &[u8]) -> io::Result<()> {
+	let width = scanline.len() / 4;
+	file.write_all(&[2, 2, (width >> 8) as u8, width as u8])?;
+	let mut data = Vec::with_capacity(width);
+	for i in 0 .. 4 {
+		data.clear();
+		data.extend(scanline.chunks(4).map(|rgbe| rgbe[i]));
+		let mut start = 0;
+		while start < width {
+			// find the next run of at least 4 equal values
+			let mut run_start = start;
+			let mut run_length = 0;
+			while run_start < width {
+				run_length = 1;
+				while run_start + run_length < width && run_length < 127
+						&& data[run_start + run_length] == data[run_start] {
+					run_length += 1;
+				}
+				if run_length >= 4 {
+					break;
+				}
+				run_start += run_length;
+			}
+			if run_start >= width {
+				run_length = 0;
+			}
+			// write literals before the run
+			while start < run_start {
+				let count = (run_start - start).min(128);
+				file.write_all(&[count as u8])?;
+				file.write_all(&data[start ..][.. count])?;
+				start += count;
+			}
+			if run_length >= 4 {
+				file.write_all(&[128 + run_length as u8, data[run_start]])?;
+				start = run_start + run_length;
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{ env, process };
+
+	// Smooth gradients, runs of equal pixels and a wide dynamic range
+	fn test_image(width: usize, height: usize) -> image::Image {
+		let mut image = image::Image::new(width, height, image::Format::RgbaF32);
+		for y in 0 .. height {
+			for x in 0 .. width {
+				let r = x as f32 * 0.37f32 + y as f32 * 0.1f32;
+				let g = if x < width / 2 { 0.25f32 } else { 3f32 };
+				let b = 2f32.powi(x as i32 % 16 - 8);
+				image.set_f(x, y, &image::ColorF::new(r, g, b, 1f32));
+			}
+		}
+		image
+	}
+
+	fn round_trip(width: usize, height: usize) {
+		let path = env::temp_dir().join(format!("tinyrenderer-{}-{}x{}.hdr", process::id(), width, height));
+		let image = test_image(width, height);
+		write(&image, &path).unwrap();
+		let result = read(&path);
+		fs::remove_file(&path).unwrap();
+		let result = result.unwrap();
+		assert_eq!((result.get_width(), result.get_height()), (width, height));
+		for y in 0 .. height {
+			for x in 0 .. width {
+				let a = image.get_f(x, y);
+				let b = result.get_f(x, y);
+				// the mantissas are 8 bits relative to the largest component
+				let tolerance = a.r.max(a.g).max(a.b) / 128f32;
+				for (a, b) in [(a.r, b.r), (a.g, b.g), (a.b, b.b)].iter() {
+					assert!((a - b).abs() <= tolerance, "({}, {}): {} != {}", x, y, a, b);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn round_trip_flat() {
+		// scanlines narrower than 8 pixels can't be run length encoded
+		round_trip(1, 1);
+		round_trip(7, 3);
+	}
+
+	#[test]
+	fn round_trip_rle() {
+		round_trip(8, 2);
+		round_trip(33, 5);
+		round_trip(301, 4);
+	}
+
+	#[test]
+	fn black() {
+		let mut rgbe = [0; 4];
+		color_to_rgbe(&image::ColorF::new(0f32, 0f32, 0f32, 1f32), &mut rgbe);
+		assert_eq!(rgbe, [0; 4]);
+		let color = rgbe_to_color(&rgbe);
+		assert_eq!((color.r, color.g, color.b), (0f32, 0f32, 0f32));
+	}
+
+	#[test]
+	fn large_values_saturate() {
+		let mut rgbe = [0; 4];
+		color_to_rgbe(&image::ColorF::new(f32::MAX, 2f32.powi(127), 1f32, 1f32), &mut rgbe);
+		assert_eq!((rgbe[0], rgbe[1], rgbe[3]), (255, 255, 255));
+		let color = rgbe_to_color(&rgbe);
+		assert!(color.r > 2f32.powi(126) && color.r == color.g);
+	}
+
+	#[test]
+	fn infinite() {
+		let mut rgbe = [1; 4];
+		color_to_rgbe(&image::ColorF::new(f32::INFINITY, 1f32, 1f32, 1f32), &mut rgbe);
+		assert_eq!(rgbe, [0; 4]);
+	}
+}
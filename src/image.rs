@@ -1,3 +1,6 @@
+use depth;
+use pipeline;
+use texture;
 use vec;
 
 use std::{ cmp, mem };
@@ -14,10 +17,10 @@ pub struct Color {
 impl Color {
 	pub fn new(r: u8, g: u8, b: u8, a: u8) -> Color {
 		Color {
-			b: b,
-			g: g,
-			r: r,
-			a: a,
+			b,
+			g,
+			r,
+			a,
 		}
 	}
 
@@ -31,6 +34,10 @@ impl Color {
 	}
 
 	fn to_u8(&self, buf: &mut [u8]) {
+		if buf.len() == 1 {
+			buf[0] = ((self.r as u16 + self.g as u16 + self.b as u16) / 3) as u8;
+			return;
+		}
 		buf[0] = self.b;
 		buf[1] = self.g;
 		buf[2] = self.r;
@@ -39,14 +46,6 @@ impl Color {
 		}
 	}
 
-	// FIXME: move into model
-	pub fn to_vec3f(&self) -> vec::Vec3<f64> {
-		let convert = |x| {
-			(x as f64) * 2f64 / 255f64 - 1f64
-		};
-		vec::Vec3::new(convert(self.r), convert(self.g), convert(self.b))
-	}
-
 	pub fn map<F>(&self, convert: F) -> Self
 			where F: Fn(u8) -> u8 {
 		Color {
@@ -57,9 +56,89 @@ impl Color {
 		}
 	}
 
+	#[allow(dead_code)]
 	pub fn intensity(&self, intensity: f64) -> Self {
 		self.map(|x| { (x as f64 * intensity).round() as u8 })
 	}
+
+	pub fn to_color_f(&self) -> ColorF {
+		let convert = |x| {
+			x as f32 / 255f32
+		};
+		ColorF::new(convert(self.r), convert(self.g), convert(self.b), convert(self.a))
+	}
+}
+
+// Floating point color, where 1.0 corresponds to 255 in a Color.
+// Channels may exceed 1.0 until they are resolved to a Color.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ColorF {
+	pub r: f32,
+	pub g: f32,
+	pub b: f32,
+	pub a: f32,
+}
+
+impl ColorF {
+	pub fn new(r: f32, g: f32, b: f32, a: f32) -> ColorF {
+		ColorF {
+			r,
+			g,
+			b,
+			a,
+		}
+	}
+
+	fn from_f32(buf: &[u8]) -> Self {
+		let convert = |i: usize| {
+			f32::from_ne_bytes([buf[i * 4], buf[i * 4 + 1], buf[i * 4 + 2], buf[i * 4 + 3]])
+		};
+		ColorF::new(convert(0), convert(1), convert(2), convert(3))
+	}
+
+	fn to_f32(self, buf: &mut [u8]) {
+		buf[0..4].clone_from_slice(&self.r.to_ne_bytes());
+		buf[4..8].clone_from_slice(&self.g.to_ne_bytes());
+		buf[8..12].clone_from_slice(&self.b.to_ne_bytes());
+		buf[12..16].clone_from_slice(&self.a.to_ne_bytes());
+	}
+
+	// FIXME: move into model
+	pub fn to_vec3f(self) -> vec::Vec3<f64> {
+		let convert = |x: f32| {
+			x as f64 * 2f64 - 1f64
+		};
+		vec::Vec3::new(convert(self.r), convert(self.g), convert(self.b))
+	}
+
+	pub fn to_color(self) -> Color {
+		let convert = |x: f32| {
+			(x.clamp(0f32, 1f32) * 255f32).round() as u8
+		};
+		Color::new(convert(self.r), convert(self.g), convert(self.b), convert(self.a))
+	}
+
+	pub fn add(&self, c: &ColorF) -> Self {
+		ColorF::new(self.r + c.r, self.g + c.g, self.b + c.b, self.a)
+	}
+
+	pub fn mul(&self, c: &ColorF) -> Self {
+		ColorF::new(self.r * c.r, self.g * c.g, self.b * c.b, self.a)
+	}
+
+	pub fn scale(&self, n: f32) -> Self {
+		self.map(|x| { x * n })
+	}
+
+	pub fn map<F>(&self, convert: F) -> Self
+			where F: Fn(f32) -> f32 {
+		ColorF {
+			r: convert(self.r),
+			g: convert(self.g),
+			b: convert(self.b),
+			a: self.a,
+		}
+	}
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -67,6 +146,7 @@ pub enum Format {
 	Gray,
 	Rgb,
 	Rgba,
+	RgbaF32,
 }
 
 impl Format {
@@ -75,6 +155,7 @@ impl Format {
 			Format::Gray => 1,
 			Format::Rgb => 3,
 			Format::Rgba => 4,
+			Format::RgbaF32 => 16,
 		}
 	}
 
@@ -83,8 +164,54 @@ impl Format {
 			Format::Gray => 0,
 			Format::Rgb => 0,
 			Format::Rgba => 1,
+			Format::RgbaF32 => 4,
 		}
 	}
+
+	pub fn is_float(&self) -> bool {
+		*self == Format::RgbaF32
+	}
+
+	// 8-bit images are assumed to be sRGB encoded, and float images linear.
+	pub fn default_color_space(&self) -> ColorSpace {
+		if self.is_float() {
+			ColorSpace::Linear
+		} else {
+			ColorSpace::Srgb
+		}
+	}
+}
+
+// The encoding of the color channels of an image.
+// get_f and set_f convert between this and linear values.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorSpace {
+	Linear,
+	Srgb,
+}
+
+pub fn srgb_to_linear(x: f32) -> f32 {
+	if x <= 0.04045f32 {
+		x / 12.92f32
+	} else {
+		((x + 0.055f32) / 1.055f32).powf(2.4f32)
+	}
+}
+
+pub fn linear_to_srgb(x: f32) -> f32 {
+	if x <= 0.0031308f32 {
+		x * 12.92f32
+	} else {
+		1.055f32 * x.powf(1f32 / 2.4f32) - 0.055f32
+	}
+}
+
+// A distinct color for an ID, such as a material or a triangle index.
+// Consecutive IDs step the hue by the golden ratio, so that their colors are far apart.
+pub fn id_color(id: usize) -> ColorF {
+	let hue = (id as f32 * 0.618034f32).fract() * 6f32;
+	let channel = |offset: f32| (((hue + offset) % 6f32 - 3f32).abs() - 1f32).clamp(0f32, 1f32);
+	ColorF::new(channel(0f32), channel(4f32), channel(2f32), 1f32)
 }
 
 pub struct Image {
@@ -92,6 +219,7 @@ pub struct Image {
 	width: usize,
 	height: usize,
 	format: Format,
+	color_space: ColorSpace,
 }
 
 impl Default for Image {
@@ -105,19 +233,21 @@ impl Image {
 		let nbytes = width * height * format.bytes_per_pixel();
 		let data = vec![0; nbytes];
 		Image {
-			data: data,
-			width: width,
-			height: height,
-			format: format,
+			data,
+			width,
+			height,
+			format,
+			color_space: format.default_color_space(),
 		}
 	}
 
 	pub fn from_data(width: usize, height: usize, format: Format, data: Vec<u8>) -> Self {
 		Image {
-			data: data,
-			width: width,
-			height: height,
-			format: format,
+			data,
+			width,
+			height,
+			format,
+			color_space: format.default_color_space(),
 		}
 	}
 
@@ -137,11 +267,24 @@ impl Image {
 		self.format
 	}
 
+	pub fn get_color_space(&self) -> ColorSpace {
+		self.color_space
+	}
+
+	pub fn set_color_space(&mut self, color_space: ColorSpace) {
+		self.color_space = color_space;
+	}
+
+	#[allow(dead_code)]
 	pub fn get(&self, x: usize, y: usize) -> Color {
 		if x < self.width && y < self.height {
 		        let bytes_per_pixel = self.format.bytes_per_pixel();
 			let offset = (x + y * self.width) * bytes_per_pixel;
-			Color::from_u8(&self.data[offset..][..bytes_per_pixel])
+			if self.format.is_float() {
+				ColorF::from_f32(&self.data[offset..][..bytes_per_pixel]).to_color()
+			} else {
+				Color::from_u8(&self.data[offset..][..bytes_per_pixel])
+			}
 		} else {
 			Color::new(0, 0, 0, 255)
 		}
@@ -151,18 +294,60 @@ impl Image {
 		if x < self.width && y < self.height {
 		        let bytes_per_pixel = self.format.bytes_per_pixel();
 			let offset = (x + y * self.width) * bytes_per_pixel;
-			color.to_u8(&mut self.data[offset..][..bytes_per_pixel]);
+			if self.format.is_float() {
+				color.to_color_f().to_f32(&mut self.data[offset..][..bytes_per_pixel]);
+			} else {
+				color.to_u8(&mut self.data[offset..][..bytes_per_pixel]);
+			}
+		}
+	}
+
+	// Returns a linear color, decoding it from the image's color space
+	pub fn get_f(&self, x: usize, y: usize) -> ColorF {
+		if x < self.width && y < self.height {
+		        let bytes_per_pixel = self.format.bytes_per_pixel();
+			let offset = (x + y * self.width) * bytes_per_pixel;
+			let color = if self.format.is_float() {
+				ColorF::from_f32(&self.data[offset..][..bytes_per_pixel])
+			} else {
+				Color::from_u8(&self.data[offset..][..bytes_per_pixel]).to_color_f()
+			};
+			match self.color_space {
+				ColorSpace::Linear => color,
+				ColorSpace::Srgb => color.map(srgb_to_linear),
+			}
+		} else {
+			ColorF::new(0f32, 0f32, 0f32, 1f32)
+		}
+	}
+
+	// Stores a linear color, encoding it to the image's color space
+	pub fn set_f(&mut self, x: usize, y: usize, color: &ColorF) {
+		if x < self.width && y < self.height {
+		        let bytes_per_pixel = self.format.bytes_per_pixel();
+			let offset = (x + y * self.width) * bytes_per_pixel;
+			let color = match self.color_space {
+				ColorSpace::Linear => *color,
+				ColorSpace::Srgb => color.map(|x| { linear_to_srgb(x.max(0f32)) }),
+			};
+			if self.format.is_float() {
+				color.to_f32(&mut self.data[offset..][..bytes_per_pixel]);
+			} else {
+				color.to_color().to_u8(&mut self.data[offset..][..bytes_per_pixel]);
+			}
 		}
 	}
 
 	pub fn flip_horizontally(&mut self) {
-		let width = self.width;
-		for i in 0 .. width/2 {
-			for j in 0 .. self.height {
-				let c1 = self.get(i, j);
-				let c2 = self.get(width - 1 - i, j);
-				self.set(i, j, &c2);
-				self.set(width - 1 - i, j, &c1);
+		let bytes_per_pixel = self.format.bytes_per_pixel();
+		let bytes_per_line = self.width * bytes_per_pixel;
+		if bytes_per_line == 0 {
+			return;
+		}
+		for line in self.data.chunks_mut(bytes_per_line) {
+			for i in 0 .. self.width / 2 {
+				let (left, right) = line.split_at_mut((self.width - 1 - i) * bytes_per_pixel);
+				left[i * bytes_per_pixel ..][.. bytes_per_pixel].swap_with_slice(&mut right[.. bytes_per_pixel]);
 			}
 		}
 	}
@@ -216,10 +401,10 @@ impl LineState {
 			a: a0,
 			a_final: a1,
 			b: b0,
-			b_frac: b_frac,
-			da: da,
-			db: db,
-			dir: dir,
+			b_frac,
+			da,
+			db,
+			dir,
 		}
 	}
 
@@ -239,6 +424,38 @@ impl LineState {
 	}
 }
 
+// Shape of the ends of a thick line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineCap {
+	// ends at the endpoints
+	Butt,
+	// extends past the endpoints by a semicircle
+	Round,
+	// extends past the endpoints by half the width
+	Square,
+}
+
+// Style for thick anti-aliased lines
+#[derive(Clone, Debug)]
+pub struct LineStyle {
+	// width in pixels
+	pub width: f64,
+	pub cap: LineCap,
+	// Alternating lengths of dashes and gaps in pixels, starting with a dash.
+	// The line is solid if this is empty.
+	pub dashes: Vec<f64>,
+}
+
+impl Default for LineStyle {
+	fn default() -> Self {
+		LineStyle {
+			width: 1f64,
+			cap: LineCap::Butt,
+			dashes: Vec::new(),
+		}
+	}
+}
+
 impl Image {
 	#[allow(dead_code)]
 	pub fn line<'a>(&mut self,
@@ -271,6 +488,79 @@ impl Image {
 		}
 	}
 
+	// Anti-aliased line one pixel wide, using Xiaolin Wu's algorithm.
+	// The color is alpha blended using the coverage of each pixel.
+	pub fn line_aa(&mut self, p0: &vec::Vec2<f64>, p1: &vec::Vec2<f64>, color: &ColorF) {
+		let (mut x0, mut y0) = p0.as_tuple();
+		let (mut x1, mut y1) = p1.as_tuple();
+		let steep = (y1 - y0).abs() > (x1 - x0).abs();
+		if steep {
+			mem::swap(&mut x0, &mut y0);
+			mem::swap(&mut x1, &mut y1);
+		}
+		if x0 > x1 {
+			mem::swap(&mut x0, &mut x1);
+			mem::swap(&mut y0, &mut y1);
+		}
+		let size = if steep { self.height } else { self.width } as f64;
+		// swap the coordinates back when plotting
+		let mut plot = |a: f64, b: f64, coverage: f64| {
+			let (x, y) = if steep { (b, a) } else { (a, b) };
+			self.blend_coverage(x, y, color, coverage as f32);
+		};
+		let fract = |x: f64| x - x.floor();
+		let dx = x1 - x0;
+		let gradient = if dx == 0f64 { 1f64 } else { (y1 - y0) / dx };
+
+		// The endpoints are weighted by how much of their pixel the line covers along its major axis
+		let xend0 = x0.round();
+		let yend0 = y0 + gradient * (xend0 - x0);
+		let xgap0 = 1f64 - fract(x0 + 0.5f64);
+		plot(xend0, yend0.floor(), (1f64 - fract(yend0)) * xgap0);
+		plot(xend0, yend0.floor() + 1f64, fract(yend0) * xgap0);
+
+		let xend1 = x1.round();
+		let yend1 = y1 + gradient * (xend1 - x1);
+		let xgap1 = fract(x1 + 0.5f64);
+		plot(xend1, yend1.floor(), (1f64 - fract(yend1)) * xgap1);
+		plot(xend1, yend1.floor() + 1f64, fract(yend1) * xgap1);
+
+		// Only step over the pixels which are inside the image
+		let start = (xend0 + 1f64).max(-1f64);
+		let end = xend1.min(size);
+		let mut x = start;
+		while x < end {
+			let y = yend0 + gradient * (x - xend0);
+			plot(x, y.floor(), 1f64 - fract(y));
+			plot(x, y.floor() + 1f64, fract(y));
+			x += 1f64;
+		}
+	}
+
+	// Anti-aliased line with a width, caps and dashes.
+	// The color is alpha blended using the coverage of each pixel.
+	pub fn stroke(&mut self, p0: &vec::Vec2<f64>, p1: &vec::Vec2<f64>, color: &ColorF, style: &LineStyle) {
+		let (x0, y0) = p0.as_tuple();
+		let (x1, y1) = p1.as_tuple();
+		let size = (self.width, self.height);
+		dash_path(&[vec::Vec2::new(x0, y0), vec::Vec2::new(x1, y1)], false, &style.dashes, |a, b, _, _| {
+			stroke_coverage(a, b, style.width, (style.cap, style.cap), size, |x, y, coverage| {
+				self.blend_coverage(x as f64, y as f64, color, coverage);
+			});
+		});
+	}
+
+	// Alpha blend a color over a pixel, scaling its alpha by the coverage
+	pub fn blend_coverage(&mut self, x: f64, y: f64, color: &ColorF, coverage: f32) {
+		if x < 0f64 || y < 0f64 || x >= self.width as f64 || y >= self.height as f64 || coverage <= 0f32 {
+			return;
+		}
+		let (x, y) = (x as usize, y as usize);
+		let src = ColorF { a: color.a * coverage.min(1f32), ..*color };
+		let dst = self.get_f(x, y);
+		self.set_f(x, y, &Blend::Alpha.apply(&src, &dst));
+	}
+
 	pub fn horizontal_line(&mut self,
 		    mut x0: i32, mut x1: i32, y: i32,
 		    color: &Color) {
@@ -361,66 +651,1049 @@ impl Image {
 			l0 <= 0 && l1 <= 0 && l2 <= 0
 		}
 	}
+}
 
-	fn barycentric(&self, p: &vec::Vec2<f64>,
-		  p0: &vec::Vec3<f64>,
-		  p1: &vec::Vec3<f64>,
-		  p2: &vec::Vec3<f64>)
-		  -> Option<vec::Vec3<f64>> {
-		let v1 = vec::Vec3::new(p1.0[0] - p0.0[0], p2.0[0] - p0.0[0], p0.0[0] - p.0[0]);
-		let v2 = vec::Vec3::new(p1.0[1] - p0.0[1], p2.0[1] - p0.0[1], p0.0[1] - p.0[1]);
-		let (l1, l2, scale) = v1.cross(&v2).as_tuple();
-		if scale == 0f64 {
-			return None;
-		}
-		let l1 = l1 / scale;
-		let l2 = l2 / scale;
-		let l0 = 1f64 - l1 - l2;
-		if l0 < 0f64 || l1 < 0f64 || l2 < 0f64 {
-			return None;
-		}
-		Some(vec::Vec3::new(l0, l1, l2))
-	}
-
-	pub fn render(&mut self, shader: &Shader, viewport: &vec::Transform4<f64>,
-		      p0: &vec::Vec4<f64>, p1: &vec::Vec4<f64>, p2: &vec::Vec4<f64>,
-		      zbuffer: &mut [f64]) {
-		let clip0 = &p0.transform(viewport);
-		let clip1 = &p1.transform(viewport);
-		let clip2 = &p2.transform(viewport);
-		let screen0 = &clip0.to_pt3();
-		let screen1 = &clip1.to_pt3();
-		let screen2 = &clip2.to_pt3();
-		let minx = cmp::max(0, screen0.0[0].min(screen1.0[0].min(screen2.0[0])).ceil() as i32);
-		let miny = cmp::max(0, screen0.0[1].min(screen1.0[1].min(screen2.0[1])).ceil() as i32);
-		let maxx = cmp::min(self.width as i32 - 1, screen0.0[0].max(screen1.0[0].max(screen2.0[0])).floor() as i32);
-		let maxy = cmp::min(self.height as i32 - 1, screen0.0[1].max(screen1.0[1].max(screen2.0[1])).floor() as i32);
-		for y in miny .. maxy + 1 {
-			for x in minx .. maxx + 1 {
-				match self.barycentric(&vec::Vec2::new(x as f64, y as f64), screen0, screen1, screen2) {
-					None => (),
-					Some(screen_bc) => {
-						let z = screen0.0[2] * screen_bc.0[0] + screen1.0[2] * screen_bc.0[1] + screen2.0[2] * screen_bc.0[2];
-						if zbuffer[x as usize + y as usize * self.width] < z {
-							let clip_bc = vec::Vec3([
-										screen_bc.0[0] / clip0.0[3],
-										screen_bc.0[1] / clip1.0[3],
-										screen_bc.0[2] / clip2.0[3]
-										]);
-							let clip_bc = clip_bc.scale(1f64 / (clip_bc.0[0] + clip_bc.0[1] + clip_bc.0[2]));
-							shader.fragment(&clip_bc).map(|color| {
-								zbuffer[x as usize + y as usize * self.width] = z;
-								self.set(x as usize, y as usize, &color);
-							});
-						}
+// Coverage of the pixels within half the width of a line segment, using a signed distance to
+// the segment for the anti-aliased edges. The caps are for the start and end of the segment.
+// Only pixels inside an image of the given size are plotted.
+pub fn stroke_coverage<F>(p0: &vec::Vec2<f64>, p1: &vec::Vec2<f64>, width: f64, caps: (LineCap, LineCap),
+			  size: (usize, usize), mut plot: F)
+		where F: FnMut(usize, usize, f32) {
+	let (dx, dy) = (p1.0[0] - p0.0[0], p1.0[1] - p0.0[1]);
+	let length = (dx * dx + dy * dy).sqrt();
+	let (ux, uy) = if length == 0f64 { (1f64, 0f64) } else { (dx / length, dy / length) };
+	let half = width / 2f64;
+	let extent = half + 1f64;
+	let minx = (p0.0[0].min(p1.0[0]) - extent).floor().max(0f64);
+	let miny = (p0.0[1].min(p1.0[1]) - extent).floor().max(0f64);
+	let maxx = (p0.0[0].max(p1.0[0]) + extent).ceil().min(size.0 as f64 - 1f64);
+	let maxy = (p0.0[1].max(p1.0[1]) + extent).ceil().min(size.1 as f64 - 1f64);
+	if minx > maxx || miny > maxy {
+		return;
+	}
+	// the rectangle along the segment extends past square caps by half the width
+	let extend = |cap: LineCap| if cap == LineCap::Square { half } else { 0f64 };
+	let (start, end) = (-extend(caps.0), length + extend(caps.1));
+	for y in miny as usize .. maxy as usize + 1 {
+		for x in minx as usize .. maxx as usize + 1 {
+			let (px, py) = (x as f64 - p0.0[0], y as f64 - p0.0[1]);
+			let along = px * ux + py * uy;
+			let across = py * ux - px * uy;
+			// distance to the rectangle
+			let qx = (along - (start + end) / 2f64).abs() - (end - start) / 2f64;
+			let qy = across.abs() - half;
+			let mut distance = (qx.max(0f64) * qx.max(0f64) + qy.max(0f64) * qy.max(0f64)).sqrt() + qx.max(qy).min(0f64);
+			// union with the circles of round caps
+			if caps.0 == LineCap::Round {
+				distance = distance.min((along * along + across * across).sqrt() - half);
+			}
+			if caps.1 == LineCap::Round {
+				distance = distance.min(((along - length) * (along - length) + across * across).sqrt() - half);
+			}
+			let coverage = (0.5f64 - distance).clamp(0f64, 1f64);
+			if coverage > 0f64 {
+				plot(x, y, coverage as f32);
+			}
+		}
+	}
+}
+
+// Split a path into the pieces covered by the dashes, continuing the pattern across the vertices.
+// Dashes are alternating lengths of dashes and gaps, starting with a dash, and the path is
+// solid if they are empty. The flags are set for the start and end of pieces which join
+// another piece at a vertex.
+pub fn dash_path<F>(points: &[vec::Vec2<f64>], closed: bool, dashes: &[f64], mut piece: F)
+		where F: FnMut(&vec::Vec2<f64>, &vec::Vec2<f64>, bool, bool) {
+	if points.len() < 2 {
+		return;
+	}
+	let edges = if closed { points.len() } else { points.len() - 1 };
+	let pattern: f64 = dashes.iter().map(|dash| dash.max(0f64)).sum();
+	if pattern <= 0f64 {
+		for i in 0 .. edges {
+			piece(&points[i], &points[(i + 1) % points.len()], closed || i > 0, closed || i + 1 < edges);
+		}
+		return;
+	}
+	let mut dash = 0;
+	// dashes and gaps alternate, also when an odd number of entries repeats
+	let mut on = true;
+	let mut remaining = dashes[0].max(0f64);
+	// whether the previous piece ended at a vertex in the middle of a dash
+	let mut joined = false;
+	for i in 0 .. edges {
+		let (a, b) = (&points[i], &points[(i + 1) % points.len()]);
+		let (dx, dy) = (b.0[0] - a.0[0], b.0[1] - a.0[1]);
+		let length = (dx * dx + dy * dy).sqrt();
+		if length == 0f64 {
+			continue;
+		}
+		let point = |t: f64| vec::Vec2::new(a.0[0] + dx * t / length, a.0[1] + dy * t / length);
+		let mut t = 0f64;
+		while t < length {
+			// whether the current dash or gap ends on this edge
+			let finished = remaining <= length - t;
+			let end = if finished { t + remaining } else { length };
+			if on && end > t {
+				let join = !finished && i + 1 < edges;
+				piece(&point(t), &point(end), joined && t == 0f64, join);
+				joined = join;
+			}
+			if finished {
+				dash = (dash + 1) % dashes.len();
+				on = !on;
+				remaining = dashes[dash].max(0f64);
+			} else {
+				remaining -= length - t;
+			}
+			t = end;
+		}
+	}
+}
+
+impl pipeline::Surface for Image {
+	fn get_width(&self) -> usize {
+		self.width
+	}
+
+	fn get_height(&self) -> usize {
+		self.height
+	}
+
+	fn get_offsets(&self) -> &'static [(f64, f64)] {
+		SAMPLES_1
+	}
+}
+
+impl pipeline::Target<ColorF> for Image {
+	fn write(&mut self, x: usize, y: usize, _mask: u32, _depth: f64, color: &ColorF, blend: Blend) {
+		let dst = self.get_f(x, y);
+		self.set_f(x, y, &blend.apply(color, &dst));
+	}
+}
+
+// How a fragment color is combined with the color already in the target, using the fragment alpha
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Blend {
+	Replace,
+	Alpha,
+	Additive,
+	Multiply,
+}
+
+impl Blend {
+	pub fn from_name(name: &str) -> Option<Blend> {
+		match name {
+			"replace" => Some(Blend::Replace),
+			"alpha" => Some(Blend::Alpha),
+			"additive" => Some(Blend::Additive),
+			"multiply" => Some(Blend::Multiply),
+			_ => None,
+		}
+	}
+
+	pub fn apply(&self, src: &ColorF, dst: &ColorF) -> ColorF {
+		let a = src.a;
+		match *self {
+			Blend::Replace => *src,
+			Blend::Alpha => {
+				let c = src.scale(a).add(&dst.scale(1f32 - a));
+				ColorF::new(c.r, c.g, c.b, a + dst.a * (1f32 - a))
+			},
+			Blend::Additive => {
+				let c = dst.add(&src.scale(a));
+				ColorF::new(c.r, c.g, c.b, dst.a)
+			},
+			Blend::Multiply => {
+				let c = dst.mul(&src.scale(a).map(|x| { x + 1f32 - a }));
+				ColorF::new(c.r, c.g, c.b, dst.a)
+			},
+		}
+	}
+}
+
+// How transparent triangles are ordered for blending
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transparency {
+	// back to front, using the blend mode
+	Sort,
+	// order independent, approximating alpha blending
+	WeightedBlended,
+}
+
+impl Transparency {
+	pub fn from_name(name: &str) -> Option<Transparency> {
+		match name {
+			"sort" => Some(Transparency::Sort),
+			"oit" => Some(Transparency::WeightedBlended),
+			_ => None,
+		}
+	}
+}
+
+// Sample positions relative to the pixel position, in the standard Direct3D patterns.
+const SAMPLES_1: &[(f64, f64)] = &[ (0f64, 0f64) ];
+const SAMPLES_2: &[(f64, f64)] = &[ (0.25f64, 0.25f64), (-0.25f64, -0.25f64) ];
+const SAMPLES_4: &[(f64, f64)] = &[
+	(-0.125f64, -0.375f64), (0.375f64, -0.125f64), (-0.375f64, 0.125f64), (0.125f64, 0.375f64),
+];
+const SAMPLES_8: &[(f64, f64)] = &[
+	(0.0625f64, -0.1875f64), (-0.0625f64, 0.1875f64), (0.3125f64, 0.0625f64), (-0.1875f64, -0.3125f64),
+	(-0.3125f64, 0.3125f64), (-0.4375f64, -0.0625f64), (0.1875f64, 0.4375f64), (0.4375f64, -0.4375f64),
+];
+const SAMPLES_16: &[(f64, f64)] = &[
+	(0.0625f64, 0.0625f64), (-0.0625f64, -0.1875f64), (-0.1875f64, 0.125f64), (0.25f64, -0.0625f64),
+	(-0.3125f64, -0.125f64), (0.125f64, 0.3125f64), (0.3125f64, 0.1875f64), (0.1875f64, -0.3125f64),
+	(-0.125f64, 0.375f64), (0f64, -0.4375f64), (-0.25f64, -0.375f64), (-0.375f64, 0.25f64),
+	(-0.5f64, 0f64), (0.4375f64, -0.25f64), (0.375f64, 0.4375f64), (-0.4375f64, -0.5f64),
+];
+const MAX_SAMPLES: usize = 16;
+
+// Color samples for multisample anti-aliasing.
+// Coverage and depth are tested for each sample, but fragments are shaded once per pixel.
+pub struct Multisample {
+	width: usize,
+	height: usize,
+	offsets: &'static [(f64, f64)],
+	color: Vec<ColorF>,
+
+	// Accumulate fragments for weighted blended order independent transparency,
+	// instead of blending them in order
+	pub oit: bool,
+	// premultiplied color multiplied by the weight, and the weight in alpha
+	accum: Vec<ColorF>,
+	// product of (1 - alpha)
+	revealage: Vec<f32>,
+}
+
+impl Multisample {
+	// Returns None if the number of samples is not 1, 2, 4, 8 or 16.
+	pub fn new(width: usize, height: usize, samples: usize) -> Option<Multisample> {
+		let offsets = match samples {
+			1 => SAMPLES_1,
+			2 => SAMPLES_2,
+			4 => SAMPLES_4,
+			8 => SAMPLES_8,
+			16 => SAMPLES_16,
+			_ => return None,
+		};
+		Some(Multisample {
+			width,
+			height,
+			offsets,
+			color: vec![ColorF::default(); width * height * samples],
+			oit: false,
+			accum: Vec::new(),
+			revealage: Vec::new(),
+		})
+	}
+
+	pub fn get_samples(&self) -> usize {
+		self.offsets.len()
+	}
+
+	// Position of a sample relative to the pixel position
+	pub fn get_offset(&self, sample: usize) -> (f64, f64) {
+		self.offsets[sample]
+	}
+
+	// Samples for a pixel are adjacent, as they are in the depth buffer for rendering.
+	pub fn set(&mut self, x: usize, y: usize, sample: usize, color: &ColorF) {
+		self.color[(x + y * self.width) * self.offsets.len() + sample] = *color;
+	}
+
+	// Blend the accumulated order independent transparency over the color samples
+	pub fn composite_oit(&mut self) {
+		for i in 0 .. self.accum.len() {
+			let revealage = self.revealage[i];
+			if revealage == 1f32 {
+				continue;
+			}
+			let accum = &self.accum[i];
+			let average = accum.scale(1f32 / accum.a.max(1e-5));
+			let dst = &self.color[i];
+			let c = average.scale(1f32 - revealage).add(&dst.scale(revealage));
+			self.color[i] = ColorF::new(c.r, c.g, c.b, 1f32 - revealage + dst.a * revealage);
+		}
+		self.accum.clear();
+		self.revealage.clear();
+	}
+
+	// Average the samples of each pixel
+	pub fn resolve(&self, format: Format) -> Image {
+		let samples = self.offsets.len();
+		let mut image = Image::new(self.width, self.height, format);
+		for y in 0 .. self.height {
+			for x in 0 .. self.width {
+				let i = (x + y * self.width) * samples;
+				let mut sum = ColorF::new(0f32, 0f32, 0f32, 0f32);
+				for c in &self.color[i .. i + samples] {
+					sum = ColorF::new(sum.r + c.r, sum.g + c.g, sum.b + c.b, sum.a + c.a);
+				}
+				let n = samples as f32;
+				image.set_f(x, y, &ColorF::new(sum.r / n, sum.g / n, sum.b / n, sum.a / n));
+			}
+		}
+		image
+	}
+}
+
+impl pipeline::Surface for Multisample {
+	fn get_width(&self) -> usize {
+		self.width
+	}
+
+	fn get_height(&self) -> usize {
+		self.height
+	}
+
+	fn get_offsets(&self) -> &'static [(f64, f64)] {
+		self.offsets
+	}
+}
+
+impl pipeline::Target<ColorF> for Multisample {
+	fn write(&mut self, x: usize, y: usize, mask: u32, depth: f64, color: &ColorF, blend: Blend) {
+		let samples = self.offsets.len();
+		if self.oit && self.accum.is_empty() {
+			self.accum = vec![ColorF::new(0f32, 0f32, 0f32, 0f32); self.color.len()];
+			self.revealage = vec![1f32; self.color.len()];
+		}
+		let first = (x + y * self.width) * samples;
+		for sample in 0 .. samples {
+			if mask & (1 << sample) == 0 {
+				continue;
+			}
+			let i = first + sample;
+			if self.oit {
+				// McGuire and Bavoil, equation 9
+				let weight = color.a * (3e3f64 * (1f64 - depth).powi(3)).max(1e-2) as f32;
+				let c = color.scale(color.a * weight);
+				self.accum[i] = self.accum[i].add(&c);
+				self.accum[i].a += color.a * weight;
+				self.revealage[i] *= 1f32 - color.a;
+			} else {
+				self.color[i] = blend.apply(color, &self.color[i]);
+			}
+		}
+	}
+}
+
+// Images of the same size which are written in one pass, each with one of the colors output by the fragment shader
+pub struct RenderTargets {
+	pub images: Vec<Image>,
+}
+
+impl RenderTargets {
+	pub fn new(width: usize, height: usize, formats: &[Format]) -> Self {
+		RenderTargets {
+			images: formats.iter().map(|&format| Image::new(width, height, format)).collect(),
+		}
+	}
+
+	pub fn get_width(&self) -> usize {
+		self.images[0].width
+	}
+
+	pub fn get_height(&self) -> usize {
+		self.images[0].height
+	}
+}
+
+impl pipeline::Surface for RenderTargets {
+	fn get_width(&self) -> usize {
+		self.images[0].width
+	}
+
+	fn get_height(&self) -> usize {
+		self.images[0].height
+	}
+
+	fn get_offsets(&self) -> &'static [(f64, f64)] {
+		SAMPLES_1
+	}
+}
+
+impl<C: AsRef<[ColorF]>> pipeline::Target<C> for RenderTargets {
+	fn write(&mut self, x: usize, y: usize, _mask: u32, _depth: f64, colors: &C, blend: Blend) {
+		for (image, color) in self.images.iter_mut().zip(colors.as_ref()) {
+			let dst = image.get_f(x, y);
+			image.set_f(x, y, &blend.apply(color, &dst));
+		}
+	}
+}
+
+// Target for depth only passes, which has the size of the depth buffer
+pub struct DepthOnly;
+
+impl pipeline::Surface for DepthOnly {
+	fn get_width(&self) -> usize {
+		usize::MAX
+	}
+
+	fn get_height(&self) -> usize {
+		usize::MAX
+	}
+
+	fn get_offsets(&self) -> &'static [(f64, f64)] {
+		SAMPLES_1
+	}
+}
+
+impl<C> pipeline::Target<C> for DepthOnly {
+	fn write(&mut self, _x: usize, _y: usize, _mask: u32, _depth: f64, _color: &C, _blend: Blend) {
+	}
+}
+
+// Shader for depth only passes
+pub struct DepthShader<'a> {
+	pub transform: &'a vec::Transform4<f64>,
+	// diffuse texture and threshold, to discard fragments with a lower alpha
+	pub alpha_test: Option<(&'a texture::Texture, f32)>,
+}
+
+impl<'a> DepthShader<'a> {
+	pub fn new(transform: &'a vec::Transform4<f64>) -> Self {
+		DepthShader {
+			transform,
+			alpha_test: None,
+		}
+	}
+}
+
+impl<'a> Shader for DepthShader<'a> {
+	// texture coordinates
+	type Varyings = vec::Vec2<f64>;
+	type Output = ColorF;
+
+	fn vertex(&self, vert: &vec::Vec3<f64>, uv: &vec::Vec3<f64>, _normal: &vec::Vec3<f64>) -> Vertex<vec::Vec2<f64>> {
+		(vert.to_pt4().transform(self.transform), vec::Vec2::new(uv.0[0], uv.0[1]))
+	}
+
+	fn fragment(&self, fragment: &Fragment<vec::Vec2<f64>>) -> Option<ColorF> {
+		if let Some((texture, threshold)) = self.alpha_test {
+			let (uv, ddx, ddy) = (&fragment.varyings, &fragment.ddx, &fragment.ddy);
+			let lod = texture.lod(ddx.0[0], ddx.0[1], ddy.0[0], ddy.0[1]);
+			if texture.sample(uv.0[0], uv.0[1], lod).a < threshold {
+				return None;
+			}
+		}
+		Some(ColorF::default())
+	}
+}
+
+fn barycentric(p: &vec::Vec2<f64>,
+	       p0: &vec::Vec3<f64>,
+	       p1: &vec::Vec3<f64>,
+	       p2: &vec::Vec3<f64>)
+	       -> Option<vec::Vec3<f64>> {
+	let v1 = vec::Vec3::new(p1.0[0] - p0.0[0], p2.0[0] - p0.0[0], p0.0[0] - p.0[0]);
+	let v2 = vec::Vec3::new(p1.0[1] - p0.0[1], p2.0[1] - p0.0[1], p0.0[1] - p.0[1]);
+	let (l1, l2, scale) = v1.cross(&v2).as_tuple();
+	if scale == 0f64 {
+		return None;
+	}
+	let l1 = l1 / scale;
+	let l2 = l2 / scale;
+	let l0 = 1f64 - l1 - l2;
+	Some(vec::Vec3::new(l0, l1, l2))
+}
+
+// Convert screen space barycentric coordinates to clip space
+fn perspective(screen_bc: &vec::Vec3<f64>, clip0: &vec::Vec4<f64>, clip1: &vec::Vec4<f64>, clip2: &vec::Vec4<f64>) -> vec::Vec3<f64> {
+	let clip_bc = vec::Vec3([
+				screen_bc.0[0] / clip0.0[3],
+				screen_bc.0[1] / clip1.0[3],
+				screen_bc.0[2] / clip2.0[3]
+				]);
+	clip_bc.scale(1f64 / (clip_bc.0[0] + clip_bc.0[1] + clip_bc.0[2]))
+}
+
+// Vertices with a smaller w are behind the eye, or too close to it to divide by w
+pub const NEAR_W: f64 = 1e-5;
+
+// Clip a triangle against the plane w = NEAR_W, and call draw for each triangle of the result.
+// The clipped polygon is split into a fan of triangles, and the edge flags passed to draw are set
+// for the edges from each vertex to the next which are on the boundary of the polygon,
+// so that the edges inside the fan are not drawn as lines.
+pub fn clip<V, F>(v0: &Vertex<V>, v1: &Vertex<V>, v2: &Vertex<V>, mut draw: F)
+		where V: Varyings, F: FnMut(&Vertex<V>, &Vertex<V>, &Vertex<V>, [bool; 3]) {
+	let inside = |v: &Vertex<V>| (v.0).0[3] >= NEAR_W;
+	if inside(v0) && inside(v1) && inside(v2) {
+		draw(v0, v1, v2, [true; 3]);
+		return;
+	}
+	// A vertex between a and b, which is also used to copy a vertex when t is 0
+	let lerp = |a: &Vertex<V>, b: &Vertex<V>, t: f64| {
+		let bc = &vec::Vec3::new(1f64 - t, t, 0f64);
+		(Varyings::interpolate(&a.0, &b.0, &b.0, bc), Varyings::interpolate(&a.1, &b.1, &b.1, bc))
+	};
+	let vertices = [v0, v1, v2];
+	let mut polygon = Vec::with_capacity(4);
+	for i in 0 .. 3 {
+		let (a, b) = (vertices[i], vertices[(i + 1) % 3]);
+		if inside(a) {
+			polygon.push(lerp(a, a, 0f64));
+		}
+		if inside(a) != inside(b) {
+			polygon.push(lerp(a, b, (NEAR_W - (a.0).0[3]) / ((b.0).0[3] - (a.0).0[3])));
+		}
+	}
+	for i in 2 .. polygon.len() {
+		draw(&polygon[0], &polygon[i - 1], &polygon[i], [i == 2, true, i == polygon.len() - 1]);
+	}
+}
+
+// A pixel covered by a triangle
+pub struct Pixel {
+	pub x: usize,
+	pub y: usize,
+	// samples covered by the triangle which pass the depth test
+	pub mask: u32,
+	// depth at the pixel position
+	pub z: f64,
+	pub front_facing: bool,
+	// perspective correct barycentric coordinates
+	bc: vec::Vec3<f64>,
+	// change in bc for one pixel step in screen x and y, within the 2x2 quad containing the pixel
+	bc_dx: vec::Vec3<f64>,
+	bc_dy: vec::Vec3<f64>,
+}
+
+// Call fragment for each pixel with a sample covered by the triangle which passes the stencil and depth tests.
+// For the line and point fill modes, pixels on the edges or at the vertices cover all of their samples.
+// Edge i from vertex i to the next is only drawn in line mode if edges[i] is set.
+// Only pixels inside the bounds are rasterized, which must be inside the depth buffer,
+// and the depth buffer must have a depth for each of the offsets.
+// Nothing is rasterized if the orientation of the triangle is culled.
+// The depth of the samples is written and the stencil pass operation is applied if fragment returns true,
+// but the stencil fail operations are applied before fragment is called.
+#[allow(clippy::too_many_arguments)]
+#[allow(clippy::needless_range_loop)]
+pub fn rasterize<F>(bounds: &pipeline::Rect, raster: &pipeline::Raster, viewport: &vec::Transform4<f64>, offsets: &[(f64, f64)],
+		p0: &vec::Vec4<f64>, p1: &vec::Vec4<f64>, p2: &vec::Vec4<f64>, edges: [bool; 3],
+		zbuffer: &mut depth::DepthBuffer, mut fragment: F)
+		where F: FnMut(&Pixel) -> bool {
+	let clip0 = &p0.transform(viewport);
+	let clip1 = &p1.transform(viewport);
+	let clip2 = &p2.transform(viewport);
+	let screen0 = &clip0.to_pt3();
+	let screen1 = &clip1.to_pt3();
+	let screen2 = &clip2.to_pt3();
+	// counter-clockwise on the screen, with y up
+	let normal = screen1.sub(screen0).cross(&screen2.sub(screen0));
+	let front_facing = normal.0[2] > 0f64;
+	if raster.cull.culls(front_facing) {
+		return;
+	}
+	// larger depths are closer, so the bias moves the triangle towards the eye
+	let bias = if normal.0[2] == 0f64 {
+		raster.depth_bias
+	} else {
+		let slope = (normal.0[0] / normal.0[2]).abs().max((normal.0[1] / normal.0[2]).abs());
+		raster.depth_bias + raster.depth_slope_bias * slope
+	};
+	let samples = offsets.len();
+	// Clip space barycentric coordinates at a pixel position, even if it is outside the triangle
+	let clip_bc = |x: i32, y: i32| {
+		barycentric(&vec::Vec2::new(x as f64, y as f64), screen0, screen1, screen2)
+			.map(|bc| perspective(&bc, clip0, clip1, clip2))
+	};
+	// Test and shade a pixel, where edge is set if the pixel covers all of its samples
+	let mut draw = |x: i32, y: i32, edge: bool| {
+		if x < bounds.x as i32 || y < bounds.y as i32 || x >= (bounds.x + bounds.width) as i32 || y >= (bounds.y + bounds.height) as i32 {
+			return;
+		}
+		let mut mask = 0u32;
+		let mut depth = [0f64; MAX_SAMPLES];
+		for (sample, &(dx, dy)) in offsets.iter().enumerate() {
+			match barycentric(&vec::Vec2::new(x as f64 + dx, y as f64 + dy), screen0, screen1, screen2) {
+				None => (),
+				Some(ref screen_bc) if !edge && (screen_bc.0[0] < 0f64 || screen_bc.0[1] < 0f64 || screen_bc.0[2] < 0f64) => (),
+				Some(screen_bc) => {
+					let z = screen0.0[2] * screen_bc.0[0] + screen1.0[2] * screen_bc.0[1] + screen2.0[2] * screen_bc.0[2] + bias;
+					if zbuffer.test(x as usize, y as usize, sample, z, front_facing) {
+						mask |= 1 << sample;
+						depth[sample] = z;
 					}
 				}
 			}
 		}
+		if mask == 0 {
+			return;
+		}
+		// Shade at the pixel position, even if it is outside the triangle
+		let screen_bc = match barycentric(&vec::Vec2::new(x as f64, y as f64), screen0, screen1, screen2) {
+			Some(screen_bc) => screen_bc,
+			None => return,
+		};
+		let z = screen0.0[2] * screen_bc.0[0] + screen1.0[2] * screen_bc.0[1] + screen2.0[2] * screen_bc.0[2] + bias;
+		let bc = perspective(&screen_bc, clip0, clip1, clip2);
+		// Derivatives are differences between the pixels of the 2x2 quad,
+		// in the row and column of this pixel
+		let (qx, qy) = (x & !1, y & !1);
+		let difference = |a: Option<vec::Vec3<f64>>, b: Option<vec::Vec3<f64>>| match (a, b) {
+			(Some(a), Some(b)) => b.sub(&a),
+			_ => vec::Vec3::default(),
+		};
+		let bc_dx = difference(clip_bc(qx, y), clip_bc(qx + 1, y));
+		let bc_dy = difference(clip_bc(x, qy), clip_bc(x, qy + 1));
+		let covered = Pixel {
+			x: x as usize,
+			y: y as usize,
+			mask,
+			z,
+			front_facing,
+			bc,
+			bc_dx,
+			bc_dy,
+		};
+		if fragment(&covered) {
+			for sample in 0 .. samples {
+				if mask & (1 << sample) != 0 {
+					zbuffer.pass(x as usize, y as usize, sample, depth[sample], front_facing);
+				}
+			}
+		}
+	};
+	let screen = [screen0, screen1, screen2];
+	match raster.fill {
+		pipeline::FillMode::Fill => {
+			let extent = offsets.iter().fold(0f64, |extent, &(dx, dy)| extent.max(dx.abs()).max(dy.abs()));
+			let minx = cmp::max(bounds.x as i32, (screen0.0[0].min(screen1.0[0].min(screen2.0[0])) - extent).ceil() as i32);
+			let miny = cmp::max(bounds.y as i32, (screen0.0[1].min(screen1.0[1].min(screen2.0[1])) - extent).ceil() as i32);
+			let maxx = cmp::min((bounds.x + bounds.width) as i32 - 1, (screen0.0[0].max(screen1.0[0].max(screen2.0[0])) + extent).floor() as i32);
+			let maxy = cmp::min((bounds.y + bounds.height) as i32 - 1, (screen0.0[1].max(screen1.0[1].max(screen2.0[1])) + extent).floor() as i32);
+			for y in miny .. maxy + 1 {
+				for x in minx .. maxx + 1 {
+					draw(x, y, false);
+				}
+			}
+		},
+		pipeline::FillMode::Line => {
+			// Step one pixel at a time along the major axis of the part of each edge inside the bounds
+			for i in (0 .. 3).filter(|&i| edges[i]) {
+				let (a, b) = (screen[i], screen[(i + 1) % 3]);
+				let (dx, dy) = (b.0[0] - a.0[0], b.0[1] - a.0[1]);
+				let (t0, t1) = match clip_line(a.0[0], a.0[1], dx, dy, bounds) {
+					Some(t) => t,
+					None => continue,
+				};
+				let n = ((t1 - t0) * dx.abs().max(dy.abs())).ceil().max(1f64) as i32;
+				for step in 0 .. n + 1 {
+					let t = t0 + (t1 - t0) * step as f64 / n as f64;
+					draw((a.0[0] + dx * t).round() as i32, (a.0[1] + dy * t).round() as i32, true);
+				}
+			}
+		},
+		pipeline::FillMode::Point => {
+			for p in &screen {
+				draw(p.0[0].round() as i32, p.0[1].round() as i32, true);
+			}
+		},
+	}
+}
+
+// Range of t for which the point (x + dx * t, y + dy * t) is within half a pixel of the bounds,
+// limited to [0, 1], or None if the range is empty
+fn clip_line(x: f64, y: f64, dx: f64, dy: f64, bounds: &pipeline::Rect) -> Option<(f64, f64)> {
+	let (mut t0, mut t1) = (0f64, 1f64);
+	let edges = [
+		(-dx, x - (bounds.x as f64 - 0.5f64)),
+		(dx, (bounds.x + bounds.width) as f64 - 0.5f64 - x),
+		(-dy, y - (bounds.y as f64 - 0.5f64)),
+		(dy, (bounds.y + bounds.height) as f64 - 0.5f64 - y),
+	];
+	for &(p, q) in &edges {
+		if p == 0f64 {
+			if q < 0f64 {
+				return None;
+			}
+		} else if p < 0f64 {
+			t0 = t0.max(q / p);
+		} else {
+			t1 = t1.min(q / p);
+		}
 	}
+	if t0 <= t1 { Some((t0, t1)) } else { None }
 }
 
-pub trait Shader {
-	fn vertex(&mut self, i: usize, vert: &vec::Vec3<f64>, uv: &vec::Vec3<f64>, normal: &vec::Vec3<f64>) -> vec::Vec4<f64>;
-	fn fragment(&self, bc: &vec::Vec3<f64>) -> Option<Color>;
+// Clip space position and varyings output by the vertex shader
+pub type Vertex<V> = (vec::Vec4<f64>, V);
+
+// Shaders only read their uniforms, so one shader can be used for many triangles at once.
+pub trait Shader: Sync {
+	type Varyings: Varyings;
+	// ColorF for a single target, or an array with a color for each of several targets
+	type Output;
+
+	fn vertex(&self, vert: &vec::Vec3<f64>, uv: &vec::Vec3<f64>, normal: &vec::Vec3<f64>) -> Vertex<Self::Varyings>;
+	// Returning None discards the fragment, so neither its color nor its depth is written.
+	fn fragment(&self, fragment: &Fragment<Self::Varyings>) -> Option<Self::Output>;
+}
+
+// Input to the fragment shader for a pixel
+pub struct Fragment<V> {
+	pub x: usize,
+	pub y: usize,
+	// interpolated depth in the viewport depth range, which is larger for closer fragments
+	pub z: f64,
+	// the triangle is counter-clockwise on the screen
+	pub front_facing: bool,
+	// index of the triangle in the draw
+	pub primitive: usize,
+	// perspective correct varyings at the pixel position
+	pub varyings: V,
+	// change in the varyings for one pixel step in screen x and y, over a 2x2 pixel quad
+	pub ddx: V,
+	pub ddy: V,
+}
+
+impl<V: Varyings> Fragment<V> {
+	pub fn new(v0: &Vertex<V>, v1: &Vertex<V>, v2: &Vertex<V>, pixel: &Pixel, primitive: usize) -> Self {
+		Fragment {
+			x: pixel.x,
+			y: pixel.y,
+			z: pixel.z,
+			front_facing: pixel.front_facing,
+			primitive,
+			varyings: V::interpolate(&v0.1, &v1.1, &v2.1, &pixel.bc),
+			ddx: V::interpolate(&v0.1, &v1.1, &v2.1, &pixel.bc_dx),
+			ddy: V::interpolate(&v0.1, &v1.1, &v2.1, &pixel.bc_dy),
+		}
+	}
+}
+
+// Values output for each vertex, and interpolated across the triangle for each fragment
+pub trait Varyings: Sized {
+	// Sum of the values for the vertices, weighted by the barycentric coordinates
+	fn interpolate(v0: &Self, v1: &Self, v2: &Self, bc: &vec::Vec3<f64>) -> Self;
+}
+
+impl Varyings for f64 {
+	fn interpolate(v0: &f64, v1: &f64, v2: &f64, bc: &vec::Vec3<f64>) -> f64 {
+		v0 * bc.0[0] + v1 * bc.0[1] + v2 * bc.0[2]
+	}
+}
+
+impl Varyings for vec::Vec2<f64> {
+	fn interpolate(v0: &Self, v1: &Self, v2: &Self, bc: &vec::Vec3<f64>) -> Self {
+		vec::Vec2::new(
+			f64::interpolate(&v0.0[0], &v1.0[0], &v2.0[0], bc),
+			f64::interpolate(&v0.0[1], &v1.0[1], &v2.0[1], bc))
+	}
+}
+
+impl Varyings for vec::Vec3<f64> {
+	fn interpolate(v0: &Self, v1: &Self, v2: &Self, bc: &vec::Vec3<f64>) -> Self {
+		v0.scale(bc.0[0]).add(&v1.scale(bc.0[1])).add(&v2.scale(bc.0[2]))
+	}
+}
+
+impl Varyings for vec::Vec4<f64> {
+	fn interpolate(v0: &Self, v1: &Self, v2: &Self, bc: &vec::Vec3<f64>) -> Self {
+		let mut v = [0f64; 4];
+		for (i, v) in v.iter_mut().enumerate() {
+			*v = f64::interpolate(&v0.0[i], &v1.0[i], &v2.0[i], bc);
+		}
+		vec::Vec4(v)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rect(x: usize, y: usize, width: usize, height: usize) -> pipeline::Rect {
+		pipeline::Rect { x, y, width, height }
+	}
+
+	#[test]
+	fn clip_line_inside() {
+		assert_eq!(clip_line(1f64, 1f64, 2f64, 1f64, &rect(0, 0, 4, 4)), Some((0f64, 1f64)));
+	}
+
+	#[test]
+	fn clip_line_crossing() {
+		// the bounds extend half a pixel beyond the pixel centers
+		let (t0, t1) = clip_line(-4.5f64, 1f64, 10f64, 0f64, &rect(0, 0, 4, 4)).unwrap();
+		assert!((t0 - 0.4f64).abs() < 1e-12 && (t1 - 0.8f64).abs() < 1e-12);
+		let (t0, t1) = clip_line(2f64, 10f64, 0f64, -20f64, &rect(0, 2, 4, 4)).unwrap();
+		assert!((t0 - 0.225f64).abs() < 1e-12 && (t1 - 0.425f64).abs() < 1e-12);
+	}
+
+	#[test]
+	fn clip_line_outside() {
+		assert_eq!(clip_line(-2f64, 1f64, 1f64, 0f64, &rect(0, 0, 4, 4)), None);
+		// parallel to an edge, outside of it
+		assert_eq!(clip_line(1f64, 5f64, 2f64, 0f64, &rect(0, 0, 4, 4)), None);
+		// the line passes the corner
+		assert_eq!(clip_line(-2f64, 3f64, 2f64, 3f64, &rect(0, 0, 4, 4)), None);
+	}
+
+	fn vertex(x: f64, w: f64) -> Vertex<vec::Vec2<f64>> {
+		(vec::Vec4([x, 0f64, 0f64, w]), vec::Vec2::new(0f64, 0f64))
+	}
+
+	#[test]
+	fn clip_edges() {
+		let mut edges = Vec::new();
+		clip(&vertex(0f64, 1f64), &vertex(1f64, 1f64), &vertex(2f64, 1f64), |_, _, _, e| edges.push(e));
+		assert_eq!(edges, vec![[true; 3]]);
+
+		// one vertex behind the eye gives a quad, split along an inner edge which isn't drawn
+		edges.clear();
+		clip(&vertex(0f64, -1f64), &vertex(1f64, 1f64), &vertex(2f64, 1f64), |_, _, _, e| edges.push(e));
+		assert_eq!(edges, vec![[true, true, false], [false, true, true]]);
+
+		// two vertices behind the eye give a single triangle
+		edges.clear();
+		clip(&vertex(0f64, -1f64), &vertex(1f64, -1f64), &vertex(2f64, 1f64), |_, _, _, e| edges.push(e));
+		assert_eq!(edges, vec![[true; 3]]);
+	}
+	// start and end of a piece, and whether they join other pieces
+	type Piece = ((f64, f64), (f64, f64), bool, bool);
+
+	fn dash(points: &[(f64, f64)], closed: bool, dashes: &[f64]) -> Vec<Piece> {
+		let points: Vec<vec::Vec2<f64>> = points.iter().map(|&(x, y)| vec::Vec2::new(x, y)).collect();
+		let mut pieces = Vec::new();
+		dash_path(&points, closed, dashes, |a, b, start, end| pieces.push((a.as_tuple(), b.as_tuple(), start, end)));
+		pieces
+	}
+
+	#[test]
+	fn dash_path_solid() {
+		let path = [(0f64, 0f64), (4f64, 0f64), (4f64, 3f64)];
+		assert_eq!(dash(&path, false, &[]), vec![
+			((0f64, 0f64), (4f64, 0f64), false, true),
+			((4f64, 0f64), (4f64, 3f64), true, false),
+		]);
+		// every edge of a closed path joins its neighbours
+		let pieces = dash(&path, true, &[]);
+		assert_eq!(pieces.len(), 3);
+		assert!(pieces.iter().all(|piece| piece.2 && piece.3));
+		assert_eq!(pieces[2].1, (0f64, 0f64));
+	}
+
+	#[test]
+	fn dash_path_pattern() {
+		assert_eq!(dash(&[(0f64, 0f64), (7f64, 0f64)], false, &[2f64, 1f64]), vec![
+			((0f64, 0f64), (2f64, 0f64), false, false),
+			((3f64, 0f64), (5f64, 0f64), false, false),
+			((6f64, 0f64), (7f64, 0f64), false, false),
+		]);
+		// an odd number of entries swaps the dashes and gaps when it repeats
+		assert_eq!(dash(&[(0f64, 0f64), (12f64, 0f64)], false, &[3f64, 1f64, 2f64]), vec![
+			((0f64, 0f64), (3f64, 0f64), false, false),
+			((4f64, 0f64), (6f64, 0f64), false, false),
+			((9f64, 0f64), (10f64, 0f64), false, false),
+		]);
+	}
+
+	#[test]
+	fn dash_path_continues_across_vertices() {
+		// the first dash turns the corner, and the pieces on both sides are joined
+		assert_eq!(dash(&[(0f64, 0f64), (3f64, 0f64), (3f64, 5f64)], false, &[4f64, 2f64]), vec![
+			((0f64, 0f64), (3f64, 0f64), false, true),
+			((3f64, 0f64), (3f64, 1f64), true, false),
+			((3f64, 3f64), (3f64, 5f64), false, false),
+		]);
+	}
+
+	struct Solid;
+
+	impl Shader for Solid {
+		type Varyings = vec::Vec2<f64>;
+		type Output = ColorF;
+
+		fn vertex(&self, vert: &vec::Vec3<f64>, _uv: &vec::Vec3<f64>, _normal: &vec::Vec3<f64>) -> Vertex<vec::Vec2<f64>> {
+			(vert.to_pt4(), vec::Vec2::new(0f64, 0f64))
+		}
+
+		fn fragment(&self, _fragment: &Fragment<vec::Vec2<f64>>) -> Option<ColorF> {
+			Some(ColorF::new(1f32, 0f32, 0f32, 1f32))
+		}
+	}
+
+	#[test]
+	fn multisample_resolve() {
+		let mut target = Multisample::new(2, 1, 4).unwrap();
+		let mut zbuffer = depth::DepthBuffer::new(2, 1, 4, depth::Format::F32);
+		let viewport = vec::viewport(0f64, 0f64, 0f64, 2f64, 1f64, 2f64);
+		{
+			// the right edge is at the position of pixel 1, which covers the samples to its left
+			let mut pipeline = pipeline::Pipeline::new(&mut target, &mut zbuffer, &viewport);
+			let vertex = |x: f64, y: f64| (vec::Vec4([x, y, 0f64, 1f64]), vec::Vec2::new(0f64, 0f64));
+			pipeline.draw_triangle(&Solid, 0, &vertex(-3f64, -3f64), &vertex(0f64, -3f64), &vertex(0f64, 5f64));
+		}
+		let image = target.resolve(Format::RgbaF32);
+		assert_eq!(image.get_f(0, 0), ColorF::new(1f32, 0f32, 0f32, 1f32));
+		assert_eq!(image.get_f(1, 0), ColorF::new(0.5f32, 0f32, 0f32, 0.5f32));
+	}
+
+	#[test]
+	fn multisample_counts() {
+		for &samples in [1, 2, 4, 8, 16].iter() {
+			let target = Multisample::new(1, 1, samples).unwrap();
+			assert_eq!(target.get_samples(), samples);
+			// the samples are inside the pixel
+			for sample in 0 .. samples {
+				let (dx, dy) = target.get_offset(sample);
+				assert!(dx.abs() <= 0.5f64 && dy.abs() <= 0.5f64);
+			}
+		}
+		assert!(Multisample::new(1, 1, 3).is_none());
+	}
+
+	fn assert_close(a: &ColorF, b: &ColorF) {
+		let close = |a: f32, b: f32| (a - b).abs() < 1e-5f32;
+		assert!(close(a.r, b.r) && close(a.g, b.g) && close(a.b, b.b) && close(a.a, b.a), "{:?} != {:?}", a, b);
+	}
+
+	#[test]
+	fn blend() {
+		let src = &ColorF::new(1f32, 0.5f32, 0f32, 0.5f32);
+		let dst = &ColorF::new(0f32, 0.5f32, 1f32, 1f32);
+		assert_close(&Blend::Replace.apply(src, dst), src);
+		assert_close(&Blend::Alpha.apply(src, dst), &ColorF::new(0.5f32, 0.5f32, 0.5f32, 1f32));
+		assert_close(&Blend::Additive.apply(src, dst), &ColorF::new(0.5f32, 0.75f32, 1f32, 1f32));
+		assert_close(&Blend::Multiply.apply(src, dst), &ColorF::new(0f32, 0.375f32, 0.5f32, 1f32));
+		// alpha blending over a transparent target keeps the source alpha
+		let clear = &ColorF::new(0f32, 0f32, 0f32, 0f32);
+		assert_close(&Blend::Alpha.apply(src, clear), &ColorF::new(0.5f32, 0.25f32, 0f32, 0.5f32));
+	}
+
+	#[test]
+	fn oit_single_layer() {
+		let dst = ColorF::new(0f32, 0.5f32, 1f32, 1f32);
+		for &depth in [0f64, 0.5f64, 1f64].iter() {
+			for &alpha in [0.25f32, 0.5f32, 1f32].iter() {
+				let color = ColorF::new(1f32, 0.5f32, 0f32, alpha);
+				let mut target = Multisample::new(1, 1, 1).unwrap();
+				target.set(0, 0, 0, &dst);
+				target.oit = true;
+				pipeline::Target::write(&mut target, 0, 0, 1, depth, &color, Blend::Alpha);
+				target.composite_oit();
+				assert_close(&target.resolve(Format::RgbaF32).get_f(0, 0), &Blend::Alpha.apply(&color, &dst));
+			}
+		}
+	}
+
+	// Interpolates the clip space position, and outputs it divided by w
+	struct ClipPosition;
+
+	impl Shader for ClipPosition {
+		type Varyings = vec::Vec4<f64>;
+		type Output = ColorF;
+
+		fn vertex(&self, vert: &vec::Vec3<f64>, _uv: &vec::Vec3<f64>, _normal: &vec::Vec3<f64>) -> Vertex<vec::Vec4<f64>> {
+			(vert.to_pt4(), vert.to_pt4())
+		}
+
+		fn fragment(&self, fragment: &Fragment<vec::Vec4<f64>>) -> Option<ColorF> {
+			let p = &fragment.varyings.0;
+			Some(ColorF::new((p[0] / p[3]) as f32, (p[1] / p[3]) as f32, 0f32, 1f32))
+		}
+	}
+
+	// Draw a triangle into an 8x8 image with the clip space positions as varyings,
+	// and check that they are interpolated to the position of each pixel
+	fn check_clip_positions(p0: [f64; 4], p1: [f64; 4], p2: [f64; 4]) {
+		let mut target = Image::new(8, 8, Format::RgbaF32);
+		let mut zbuffer = depth::DepthBuffer::new(8, 8, 1, depth::Format::F32);
+		let viewport = vec::viewport(0f64, 0f64, 0f64, 8f64, 8f64, 2f64);
+		{
+			let mut pipeline = pipeline::Pipeline::new(&mut target, &mut zbuffer, &viewport);
+			let vertex = |p: [f64; 4]| (vec::Vec4(p), vec::Vec4(p));
+			pipeline.draw_triangle(&ClipPosition, 0, &vertex(p0), &vertex(p1), &vertex(p2));
+		}
+		let mut covered = 0;
+		for y in 0 .. 8 {
+			for x in 0 .. 8 {
+				let c = target.get_f(x, y);
+				if c.a == 0f32 {
+					continue;
+				}
+				covered += 1;
+				let (ndc_x, ndc_y) = ((x as f32 - 4f32) / 4f32, (y as f32 - 4f32) / 4f32);
+				assert!((c.r - ndc_x).abs() < 1e-4f32 && (c.g - ndc_y).abs() < 1e-4f32, "({}, {}): {:?}", x, y, c);
+			}
+		}
+		assert!(covered > 0);
+	}
+
+	#[test]
+	fn perspective_correct() {
+		// the vertices have different depths, so affine interpolation would not match the pixel positions
+		check_clip_positions([-1f64, -1f64, 0f64, 1f64], [3f64, -1f64, 0f64, 3f64], [-2f64, 4f64, 0f64, 2f64]);
+	}
+
+	#[test]
+	fn clipped_perspective_correct() {
+		// one vertex and then two vertices behind the eye
+		check_clip_positions([-1f64, -1f64, 0f64, 1f64], [1f64, -1f64, 0f64, 1f64], [0f64, 2f64, 0f64, -1f64]);
+		check_clip_positions([-1f64, -1f64, 0f64, 1f64], [1f64, -1f64, 0f64, -2f64], [0f64, 2f64, 0f64, -1f64]);
+	}
+
+	#[test]
+	fn clip_interpolates_varyings() {
+		// a varying which is linear in clip space keeps its value at the clipped vertices
+		let linear = |p: &vec::Vec4<f64>| 2f64 * p.0[0] - p.0[1] + 3f64 * p.0[3];
+		let vertex = |x: f64, y: f64, w: f64| {
+			let p = vec::Vec4([x, y, 0f64, w]);
+			let v = linear(&p);
+			(p, v)
+		};
+		let mut triangles = 0;
+		clip(&vertex(0f64, 0f64, 1f64), &vertex(1f64, 2f64, 2f64), &vertex(3f64, -1f64, -1f64), |v0, v1, v2, _| {
+			for v in [v0, v1, v2].iter() {
+				assert!((v.0).0[3] >= NEAR_W * 0.999f64);
+				assert!((v.1 - linear(&v.0)).abs() < 1e-9f64);
+			}
+			triangles += 1;
+		});
+		assert_eq!(triangles, 2);
+	}
+
+	// Outputs the derivatives of the clip space x and y
+	struct Derivatives;
+
+	impl Shader for Derivatives {
+		type Varyings = vec::Vec4<f64>;
+		type Output = ColorF;
+
+		fn vertex(&self, vert: &vec::Vec3<f64>, _uv: &vec::Vec3<f64>, _normal: &vec::Vec3<f64>) -> Vertex<vec::Vec4<f64>> {
+			(vert.to_pt4(), vert.to_pt4())
+		}
+
+		fn fragment(&self, fragment: &Fragment<vec::Vec4<f64>>) -> Option<ColorF> {
+			let (ddx, ddy) = (&fragment.ddx.0, &fragment.ddy.0);
+			Some(ColorF::new(ddx[0] as f32, ddx[1] as f32, ddy[0] as f32, ddy[1] as f32))
+		}
+	}
+
+	#[test]
+	fn derivatives_of_linear_varyings() {
+		let mut target = Image::new(8, 8, Format::RgbaF32);
+		let mut zbuffer = depth::DepthBuffer::new(8, 8, 1, depth::Format::F32);
+		let viewport = vec::viewport(0f64, 0f64, 0f64, 8f64, 8f64, 2f64);
+		{
+			let mut pipeline = pipeline::Pipeline::new(&mut target, &mut zbuffer, &viewport);
+			let vertex = |x: f64, y: f64| (vec::Vec4([x, y, 0f64, 1f64]), vec::Vec4([x, y, 0f64, 1f64]));
+			pipeline.draw_triangle(&Derivatives, 0, &vertex(-0.9f64, -0.8f64), &vertex(0.7f64, -0.6f64), &vertex(-0.3f64, 0.9f64));
+		}
+		// one pixel is a quarter of the clip space, for every pixel of each quad
+		let mut covered = 0;
+		for y in 0 .. 8 {
+			for x in 0 .. 8 {
+				let c = target.get_f(x, y);
+				if c == ColorF::default() {
+					continue;
+				}
+				covered += 1;
+				assert_close(&c, &ColorF::new(0.25f32, 0f32, 0f32, 0.25f32));
+			}
+		}
+		assert!(covered > 4);
+	}
 }
@@ -0,0 +1,167 @@
+use image;
+use vec;
+
+pub enum Kind {
+	// direction points towards the light
+	Directional {
+		direction: vec::Vec3<f64>,
+	},
+	Point {
+		position: vec::Vec3<f64>,
+	},
+	// direction points away from the light, and the cone angles are in radians
+	Spot {
+		position: vec::Vec3<f64>,
+		direction: vec::Vec3<f64>,
+		inner: f64,
+		outer: f64,
+	},
+}
+
+pub struct Light {
+	pub kind: Kind,
+	pub color: image::ColorF,
+	pub intensity: f64,
+	// constant, linear and quadratic attenuation for point and spot lights
+	pub attenuation: [f64; 3],
+}
+
+impl Default for Light {
+	fn default() -> Self {
+		Light {
+			kind: Kind::Directional {
+				direction: vec::Vec3::new(1f64, 1f64, 0f64).normalize(),
+			},
+			color: image::ColorF::new(1f32, 1f32, 1f32, 1f32),
+			intensity: 1f64,
+			attenuation: [ 1f64, 0f64, 1f64 ],
+		}
+	}
+}
+
+impl Light {
+	// Parse a light of the form "kind:key=value:key=value...".
+	// Vectors and colors are comma separated, and angles are in degrees.
+	pub fn parse(s: &str) -> Result<Light, String> {
+		let mut words = s.split(':');
+		let kind = words.next().unwrap_or("");
+		let mut light = Light::default();
+		let mut direction = None;
+		let mut position = vec::Vec3::new(0f64, 0f64, 0f64);
+		let mut inner = 20f64;
+		let mut outer = 30f64;
+		for word in words {
+			let mut kv = word.splitn(2, '=');
+			let key = kv.next().unwrap_or("");
+			let value = kv.next().unwrap_or("");
+			match key {
+				"dir" => {
+					let mut d = parse_vec3(key, value)?;
+					if d.norm() == 0f64 {
+						return Err(format!("invalid light {}: the direction has no length", key));
+					}
+					direction = Some(d.normalize());
+				},
+				"pos" => position = parse_vec3(key, value)?,
+				"color" => {
+					let c = parse_vec3(key, value)?;
+					light.color = image::ColorF::new(c.0[0] as f32, c.0[1] as f32, c.0[2] as f32, 1f32);
+				},
+				"intensity" => light.intensity = parse_f64(key, value)?,
+				"atten" => light.attenuation = parse_vec3(key, value)?.0,
+				"inner" => inner = parse_f64(key, value)?,
+				"outer" => outer = parse_f64(key, value)?,
+				_ => return Err(format!("unknown light parameter: {}", key)),
+			}
+		}
+		light.kind = match kind {
+			"directional" => Kind::Directional {
+				direction: direction.unwrap_or_else(|| vec::Vec3::new(1f64, 1f64, 0f64).normalize()),
+			},
+			"point" => Kind::Point { position },
+			"spot" => Kind::Spot {
+				position,
+				direction: direction.unwrap_or_else(|| vec::Vec3::new(0f64, -1f64, 0f64)),
+				inner: inner.to_radians(),
+				outer: outer.max(inner).to_radians(),
+			},
+			_ => return Err(format!("unknown light kind: {}", kind)),
+		};
+		Ok(light)
+	}
+
+	// Returns the direction from the point towards the light,
+	// and the amount of light reaching the point.
+	pub fn illuminate(&self, p: &vec::Vec3<f64>) -> (vec::Vec3<f64>, image::ColorF) {
+		let (l, scale) = match self.kind {
+			Kind::Directional { ref direction } => (vec::Vec3(direction.0), 1f64),
+			Kind::Point { ref position } => {
+				let d = position.sub(p);
+				if d.norm() == 0f64 {
+					// there is no direction towards a light at the point
+					return (d, self.color.scale(0f32));
+				}
+				(d.scale(1f64 / d.norm()), self.attenuate(d.norm()))
+			},
+			Kind::Spot { ref position, ref direction, inner, outer } => {
+				let d = position.sub(p);
+				if d.norm() == 0f64 {
+					return (d, self.color.scale(0f32));
+				}
+				let l = d.scale(1f64 / d.norm());
+				let cos = -l.dot(direction);
+				let (cos_inner, cos_outer) = (inner.cos(), outer.cos());
+				let cone = if cos >= cos_inner {
+					1f64
+				} else if cos <= cos_outer {
+					0f64
+				} else {
+					let t = (cos - cos_outer) / (cos_inner - cos_outer);
+					t * t * (3f64 - 2f64 * t)
+				};
+				(l, cone * self.attenuate(d.norm()))
+			},
+		};
+		(l, self.color.scale((scale * self.intensity) as f32))
+	}
+
+	fn attenuate(&self, d: f64) -> f64 {
+		let a = &self.attenuation;
+		1f64 / (a[0] + a[1] * d + a[2] * d * d).max(1e-6)
+	}
+}
+
+fn parse_f64(key: &str, value: &str) -> Result<f64, String> {
+	value.parse::<f64>().map_err(|err| format!("invalid light {}: {}", key, err))
+}
+
+fn parse_vec3(key: &str, value: &str) -> Result<vec::Vec3<f64>, String> {
+	let mut v = vec::Vec3::new(0f64, 0f64, 0f64);
+	let mut n = 0;
+	for (i, word) in value.split(',').enumerate() {
+		if i >= 3 {
+			return Err(format!("invalid light {}: {}", key, value));
+		}
+		v.0[i] = parse_f64(key, word)?;
+		n += 1;
+	}
+	if n != 3 {
+		return Err(format!("invalid light {}: {}", key, value));
+	}
+	Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_direction() {
+		match Light::parse("spot:dir=0,-2,0").unwrap().kind {
+			Kind::Spot { direction, .. } => assert_eq!(direction.0, [0f64, -1f64, 0f64]),
+			_ => panic!("not a spot light"),
+		}
+		assert!(Light::parse("directional:dir=0,0,0").is_err());
+		assert!(Light::parse("spot:dir=0,0,0").is_err());
+	}
+}
@@ -1,15 +1,44 @@
+mod bake;
+mod canvas;
+mod debug;
+mod deferred;
+mod depth;
+mod environment;
+mod exr;
+mod hdr;
 mod image;
+mod light;
 mod model;
+mod options;
+mod overlay;
+mod postprocess;
+mod pbr;
+mod pipeline;
+mod shadow;
+mod ssao;
+mod texture;
 mod tga;
+mod tonemap;
 mod vec;
 
-use std::{env, f64, path};
+use std::{cmp, env, f64, io, path, process};
+
+// Moves wireframe lines towards the eye, in the depth range of the viewport
+const WIREFRAME_DEPTH_BIAS: f64 = 0.05f64;
 
 fn main() {
-	let (width, height) = (800, 800);
+	let options = match options::Options::parse(env::args().skip(1)) {
+		Ok(options) => options,
+		Err(err) => {
+			eprintln!("{}", err);
+			process::exit(1);
+		}
+	};
+
+	// Render at a multiple of the output size when supersampling
+	let (width, height) = (800 * options.supersample, 800 * options.supersample);
 	let viewport = &vec::viewport(width as f64 / 8f64, height as f64 / 8f64, 0f64,
 				      width as f64 * 0.75f64, height as f64 * 0.75f64, 255f64);
-	let light = &vec::Vec3([ 1f64, 1f64, 0f64 ]).normalize();
 	let eye = &vec::Vec3([ 1f64, 1f64, 4f64 ]);
 	let center = &vec::Vec3([ 0f64, 0f64, 0f64 ]);
 	let up = &vec::Vec3([ 0f64, 1f64, 0f64 ]);
@@ -18,88 +47,456 @@ fn main() {
 	let modelview = &vec::lookat(eye, center, up);
 	let transform = projection.mul(modelview);
 	let transform_it = transform.inverse_transpose();
-	let light_transform = light.transform_vec(&transform).normalize();
-
-	let (shadow_width, shadow_height) = (800, 800);
-	let shadow_viewport = &vec::viewport(shadow_width as f64 / 8f64, shadow_height as f64 / 8f64, 0f64,
-					     shadow_width as f64 * 0.75f64, shadow_height as f64 * 0.75f64, 1f64);
-	let shadow_transform = &vec::lookat(light, center, up);
-
-	let mut image = image::Image::new(width, height, image::Format::Rgb);
-	let mut shadow_image = image::Image::new(shadow_width, shadow_height, image::Format::Rgb);
-	let mut zbuffer = vec![f64::MIN; width * height];
-	let mut shadow_zbuffer = vec![f64::MIN; shadow_width * shadow_height];
-	for arg in env::args().skip(1) {
-		let model = model::Model::read(path::Path::new(&format!("{}.obj", arg))).unwrap();
-		let texture = Box::new(tga::read(path::Path::new(&format!("{}_diffuse.tga", arg))).unwrap());
-		//let texture = Box::new(tga::read(path::Path::new("obj/grid.tga")).unwrap());
-		let normal = Box::new(tga::read(path::Path::new(&format!("{}_nm.tga", arg))).unwrap_or(image::Image::default()));
-		let tangent = Box::new(tga::read(path::Path::new(&format!("{}_nm_tangent.tga", arg))).unwrap_or(image::Image::default()));
-		let specular = Box::new(tga::read(path::Path::new(&format!("{}_spec.tga", arg))).unwrap_or(image::Image::default()));
-
-		let shadow = false;
-		if shadow {
-			let mut shadow_shader = ShadowShader {
-				shadow_transform: &shadow_transform,
-				shadow_viewport: shadow_viewport,
-				shadow_vert: Default::default(),
-			};
-			model.render(&mut shadow_image, &mut shadow_shader, shadow_viewport, &mut shadow_zbuffer[..]);
+
+	if options.bake_ao {
+		// don't replace authored textures by accident
+		for arg in &options.models {
+			let name = format!("{}_ao.tga", arg);
+			if !options.force && path::Path::new(&name).exists() {
+				eprintln!("{} already exists, use --force to replace it", name);
+				process::exit(1);
+			}
+		}
+		for arg in &options.models {
+			let model = model::Model::read(path::Path::new(&format!("{}.obj", arg))).unwrap();
+			let ao = bake::ambient_occlusion(&options.bake_settings, &model);
+			tga::write(&ao, path::Path::new(&format!("{}_ao.tga", arg)), true).unwrap();
 		}
+	}
 
-		let mut shader = Shader {
-			intensity: Intensity::Constant,
+	let mut objects = Vec::new();
+	for arg in &options.models {
+		let mut object = Object {
+			model: model::Model::read(path::Path::new(&format!("{}.obj", arg))).unwrap(),
+			texture: texture::Texture::new(tga::read(path::Path::new(&format!("{}_diffuse.tga", arg))).unwrap()),
+			//texture: texture::Texture::new(tga::read(path::Path::new("obj/grid.tga")).unwrap()),
+			normal: read_data_texture(&format!("{}_nm.tga", arg), None),
+			tangent: read_data_texture(&format!("{}_nm_tangent.tga", arg), None),
+			specular: read_data_texture(&format!("{}_spec.tga", arg), None),
+			metallic: read_data_texture(&format!("{}_metallic.tga", arg), Some(options.metallic)),
+			roughness: read_data_texture(&format!("{}_roughness.tga", arg), Some(options.roughness)),
+			ao: read_data_texture(&format!("{}_ao.tga", arg), Some(1f32)),
+			// debug shaders draw every model opaque
+			transparent: options.transparent.contains(arg) && options.debug.is_none(),
+		};
+		for (slot, t) in [
+			("diffuse", &mut object.texture),
+			("normal", &mut object.normal),
+			("tangent", &mut object.tangent),
+			("specular", &mut object.specular),
+			("metallic", &mut object.metallic),
+			("roughness", &mut object.roughness),
+			("ao", &mut object.ao),
+		] {
+			t.sampler.filter = options.filter;
+			for &(ref wrap_slot, wrap) in &options.wrap {
+				if wrap_slot.as_ref().is_none_or(|s| s == slot) {
+					t.sampler.wrap_u = wrap;
+					t.sampler.wrap_v = wrap;
+				}
+			}
+		}
+		objects.push(object);
+	}
+
+	let intensity = options.shading;
+
+	// The first light casts shadows
+	let shadow_map = match options.lights[0].kind {
+		light::Kind::Directional { ref direction } if options.shadow => {
+			let models: Vec<(&model::Model, Option<(&texture::Texture, f32)>)> = objects.iter()
+				.map(|object| (&object.model, options.alpha_test.map(|threshold| (&object.texture, threshold))))
+				.collect();
+			let mut shadow_map = shadow::ShadowMap::new(options.shadow_size, direction, &models);
+			shadow_map.bias = options.shadow_bias;
+			shadow_map.slope_bias = options.shadow_slope_bias;
+			shadow_map.max_bias = options.shadow_max_bias;
+			shadow_map.pcf = options.shadow_pcf;
+			if let Some(ref name) = options.dump_shadow {
+				tga::write(&shadow_map.to_image(), path::Path::new(name), true).unwrap();
+			}
+			Some(shadow_map)
+		},
+		_ => None,
+	};
+
+	let environment = options.env.as_ref().map(|name| {
+		let mut environment = environment::Environment::new(read_image(path::Path::new(name)).unwrap());
+		environment.intensity = options.env_intensity;
+		environment
+	});
+
+	let screen = &viewport.mul(&transform);
+	let depth_range = depth_range(&objects, &transform, viewport);
+	let new_zbuffer = |samples: usize| {
+		let mut zbuffer = depth::DepthBuffer::new(width, height, samples, options.depth_format);
+		zbuffer.compare = options.depth_compare;
+		zbuffer.clear_value = options.depth_compare.clear_value();
+		zbuffer.range = depth_range;
+		zbuffer.clear();
+		zbuffer
+	};
+	let occlusion = if options.ssao {
+		let mut zbuffer = new_zbuffer(1);
+		let mut target = image::DepthOnly;
+		let mut pipeline = view_pipeline(&mut target, &mut zbuffer, viewport, &options);
+		for object in &objects {
+			let mut shader = image::DepthShader::new(&transform);
+			shader.alpha_test = options.alpha_test.map(|threshold| (&object.texture, threshold));
+			pipeline.draw(&object.model, &shader);
+		}
+		let occlusion = ssao::AmbientOcclusion::new(&options.ssao_settings, &zbuffer, screen, eye);
+		if let Some(ref name) = options.dump_ssao {
+			tga::write(&occlusion.to_image(), path::Path::new(name), true).unwrap();
+		}
+		Some(occlusion)
+	} else {
+		None
+	};
+
+	let mut target = image::Multisample::new(width, height, options.msaa).unwrap();
+	let samples = target.get_samples();
+	let mut zbuffer = new_zbuffer(samples);
+	if options.outline.is_some() {
+		// Mark the samples covered by opaque objects, so that the outline is only drawn outside them
+		let mut face = depth::StencilFace::new(depth::Compare::Always);
+		face.pass = depth::StencilOp::Replace;
+		zbuffer.stencil = Some(depth::Stencil::new(face, 1));
+	}
+	let shaders: Vec<Shader> = objects.iter().map(|object| {
+		Shader {
+			intensity,
 			color: Color::Texture,
-			shadow: shadow,
 
-			light: light,
-			light_transform: &light_transform,
+			lights: &options.lights,
+			ambient: options.ambient,
+			environment: environment.as_ref(),
+			occlusion: occlusion.as_ref(),
+			eye,
 			transform: &transform,
 			transform_it: &transform_it,
 
-			texture: texture,
-			normal: normal,
-			specular: specular,
-			tangent: tangent,
+			texture: &object.texture,
+			normal: &object.normal,
+			specular: &object.specular,
+			tangent: &object.tangent,
+			metallic: &object.metallic,
+			roughness: &object.roughness,
+			ao: &object.ao,
+			two_sided: options.two_sided,
+			alpha_test: options.alpha_test,
+			opacity: if object.transparent { options.opacity } else { 1f32 },
+
+			shadow: shadow_map.as_ref(),
+			shadow_darkness: options.shadow_darkness,
+		}
+	}).collect();
+	if let Some(mode) = options.debug {
+		let models: Vec<&model::Model> = objects.iter().map(|object| &object.model).collect();
+		let counts;
+		let mut shader = debug::DebugShader::new(mode, &transform, modelview, &models);
+		if mode == debug::Mode::Overdraw {
+			// Count the fragments at each pixel without a depth test, then color the visible fragments by their count
+			let mut image = image::Image::new(width, height, image::Format::RgbaF32);
+			let mut zbuffer = new_zbuffer(1);
+			zbuffer.compare = depth::Compare::Always;
+			zbuffer.write = false;
+			{
+				let mut pipeline = view_pipeline(&mut image, &mut zbuffer, viewport, &options);
+				pipeline.blend = image::Blend::Additive;
+				debug::draw(&mut pipeline, &models, &shader);
+			}
+			counts = image;
+			shader.counts = Some(&counts);
+		}
+		let mut pipeline = view_pipeline(&mut target, &mut zbuffer, viewport, &options);
+		pipeline.raster.fill = options.fill;
+		debug::draw(&mut pipeline, &models, &shader);
+	} else if options.deferred {
+		// Rasterize the opaque objects into the G-buffer, then light each pixel once
+		let mut gbuffer = deferred::GBuffer::new(zbuffer);
+		let mut pipeline = view_pipeline(&mut gbuffer.targets, &mut gbuffer.zbuffer, viewport, &options);
+		for (i, (object, shader)) in objects.iter().zip(shaders.iter()).enumerate() {
+			if !object.transparent {
+				let shader = &GBufferShader {
+					shader,
+					id: i + 1,
+				};
+				pipeline.draw(&object.model, shader);
+			}
+		}
+		if let Some(ref prefix) = options.dump_gbuffer {
+			for (name, image) in gbuffer.to_images() {
+				tga::write(&image, path::Path::new(&format!("{}_{}.tga", prefix, name)), true).unwrap();
+			}
+		}
+		let lighting = deferred::Lighting {
+			lights: &options.lights,
+			ambient: options.ambient,
+			environment: environment.as_ref(),
+			occlusion: occlusion.as_ref(),
+		};
+		let image = deferred::shade(&gbuffer, &lighting, screen, eye);
+		for y in 0 .. height {
+			for x in 0 .. width {
+				if !gbuffer.zbuffer.is_clear(x, y, 0) {
+					target.set(x, y, 0, &image.get_f(x, y));
+				}
+			}
+		}
+		// there is one sample per pixel
+		zbuffer = gbuffer.zbuffer;
+	} else {
+		let mut pipeline = view_pipeline(&mut target, &mut zbuffer, viewport, &options);
+		pipeline.raster.fill = options.fill;
+		for (object, shader) in objects.iter().zip(shaders.iter()) {
+			if !object.transparent {
+				pipeline.draw(&object.model, shader);
+			}
+		}
+	}
 
-			shadow_transform: &shadow_transform,
-			shadow_zbuffer: &shadow_zbuffer,
-			shadow_width: shadow_width,
-			shadow_height: shadow_height,
-			shadow_viewport: shadow_viewport,
+	if let Some(color) = options.outline {
+		// Draw the objects expanded along their normals, except where the objects themselves were drawn
+		zbuffer.stencil = Some(depth::Stencil::new(depth::StencilFace::new(depth::Compare::NotEqual), 1));
+		let shader = &ColorShader {
+			transform: &transform,
+			offset: options.outline_width,
+			color,
+		};
+		let mut pipeline = view_pipeline(&mut target, &mut zbuffer, viewport, &options);
+		for object in objects.iter().filter(|object| !object.transparent) {
+			pipeline.draw(&object.model, shader);
+		}
+		pipeline.zbuffer.stencil = None;
+	}
 
-			u: Default::default(),
-			v: Default::default(),
-			vert: Default::default(),
-			shadow_vert: Default::default(),
-			vert_intensity: Default::default(),
-			vert_normal: Default::default(),
+	if let Some(color) = options.wireframe {
+		// Draw the edges slightly closer than the triangles, so that only the visible edges pass the depth test
+		let shader = &ColorShader {
+			transform: &transform,
+			offset: 0f64,
+			color,
 		};
-		model.render(&mut image, &mut shader, viewport, &mut zbuffer[..]);
+		let mut pipeline = view_pipeline(&mut target, &mut zbuffer, viewport, &options);
+		pipeline.raster.fill = pipeline::FillMode::Line;
+		pipeline.raster.depth_bias = WIREFRAME_DEPTH_BIAS;
+		pipeline.raster.depth_slope_bias = 1f64;
+		for object in objects.iter().filter(|object| !object.transparent) {
+			pipeline.draw(&object.model, shader);
+		}
+	}
+
+	// Draw the environment where no geometry covers a sample
+	if let Some(ref environment) = environment {
+		let inverse = screen.inverse();
+		let z = center.transform_pt(screen).0[2];
+		for y in 0 .. height {
+			for x in 0 .. width {
+				for sample in 0 .. samples {
+					if zbuffer.is_clear(x, y, sample) {
+						let (dx, dy) = target.get_offset(sample);
+						let p = vec::Vec3::new(x as f64 + dx, y as f64 + dy, z).transform_pt(&inverse);
+						target.set(x, y, sample, &environment.background(&p.sub(eye).normalize()));
+					}
+				}
+			}
+		}
+	}
+
+	// Transparent objects are depth tested against opaque objects, but do not write depth
+	zbuffer.write = false;
+	let mut pipeline = view_pipeline(&mut target, &mut zbuffer, viewport, &options);
+	pipeline.raster.fill = options.fill;
+	match options.transparency {
+		image::Transparency::Sort => {
+			// Sort all transparent triangles back to front by the view depth of their centroids
+			let mut faces = Vec::new();
+			for (i, object) in objects.iter().enumerate().filter(|&(_, object)| object.transparent) {
+				for face in 0 .. object.model.nfaces() {
+					let mut centroid = vec::Vec3::default();
+					for idx in 0 .. 3 {
+						centroid = centroid.add(object.model.vert(face, idx));
+					}
+					let z = centroid.scale(1f64 / 3f64).transform_pt(modelview).0[2];
+					faces.push((z, i, face));
+				}
+			}
+			faces.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(cmp::Ordering::Equal));
+			pipeline.blend = options.blend;
+			for (_, i, face) in faces {
+				pipeline.draw_face(&objects[i].model, &shaders[i], face);
+			}
+		},
+		image::Transparency::WeightedBlended => {
+			pipeline.target.oit = true;
+			for (object, shader) in objects.iter().zip(shaders.iter()) {
+				if object.transparent {
+					pipeline.draw(&object.model, shader);
+				}
+			}
+			pipeline.target.composite_oit();
+		},
 	}
 
-	tga::write(&image, path::Path::new("output.tga"), true).unwrap();
-	tga::write(&shadow_image, path::Path::new("shadow.tga"), true).unwrap();
+	if let Some(ref name) = options.depth_output {
+		write_depth(&zbuffer, path::Path::new(name)).unwrap();
+	}
+
+	let mut image = target.resolve(image::Format::RgbaF32);
+	if options.supersample > 1 {
+		image = postprocess::downsample(&image, options.supersample, options.downsample);
+	}
+	// overlays and labels are drawn on the display image, so that they keep their colors
+	let mut image = finish_output(image, &options);
+	if !options.overlay.is_empty() {
+		let models: Vec<&model::Model> = objects.iter().map(|object| &object.model).collect();
+		let screen = &output_transform(options.supersample).mul(screen);
+		for &overlay in &options.overlay {
+			overlay::draw(&mut image, overlay, &models, screen, options.overlay_width);
+		}
+	}
+	if let Some(ref label) = options.label {
+		draw_label(&mut image, label, options.label_scale);
+	}
+	write_output(&image, &options).unwrap();
 }
 
-struct ShadowShader<'a> {
-	// uniform
-	shadow_transform: &'a vec::Transform4<f64>,
-	shadow_viewport: &'a vec::Transform4<f64>,
+struct Object {
+	model: model::Model,
+	texture: texture::Texture,
+	normal: texture::Texture,
+	tangent: texture::Texture,
+	specular: texture::Texture,
+	metallic: texture::Texture,
+	roughness: texture::Texture,
+	ao: texture::Texture,
+	// rendered after opaque objects, and blended
+	transparent: bool,
+}
+
+// Textures which contain data rather than colors are not sRGB encoded.
+// A missing texture is empty, or a single texel containing the default value.
+fn read_data_texture(name: &str, default: Option<f32>) -> texture::Texture {
+	let mut image = match (tga::read(path::Path::new(name)), default) {
+		(Ok(image), _) => image,
+		(Err(_), Some(value)) => {
+			let mut image = image::Image::new(1, 1, image::Format::Gray);
+			image.set_color_space(image::ColorSpace::Linear);
+			image.set_f(0, 0, &image::ColorF::new(value, value, value, 1f32));
+			image
+		},
+		(Err(_), None) => image::Image::default(),
+	};
+	image.set_color_space(image::ColorSpace::Linear);
+	texture::Texture::new(image)
+}
+
+// Range of the depths of the models in the viewport, which integer depth formats are stored over.
+// The projection doesn't map depths into the depth range of the viewport, so the range is
+// found from the vertices in front of the near clip plane. Triangles which are clipped can be
+// closer than all of their vertices, and those depths saturate at the close end of the range.
+// The range is padded so that the other drawn depths, including the wireframe bias, don't tie
+// with the clear value or the saturated depths.
+fn depth_range(objects: &[Object], transform: &vec::Transform4<f64>, viewport: &vec::Transform4<f64>) -> (f64, f64) {
+	let (mut min, mut max) = (f64::MAX, f64::MIN);
+	for object in objects {
+		for face in 0 .. object.model.nfaces() {
+			for idx in 0 .. 3 {
+				let p = object.model.vert(face, idx).to_pt4().transform(transform);
+				if p.0[3] < image::NEAR_W {
+					continue;
+				}
+				let z = p.to_pt3().transform_pt(viewport).0[2];
+				min = min.min(z);
+				max = max.max(z);
+			}
+		}
+	}
+	if min > max {
+		// nothing is drawn, so use the depth range of the viewport
+		let (scale, offset) = (viewport.0[2][2], viewport.0[2][3]);
+		return (offset - scale, offset + scale);
+	}
+	let pad = (max - min).max(1f64) * 0.01f64;
+	(min - pad, max + WIREFRAME_DEPTH_BIAS + pad)
+}
+
+fn read_image(path: &path::Path) -> io::Result<image::Image> {
+	match path.extension().and_then(|ext| ext.to_str()) {
+		Some("hdr") => hdr::read(path),
+		Some("exr") => exr::read(path),
+		_ => tga::read(path),
+	}
+}
 
-	// varying
-	shadow_vert: vec::Mat3<f64>,
+// A pipeline for the main view, using the state from the options.
+// The fill mode is only set for the passes which draw the colors of the models.
+fn view_pipeline<'a, T: pipeline::Surface>(target: &'a mut T, zbuffer: &'a mut depth::DepthBuffer, viewport: &'a vec::Transform4<f64>,
+			options: &options::Options) -> pipeline::Pipeline<'a, T> {
+	let mut pipeline = pipeline::Pipeline::new(target, zbuffer, viewport);
+	pipeline.scissor = options.scissor;
+	pipeline.raster.cull = options.cull;
+	pipeline
 }
 
-impl<'a> image::Shader for ShadowShader<'a> {
-	fn vertex(&mut self, idx: usize, vert: &vec::Vec3<f64>, _uv: &vec::Vec3<f64>, _normal: &vec::Vec3<f64>) -> vec::Vec4<f64> {
-		self.shadow_vert.set_row(idx, &vert.transform_pt(&self.shadow_transform));
-		vert.to_pt4().transform(&self.shadow_transform)
+// HDR formats store the depths, other formats store them normalized to the covered range
+fn write_depth(zbuffer: &depth::DepthBuffer, path: &path::Path) -> io::Result<()> {
+	match path.extension().and_then(|ext| ext.to_str()) {
+		Some("hdr") => hdr::write(&zbuffer.to_float_image(), path),
+		Some("exr") => exr::write(&zbuffer.to_float_image(), path, exr::Compression::Zip),
+		_ => tga::write(&zbuffer.to_image(), path, true),
 	}
+}
+
+// Draw text over a translucent box in the bottom left corner of the image
+fn draw_label(image: &mut image::Image, label: &str, scale: usize) {
+	let (width, height) = canvas::text_size(label, scale);
+	let margin = (2 * scale) as f64;
+	let mut canvas = canvas::Canvas::new(image);
+	// the box covers whole pixels, which are centered on integer coordinates
+	let min = vec::Vec2::new(-0.5f64, -0.5f64);
+	let max = vec::Vec2::new(width as f64 + 2f64 * margin - 0.5f64, height as f64 + 2f64 * margin - 0.5f64);
+	canvas.fill_rect(&min, &max, &image::ColorF::new(0f32, 0f32, 0f32, 0.6f32));
+	canvas.text(&vec::Vec2::new(margin, margin + height as f64 - 1f64), label, &image::ColorF::new(1f32, 1f32, 1f32, 1f32), scale);
+}
 
-	fn fragment(&self, bc: &vec::Vec3<f64>) -> Option<image::Color> {
-		let shadow_p = self.shadow_vert.interpolate(bc).transform_pt(self.shadow_viewport);
-		Some(image::Color::new(255, 255, 255, 255).intensity(shadow_p.0[2]))
+// Maps pixels of the rendered image to the downsampled image,
+// where each pixel is at the center of the pixels it covers.
+fn output_transform(supersample: usize) -> vec::Transform4<f64> {
+	let scale = 1f64 / supersample as f64;
+	let offset = (1f64 - supersample as f64) / 2f64 * scale;
+	let mut transform = vec::Transform4::default();
+	transform.0[0][0] = scale;
+	transform.0[1][1] = scale;
+	transform.0[0][3] = offset;
+	transform.0[1][3] = offset;
+	transform
+}
+
+// HDR formats keep the linear image, other formats are tone mapped.
+// FXAA is applied to the image which is written.
+// Debug colors are written unchanged, without tone mapping or FXAA.
+fn finish_output(image: image::Image, options: &options::Options) -> image::Image {
+	let path = path::Path::new(&options.output);
+	let tonemap = if options.debug.is_some() { tonemap::ToneMap::default() } else { options.tonemap };
+	let image = match path.extension().and_then(|ext| ext.to_str()) {
+		Some("hdr") | Some("exr") => image,
+		_ => tonemap.resolve(&image, image::Format::Rgb),
+	};
+	if options.fxaa && options.debug.is_none() {
+		postprocess::fxaa(&image)
+	} else {
+		image
+	}
+}
+
+fn write_output(image: &image::Image, options: &options::Options) -> io::Result<()> {
+	let path = path::Path::new(&options.output);
+	match path.extension().and_then(|ext| ext.to_str()) {
+		Some("hdr") => hdr::write(image, path),
+		Some("exr") => exr::write(image, path, exr::Compression::Zip),
+		_ => tga::write(image, path, true),
 	}
 }
 
@@ -107,35 +504,60 @@ struct Shader<'a> {
 	// options
 	intensity: Intensity,
 	color: Color,
-	shadow: bool,
 
 	// uniform
-	light: &'a vec::Vec3<f64>,
-	light_transform: &'a vec::Vec3<f64>,
+	lights: &'a [light::Light],
+	ambient: f32,
+	environment: Option<&'a environment::Environment>,
+	occlusion: Option<&'a ssao::AmbientOcclusion>,
+	eye: &'a vec::Vec3<f64>,
 	transform: &'a vec::Transform4<f64>,
 	transform_it: &'a vec::Transform4<f64>,
-	texture: Box<image::Image>,
-	normal: Box<image::Image>,
-	tangent: Box<image::Image>,
-	specular: Box<image::Image>,
+	texture: &'a texture::Texture,
+	normal: &'a texture::Texture,
+	tangent: &'a texture::Texture,
+	specular: &'a texture::Texture,
+	metallic: &'a texture::Texture,
+	roughness: &'a texture::Texture,
+	ao: &'a texture::Texture,
+	// flip the normal for back faces
+	two_sided: bool,
+	// discard fragments with a lower diffuse alpha
+	alpha_test: Option<f32>,
+	// multiplies the fragment alpha
+	opacity: f32,
 
 	// shadow
-	shadow_transform: &'a vec::Transform4<f64>,
-	shadow_zbuffer: &'a [f64],
-	shadow_width: usize,
-	shadow_height: usize,
-	shadow_viewport: &'a vec::Transform4<f64>,
-
-	// varying
-	u: vec::Vec3<f64>,
-	v: vec::Vec3<f64>,
-	vert: [vec::Vec3<f64>; 3],
-	shadow_vert: vec::Mat3<f64>,
-	vert_intensity: vec::Vec3<f64>,
-	vert_normal: vec::Mat3<f64>,
+	shadow: Option<&'a shadow::ShadowMap>,
+	shadow_darkness: f64,
 }
 
-#[allow(dead_code)]
+struct Varyings {
+	uv: vec::Vec2<f64>,
+	// position in transformed space
+	vert: vec::Vec3<f64>,
+	vert_world: vec::Vec3<f64>,
+	// position in light clip space
+	shadow_vert: vec::Vec3<f64>,
+	// lighting for gouraud shading
+	color: vec::Vec3<f64>,
+	normal: vec::Vec3<f64>,
+}
+
+impl image::Varyings for Varyings {
+	fn interpolate(v0: &Self, v1: &Self, v2: &Self, bc: &vec::Vec3<f64>) -> Self {
+		Varyings {
+			uv: image::Varyings::interpolate(&v0.uv, &v1.uv, &v2.uv, bc),
+			vert: image::Varyings::interpolate(&v0.vert, &v1.vert, &v2.vert, bc),
+			vert_world: image::Varyings::interpolate(&v0.vert_world, &v1.vert_world, &v2.vert_world, bc),
+			shadow_vert: image::Varyings::interpolate(&v0.shadow_vert, &v1.shadow_vert, &v2.shadow_vert, bc),
+			color: image::Varyings::interpolate(&v0.color, &v1.color, &v2.color, bc),
+			normal: image::Varyings::interpolate(&v0.normal, &v1.normal, &v2.normal, bc),
+		}
+	}
+}
+
+#[derive(Clone, Copy)]
 enum Intensity {
 	Constant,
 	Gouraud,
@@ -146,6 +568,25 @@ enum Intensity {
 	NormalMapTransform,
 	NormalMapSpecular,
 	TangentMap,
+	Pbr,
+}
+
+impl Intensity {
+	fn from_name(name: &str) -> Option<Intensity> {
+		match name {
+			"constant" => Some(Intensity::Constant),
+			"gouraud" => Some(Intensity::Gouraud),
+			"phong" => Some(Intensity::Phong),
+			"phong-transform" => Some(Intensity::PhongTransform),
+			"phong-specular" => Some(Intensity::PhongSpecular),
+			"normal-map" => Some(Intensity::NormalMap),
+			"normal-map-transform" => Some(Intensity::NormalMapTransform),
+			"normal-map-specular" => Some(Intensity::NormalMapSpecular),
+			"tangent-map" => Some(Intensity::TangentMap),
+			"pbr" => Some(Intensity::Pbr),
+			_ => None,
+		}
+	}
 }
 
 #[allow(dead_code)]
@@ -154,120 +595,265 @@ enum Color {
 	Texture,
 }
 
-impl<'a> image::Shader for Shader<'a> {
-	fn vertex(&mut self, idx: usize, vert: &vec::Vec3<f64>, uv: &vec::Vec3<f64>, normal: &vec::Vec3<f64>) -> vec::Vec4<f64> {
-		match self.intensity {
-			Intensity::Gouraud
-			=> {
-				self.vert_intensity.0[idx] = normal.dot(&self.light).max(0f64);
-			},
-			Intensity::Phong
-			=> {
-				self.vert_normal.set_row(idx, normal);
-			},
-			Intensity::PhongTransform
-			| Intensity::PhongSpecular
-			| Intensity::TangentMap
-			=> {
-				self.vert_normal.set_row(idx, &normal.transform_vec(&self.transform_it));
-			},
-			Intensity::Constant
-			| Intensity::NormalMap
-			| Intensity::NormalMapSpecular
-			| Intensity::NormalMapTransform
-			=> { }
-		}
-		self.u.0[idx] = uv.0[0];
-		self.v.0[idx] = uv.0[1];
-		self.vert[idx].0 = vert.transform_pt(&self.transform).0;
-		if self.shadow {
-			self.shadow_vert.set_row(idx, &vert.transform_pt(&self.shadow_transform));
+impl<'a> Shader<'a> {
+	// Sample a texture with the level of detail for the fragment
+	fn sample(&self, texture: &texture::Texture, fragment: &image::Fragment<Varyings>) -> image::ColorF {
+		let (u, v) = fragment.varyings.uv.as_tuple();
+		let (du_dx, dv_dx) = fragment.ddx.uv.as_tuple();
+		let (du_dy, dv_dy) = fragment.ddy.uv.as_tuple();
+		texture.sample(u, v, texture.lod(du_dx, dv_dx, du_dy, dv_dy))
+	}
+
+	// The surface color, or None if the fragment fails the alpha test
+	fn albedo(&self, fragment: &image::Fragment<Varyings>) -> Option<image::ColorF> {
+		let color = match self.color {
+			Color::White => image::ColorF::new(1f32, 1f32, 1f32, 1f32),
+			Color::Texture => self.sample(self.texture, fragment),
+		};
+		match self.alpha_test {
+			Some(threshold) if color.a < threshold => None,
+			_ => Some(color),
 		}
-		vert.to_pt4().transform(&self.transform)
 	}
 
-	fn fragment(&self, bc: &vec::Vec3<f64>) -> Option<image::Color> {
-		let u = (self.u.dot(bc) * self.texture.get_width() as f64).floor() as usize;
-		let v = (self.v.dot(bc) * self.texture.get_height() as f64).floor() as usize;
-		let ambient = 0f64;
-		let diffuse;
-		let mut spec = 0f64;
-		match self.intensity {
-			Intensity::Constant => {
-				diffuse = 1.0;
-			},
-			Intensity::Gouraud => {
-				diffuse = self.vert_intensity.dot(bc).max(0f64);
+	// The normal, whether it is in transformed space, and the specular power
+	fn normal(&self, fragment: &image::Fragment<Varyings>) -> (vec::Vec3<f64>, bool, Option<i32>) {
+		let (varyings, dx, dy) = (&fragment.varyings, &fragment.ddx, &fragment.ddy);
+		let (du_dx, dv_dx) = dx.uv.as_tuple();
+		let (du_dy, dv_dy) = dy.uv.as_tuple();
+		let sample = |texture: &texture::Texture| self.sample(texture, fragment);
+		let (normal, transformed, spec_power) = match self.intensity {
+			Intensity::Constant
+			| Intensity::Gouraud => {
+				(vec::Vec3::default(), false, None)
 			},
 			Intensity::Phong => {
-				let normal = &self.vert_normal.interpolate(bc).normalize();
-				diffuse = normal.dot(&self.light).max(0f64);
+				(vec::Vec3(varyings.normal.0).normalize(), false, None)
 			},
 			Intensity::PhongTransform => {
-				let normal = &self.vert_normal.interpolate(bc).normalize();
-				diffuse = normal.dot(&self.light_transform).max(0f64);
+				(vec::Vec3(varyings.normal.0).normalize(), true, None)
 			},
 			Intensity::PhongSpecular => {
-				let normal = &self.vert_normal.interpolate(bc).normalize();
-				diffuse = normal.dot(&self.light_transform).max(0f64);
-				let reflect = normal.scale(2f64 * normal.dot(&self.light_transform)).sub(&self.light_transform).normalize();
-				let spec_power = self.specular.get(u, v).r as i32;
-				spec = reflect.0[2].max(0f64).powi(spec_power);
+				let spec_power = (sample(self.specular).r * 255f32) as i32;
+				(vec::Vec3(varyings.normal.0).normalize(), true, Some(spec_power))
 			},
 			Intensity::NormalMap => {
-				let normal = &self.normal.get(u, v).to_vec3f().normalize();
-				diffuse = normal.dot(&self.light).max(0f64);
+				(sample(self.normal).to_vec3f().normalize(), false, None)
 			},
 			Intensity::NormalMapTransform => {
-				let normal = self.normal.get(u, v).to_vec3f().transform_vec(&self.transform_it).normalize();
-				diffuse = normal.dot(&self.light_transform).max(0f64);
+				(sample(self.normal).to_vec3f().transform_vec(self.transform_it).normalize(), true, None)
 			},
 			Intensity::NormalMapSpecular => {
-				let normal = self.normal.get(u, v).to_vec3f().transform_vec(&self.transform_it).normalize();
-				diffuse = normal.dot(&self.light_transform).max(0f64);
-				let reflect = normal.scale(2f64 * normal.dot(&self.light_transform)).sub(&self.light_transform).normalize();
-				let spec_power = self.specular.get(u, v).r as i32 + 1;
-				spec = reflect.0[2].max(0f64).powi(spec_power);
+				let spec_power = (sample(self.specular).r * 255f32) as i32 + 1;
+				(sample(self.normal).to_vec3f().transform_vec(self.transform_it).normalize(), true, Some(spec_power))
 			},
 			Intensity::TangentMap => {
-				let n = &self.vert_normal.interpolate(bc).normalize();
+				let n = &vec::Vec3(varyings.normal.0).normalize();
 
 				// Three vectors for which we know the dot product with u/v/n
 				let a = &mut vec::Mat3::default();
-				a.set_row(0, &self.vert[1].sub(&self.vert[0]));
-				a.set_row(1, &self.vert[2].sub(&self.vert[0]));
+				a.set_row(0, &dx.vert);
+				a.set_row(1, &dy.vert);
 				a.set_row(2, n);
 				let ai = &a.inv();
 
 				// Solve for u/v/n, normalize, and create change of basis transform
 				let b = &vec::Transform3::rotate(
-					&vec::Vec3([ self.u.0[1] - self.u.0[0], self.u.0[2] - self.u.0[0], 0f64]).mul(ai).normalize(),
-					&vec::Vec3([ self.v.0[1] - self.v.0[0], self.v.0[2] - self.v.0[0], 0f64]).mul(ai).normalize(),
+					&vec::Vec3([ du_dx, du_dy, 0f64]).mul(ai).normalize(),
+					&vec::Vec3([ dv_dx, dv_dy, 0f64]).mul(ai).normalize(),
 					n);
 
-				let normal = &self.tangent.get(u, v).to_vec3f().transform(b).normalize();
-				diffuse = normal.dot(&self.light_transform).max(0f64);
+				(sample(self.tangent).to_vec3f().transform(b).normalize(), true, None)
+			},
+			Intensity::Pbr => {
+				// Object space normal map if there is one, otherwise the vertex normal
+				if self.normal.get_width() != 0 {
+					(sample(self.normal).to_vec3f().normalize(), false, None)
+				} else {
+					(vec::Vec3(varyings.normal.0).normalize(), false, None)
+				}
 			},
 		};
-		let mut shadow = 1.0;
-		if self.shadow {
-			let shadow_p = self.shadow_vert.interpolate(bc).transform_pt(self.shadow_viewport);
-			let shadow_x = shadow_p.0[0] as usize;
-			let shadow_y = shadow_p.0[1] as usize;
-			if shadow_x < self.shadow_width && shadow_y < self.shadow_height {
-				let shadow_z = self.shadow_zbuffer[shadow_x + shadow_y * self.shadow_width];
-				if shadow_p.0[2] + 0.01 < shadow_z {
-					shadow = 0.3;
+		// Light the back of two sided surfaces as if it was the front
+		let normal = if self.two_sided && !fragment.front_facing { normal.scale(-1f64) } else { normal };
+		(normal, transformed, spec_power)
+	}
+
+	// Fraction of the first light which is not shadowed
+	fn shadow(&self, fragment: &image::Fragment<Varyings>) -> f64 {
+		let (varyings, dx, dy) = (&fragment.varyings, &fragment.ddx, &fragment.ddy);
+		match self.shadow {
+			Some(shadow) => {
+				// the triangle only needs to give the depth slope
+				let p = &varyings.shadow_vert;
+				let triangle = &mut vec::Mat3::default();
+				triangle.set_row(0, p);
+				triangle.set_row(1, &p.add(&dx.shadow_vert));
+				triangle.set_row(2, &p.add(&dy.shadow_vert));
+				let visibility = shadow.visibility(p, triangle);
+				1f64 - self.shadow_darkness * (1f64 - visibility)
+			},
+			None => 1f64,
+		}
+	}
+}
+
+impl<'a> image::Shader for Shader<'a> {
+	type Varyings = Varyings;
+	type Output = image::ColorF;
+
+	fn vertex(&self, vert: &vec::Vec3<f64>, uv: &vec::Vec3<f64>, normal: &vec::Vec3<f64>) -> image::Vertex<Varyings> {
+		let mut varyings = Varyings {
+			uv: vec::Vec2::new(uv.0[0], uv.0[1]),
+			vert: vert.transform_pt(self.transform),
+			vert_world: vec::Vec3(vert.0),
+			shadow_vert: Default::default(),
+			color: Default::default(),
+			normal: Default::default(),
+		};
+		match self.intensity {
+			Intensity::Gouraud
+			=> {
+				let mut color = image::ColorF::default();
+				for light in self.lights {
+					let (l, radiance) = light.illuminate(vert);
+					color = color.add(&radiance.scale(normal.dot(&l).max(0f64) as f32));
 				}
-			}
+				varyings.color = vec::Vec3::new(color.r as f64, color.g as f64, color.b as f64);
+			},
+			Intensity::Phong
+			| Intensity::Pbr
+			=> {
+				varyings.normal = vec::Vec3(normal.0);
+			},
+			Intensity::PhongTransform
+			| Intensity::PhongSpecular
+			| Intensity::TangentMap
+			=> {
+				varyings.normal = normal.transform_vec(self.transform_it);
+			},
+			Intensity::Constant
+			| Intensity::NormalMap
+			| Intensity::NormalMapSpecular
+			| Intensity::NormalMapTransform
+			=> { }
 		}
-		let color = match self.color {
-			Color::White => image::Color::new(255, 255, 255, 255),
-			Color::Texture => self.texture.get(u, v),
+		if let Some(shadow) = self.shadow {
+			varyings.shadow_vert = vert.transform_pt(&shadow.transform);
+		}
+		(vert.to_pt4().transform(self.transform), varyings)
+	}
+
+	fn fragment(&self, fragment: &image::Fragment<Varyings>) -> Option<image::ColorF> {
+		let sample = |texture: &texture::Texture| self.sample(texture, fragment);
+		let color = self.albedo(fragment)?;
+		let (normal, transformed, spec_power) = self.normal(fragment);
+		let shadow = self.shadow(fragment);
+		let varyings = &fragment.varyings;
+
+		let occlusion = match self.occlusion {
+			Some(occlusion) => occlusion.get(fragment.x, fragment.y),
+			None => 1f32,
 		};
-		let convert = |x| {
-			(ambient + (x as f64) * shadow * (diffuse + 0.6 * spec)).min(255f64) as u8
+
+		let lighting = match self.intensity {
+			Intensity::Pbr => {
+				let p = &varyings.vert_world;
+				let view = &self.eye.sub(p).normalize();
+				let material = pbr::Material {
+					base_color: color,
+					metallic: sample(self.metallic).r as f64,
+					roughness: sample(self.roughness).r as f64,
+				};
+				let ao = sample(self.ao).r * occlusion;
+				let radiance = material.indirect(self.environment, self.ambient, &normal, view).scale(ao)
+					.add(&material.direct(self.lights, p, &normal, view, shadow));
+				return Some(image::ColorF { a: radiance.a * self.opacity, ..radiance });
+			},
+			Intensity::Constant => {
+				image::ColorF::new(1f32, 1f32, 1f32, 1f32).scale(shadow as f32)
+			},
+			Intensity::Gouraud => {
+				let c = &varyings.color;
+				image::ColorF::new(c.0[0] as f32, c.0[1] as f32, c.0[2] as f32, 1f32).scale(shadow as f32)
+			},
+			_ => {
+				let p = &varyings.vert_world;
+				let mut lighting = image::ColorF::new(0f32, 0f32, 0f32, 1f32);
+				for (i, light) in self.lights.iter().enumerate() {
+					let (l, radiance) = light.illuminate(p);
+					let l = &if transformed { l.transform_vec(self.transform).normalize() } else { l };
+					let diffuse = normal.dot(l).max(0f64);
+					let spec = match spec_power {
+						Some(spec_power) => {
+							let reflect = normal.scale(2f64 * normal.dot(l)).sub(l).normalize();
+							reflect.0[2].max(0f64).powi(spec_power)
+						},
+						None => 0f64,
+					};
+					let shadow = if i == 0 { shadow } else { 1f64 };
+					lighting = lighting.add(&radiance.scale((shadow * (diffuse + 0.6 * spec)) as f32));
+				}
+				lighting
+			},
 		};
-		Some(color.map(convert))
+
+		let color = color.mul(&lighting).map(|x| { self.ambient * occlusion + x });
+		Some(image::ColorF { a: color.a * self.opacity, ..color })
+	}
+}
+
+// Writes the surface attributes from a PBR shader to the G-buffer targets
+struct GBufferShader<'a: 'b, 'b> {
+	shader: &'b Shader<'a>,
+	// material ID, where 0 is no geometry
+	id: usize,
+}
+
+impl<'a, 'b> image::Shader for GBufferShader<'a, 'b> {
+	type Varyings = Varyings;
+	type Output = [image::ColorF; 3];
+
+	fn vertex(&self, vert: &vec::Vec3<f64>, uv: &vec::Vec3<f64>, normal: &vec::Vec3<f64>) -> image::Vertex<Varyings> {
+		image::Shader::vertex(self.shader, vert, uv, normal)
+	}
+
+	fn fragment(&self, fragment: &image::Fragment<Varyings>) -> Option<[image::ColorF; 3]> {
+		let shader = self.shader;
+		let albedo = shader.albedo(fragment)?;
+		let (n, _, _) = shader.normal(fragment);
+		let shadow = shader.shadow(fragment);
+		let mut outputs = [image::ColorF::default(); 3];
+		outputs[deferred::ALBEDO] = albedo;
+		outputs[deferred::NORMAL] = image::ColorF::new(n.0[0] as f32, n.0[1] as f32, n.0[2] as f32, shadow as f32);
+		outputs[deferred::MATERIAL] = image::ColorF::new(
+			shader.sample(shader.metallic, fragment).r,
+			shader.sample(shader.roughness, fragment).r,
+			shader.sample(shader.ao, fragment).r,
+			self.id as f32);
+		Some(outputs)
+	}
+}
+
+// Draws models in a constant color, optionally expanded along their normals
+struct ColorShader<'a> {
+	transform: &'a vec::Transform4<f64>,
+	// distance to move each vertex along its normal, in model units
+	offset: f64,
+	color: image::ColorF,
+}
+
+impl<'a> image::Shader for ColorShader<'a> {
+	// unused
+	type Varyings = f64;
+	type Output = image::ColorF;
+
+	fn vertex(&self, vert: &vec::Vec3<f64>, _uv: &vec::Vec3<f64>, normal: &vec::Vec3<f64>) -> image::Vertex<f64> {
+		let offset = vec::Vec3(normal.0).normalize().scale(self.offset);
+		(vert.add(&offset).to_pt4().transform(self.transform), 0f64)
+	}
+
+	fn fragment(&self, _fragment: &image::Fragment<f64>) -> Option<image::ColorF> {
+		Some(self.color)
 	}
 }
@@ -1,10 +1,10 @@
 use image;
-use image::Shader;
 use vec;
 
 use std::{fs, io, num, path};
 use std::io::BufRead;
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum ModelError {
 	Io(io::Error),
@@ -35,6 +35,7 @@ struct Face {
 	normal: Vec<usize>,
 }
 
+#[derive(Default)]
 pub struct Model {
 	vert: Vec<vec::Vec3<f64>>,
 	normal: Vec<vec::Vec3<f64>>,
@@ -42,29 +43,18 @@ pub struct Model {
 	face: Vec<Face>,
 }
 
-impl Default for Model {
-	fn default() -> Self {
-		Model {
-			vert: Vec::new(),
-			normal: Vec::new(),
-			texture: Vec::new(),
-			face: Vec::new(),
-		}
-	}
-}
-
 impl Model {
 	pub fn read(path: &path::Path) -> Result<Model, ModelError> {
-		let file = io::BufReader::new(try!(fs::File::open(path)));
+		let file = io::BufReader::new(fs::File::open(path)?);
 		let mut model = Model::default();
 		for line in file.lines() {
-			let line = try!(line);
+			let line = line?;
 			let mut words = line.split_whitespace();
 			match words.next() {
-				Some("v") => model.vert.push(try!(Model::read_vert(&mut words))),
-				Some("vn") => model.normal.push(try!(Model::read_vert(&mut words))),
-				Some("vt") => model.texture.push(try!(Model::read_vert(&mut words))),
-				Some("f") => model.face.push(try!(Model::read_face(&mut words, model.vert.len(), model.texture.len(), model.normal.len()))),
+				Some("v") => model.vert.push(Model::read_vert(&mut words)?),
+				Some("vn") => model.normal.push(Model::read_vert(&mut words)?),
+				Some("vt") => model.texture.push(Model::read_vert(&mut words)?),
+				Some("f") => model.face.push(Model::read_face(&mut words, model.vert.len(), model.texture.len(), model.normal.len())?),
 				_ => (),
 			}
 		};
@@ -72,15 +62,15 @@ impl Model {
 	}
 
 	fn read_vert<'a, I: Iterator<Item=&'a str>>(words: &mut I) -> Result<vec::Vec3<f64>, ModelError> {
-		let x = try!(Model::read_f64(words));
-		let y = try!(Model::read_f64(words));
+		let x = Model::read_f64(words)?;
+		let y = Model::read_f64(words)?;
 		let z = Model::read_f64(words).unwrap_or(0f64);
 		Ok(vec::Vec3::new(x, y, z))
 	}
 
 	fn read_f64<'a, I: Iterator<Item=&'a str>>(words: &mut I) -> Result<f64, ModelError> {
 		match words.next() {
-			Some(word) => Ok(try!(word.parse::<f64>())),
+			Some(word) => Ok(word.parse::<f64>()?),
 			None => Err(ModelError::Parse("missing f64".into())),
 		}
 	}
@@ -93,9 +83,9 @@ impl Model {
 		};
 		for word in words {
 			let mut indices = word.split('/');
-			face.vert.push(try!(Model::read_idx(indices.next(), vert_len)));
-			face.texture.push(try!(Model::read_idx(indices.next(), texture_len)));
-			face.normal.push(try!(Model::read_idx(indices.next(), normal_len)));
+			face.vert.push(Model::read_idx(indices.next(), vert_len)?);
+			face.texture.push(Model::read_idx(indices.next(), texture_len)?);
+			face.normal.push(Model::read_idx(indices.next(), normal_len)?);
 		}
 		if face.vert.len() != 3 {
 			return Err(ModelError::Parse("face must have exactly 3 vertices".into()));
@@ -105,7 +95,7 @@ impl Model {
 
 	fn read_idx(word_opt: Option<&str>, len: usize) -> Result<usize, ModelError> {
 		let idx = match word_opt {
-			Some(word) => try!(word.parse::<usize>()) - 1,
+			Some(word) => word.parse::<usize>()? - 1,
 			None => return Err(ModelError::Parse("missing idx".into())),
 		};
 		if idx >= len {
@@ -135,21 +125,35 @@ impl Model {
 		}
 	}
 
-	pub fn render(&self, image: &mut image::Image, shader: &mut image::Shader, viewport: &vec::Transform4<f64>,
-		      zbuffer: &mut [f64]) {
-		for face in &self.face {
-			let p0 = &self.render_vertex(shader, face, 0);
-			let p1 = &self.render_vertex(shader, face, 1);
-			let p2 = &self.render_vertex(shader, face, 2);
-			image.render(shader, viewport, p0, p1, p2, zbuffer);
+	// Bounding box of the vertices after transformation, or None if there are no vertices
+	pub fn bounds(&self, transform: &vec::Transform4<f64>) -> Option<(vec::Vec3<f64>, vec::Vec3<f64>)> {
+		let mut bounds: Option<(vec::Vec3<f64>, vec::Vec3<f64>)> = None;
+		for vert in &self.vert {
+			let p = vert.transform_pt(transform);
+			bounds = Some(match bounds {
+				None => (vec::Vec3(p.0), p),
+				Some((min, max)) => (
+					vec::Vec3([ min.0[0].min(p.0[0]), min.0[1].min(p.0[1]), min.0[2].min(p.0[2]) ]),
+					vec::Vec3([ max.0[0].max(p.0[0]), max.0[1].max(p.0[1]), max.0[2].max(p.0[2]) ])),
+			});
 		}
+		bounds
+	}
+
+	pub fn nfaces(&self) -> usize {
+		self.face.len()
+	}
+
+	pub fn vert(&self, face: usize, idx: usize) -> &vec::Vec3<f64> {
+		&self.vert[self.face[face].vert[idx]]
+	}
+
+	pub fn uv(&self, face: usize, idx: usize) -> &vec::Vec3<f64> {
+		&self.texture[self.face[face].texture[idx]]
 	}
 
-	fn render_vertex(&self, shader: &mut image::Shader, face: &Face, idx: usize) -> vec::Vec4<f64> {
-		let vert = &self.vert[face.vert[idx]];
-		let texture = &self.texture[face.texture[idx]];
-		let normal = &self.normal[face.normal[idx]];
-		shader.vertex(idx, vert, texture, normal)
+	pub fn normal(&self, face: usize, idx: usize) -> &vec::Vec3<f64> {
+		&self.normal[self.face[face].normal[idx]]
 	}
 }
 
@@ -0,0 +1,355 @@
+use bake;
+use debug;
+use depth;
+use image;
+use light;
+use overlay;
+use pipeline;
+use postprocess;
+use ssao;
+use texture;
+use tonemap;
+use Intensity;
+
+pub struct Options {
+	pub models: Vec<String>,
+	pub output: String,
+	pub tonemap: tonemap::ToneMap,
+	pub filter: texture::Filter,
+	// Wrap modes for a texture slot, or all slots if None
+	pub wrap: Vec<(Option<String>, texture::Wrap)>,
+	pub shadow: bool,
+	pub shadow_size: usize,
+	pub shadow_bias: f64,
+	pub shadow_slope_bias: f64,
+	pub shadow_max_bias: f64,
+	pub shadow_pcf: usize,
+	pub shadow_darkness: f64,
+	// Write the shadow map as a grayscale image
+	pub dump_shadow: Option<String>,
+	pub ssao: bool,
+	pub ssao_settings: ssao::Settings,
+	// Write the ambient occlusion as a grayscale image
+	pub dump_ssao: Option<String>,
+	// Bake ambient occlusion textures for the models before rendering
+	pub bake_ao: bool,
+	pub bake_settings: bake::Settings,
+	// Replace existing files when baking
+	pub force: bool,
+	// Samples per pixel for multisample anti-aliasing
+	pub msaa: usize,
+	// Render at this multiple of the output size, and downsample
+	pub supersample: usize,
+	pub downsample: postprocess::Downsample,
+	pub fxaa: bool,
+	// Rasterize opaque models into a G-buffer, and light each pixel afterwards
+	pub deferred: bool,
+	// Write each G-buffer target as an image named with this prefix
+	pub dump_gbuffer: Option<String>,
+	pub cull: pipeline::Cull,
+	pub fill: pipeline::FillMode,
+	// Color of lines drawn over the edges of the visible triangles of the opaque models
+	pub wireframe: Option<image::ColorF>,
+	// Only draw pixels inside this rectangle
+	pub scissor: Option<pipeline::Rect>,
+	// Debug lines drawn over the image
+	pub overlay: Vec<overlay::Overlay>,
+	// width of the overlay lines in pixels of the output
+	pub overlay_width: f64,
+	// Text drawn in the bottom left corner of the output
+	pub label: Option<String>,
+	// size of each pixel of the label font, in pixels of the output
+	pub label_scale: usize,
+	pub depth_format: depth::Format,
+	// Closer fragments have larger depths, so greater keeps the closest surface,
+	// and less keeps the farthest surface instead of the closest as in OpenGL
+	pub depth_compare: depth::Compare,
+	// Write the depth buffer as a grayscale image, or as float depths for hdr and exr
+	pub depth_output: Option<String>,
+	// Models which are rendered after opaque models, and blended
+	pub transparent: Vec<String>,
+	pub opacity: f32,
+	pub blend: image::Blend,
+	pub transparency: image::Transparency,
+	// Light back faces using the reversed normal
+	pub two_sided: bool,
+	// Color of an outline around the silhouette of the opaque models
+	pub outline: Option<image::ColorF>,
+	// how far the outline extends beyond the models, in model units
+	pub outline_width: f64,
+	// Discard fragments with a diffuse alpha below this threshold
+	pub alpha_test: Option<f32>,
+	pub shading: Intensity,
+	// Draw every model opaque with a debug shader, instead of lighting it
+	pub debug: Option<debug::Mode>,
+	pub lights: Vec<light::Light>,
+	pub ambient: f32,
+	// Equirectangular environment map for image based lighting and the background
+	pub env: Option<String>,
+	pub env_intensity: f32,
+	// PBR material values for models without metallic or roughness maps
+	pub metallic: f32,
+	pub roughness: f32,
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Options {
+			models: Vec::new(),
+			output: "output.tga".into(),
+			tonemap: Default::default(),
+			filter: texture::Filter::Trilinear,
+			wrap: Vec::new(),
+			shadow: false,
+			shadow_size: 1024,
+			shadow_bias: 0.002f64,
+			shadow_slope_bias: 1.5f64,
+			shadow_max_bias: 0.01f64,
+			shadow_pcf: 3,
+			shadow_darkness: 0.7f64,
+			dump_shadow: None,
+			ssao: false,
+			ssao_settings: Default::default(),
+			dump_ssao: None,
+			bake_ao: false,
+			bake_settings: Default::default(),
+			force: false,
+			msaa: 1,
+			supersample: 1,
+			downsample: postprocess::Downsample::Box,
+			fxaa: false,
+			deferred: false,
+			dump_gbuffer: None,
+			cull: pipeline::Cull::None,
+			fill: pipeline::FillMode::Fill,
+			wireframe: None,
+			scissor: None,
+			overlay: Vec::new(),
+			overlay_width: 1f64,
+			label: None,
+			label_scale: 2,
+			depth_format: depth::Format::F32,
+			depth_compare: depth::Compare::Greater,
+			depth_output: None,
+			transparent: Vec::new(),
+			opacity: 1f32,
+			blend: image::Blend::Alpha,
+			transparency: image::Transparency::Sort,
+			two_sided: false,
+			outline: None,
+			outline_width: 0.02f64,
+			alpha_test: None,
+			shading: Intensity::Constant,
+			debug: None,
+			lights: Vec::new(),
+			ambient: 0f32,
+			env: None,
+			env_intensity: 1f32,
+			metallic: 0f32,
+			roughness: 0.5f32,
+		}
+	}
+}
+
+impl Options {
+	// Arguments are model names, or options of the form "--name=value".
+	pub fn parse<I: Iterator<Item=String>>(args: I) -> Result<Options, String> {
+		let mut options = Options::default();
+		for arg in args {
+			if !arg.starts_with("--") {
+				options.models.push(arg);
+				continue;
+			}
+			let mut words = arg[2..].splitn(2, '=');
+			let name = words.next().unwrap_or("");
+			let value = words.next().unwrap_or("");
+			match name {
+				"output" => options.output = value.into(),
+				"tonemap" => {
+					options.tonemap.operator = tonemap::Operator::from_name(value)
+						.ok_or(format!("unknown tonemap operator: {}", value))?;
+				},
+				"filter" => {
+					options.filter = texture::Filter::from_name(value)
+						.ok_or(format!("unknown texture filter: {}", value))?;
+				},
+				"wrap" => {
+					// "mode" or "slot:mode"
+					let (slot, mode) = match value.find(':') {
+						Some(i) => (Some(value[.. i].to_string()), &value[i + 1 ..]),
+						None => (None, value),
+					};
+					let wrap = texture::Wrap::from_name(mode)
+						.ok_or(format!("unknown texture wrap: {}", mode))?;
+					options.wrap.push((slot, wrap));
+				},
+				"exposure" => options.tonemap.exposure = parse_f32(name, value)?,
+				"ssao" => options.ssao = parse_bool(name, value)?,
+				"ssao-kernel" => options.ssao_settings.kernel = parse_usize(name, value)?,
+				"ssao-radius" => options.ssao_settings.radius = parse_f64(name, value)?,
+				"ssao-blur" => options.ssao_settings.blur = parse_usize(name, value)?,
+				"dump-ssao" => options.dump_ssao = Some(value.into()),
+				"bake-ao" => options.bake_ao = parse_bool(name, value)?,
+				"bake-mode" => {
+					options.bake_settings.mode = bake::Mode::from_name(value)
+						.ok_or(format!("unknown bake mode: {}", value))?;
+				},
+				"bake-size" => options.bake_settings.size = parse_usize(name, value)?,
+				"bake-directions" => options.bake_settings.directions = parse_usize(name, value)?,
+				"bake-depth-size" => options.bake_settings.depth_size = parse_usize(name, value)?,
+				"bake-padding" => options.bake_settings.padding = parse_usize(name, value)?,
+				"force" => options.force = parse_bool(name, value)?,
+				"msaa" => {
+					options.msaa = parse_usize(name, value)?;
+					if ![1, 2, 4, 8, 16].contains(&options.msaa) {
+						return Err(format!("invalid msaa: {} (must be 1, 2, 4, 8 or 16)", value));
+					}
+				},
+				"supersample" => {
+					options.supersample = parse_usize(name, value)?;
+					if options.supersample == 0 {
+						return Err(format!("invalid supersample: {}", value));
+					}
+				},
+				"downsample" => {
+					options.downsample = postprocess::Downsample::from_name(value)
+						.ok_or(format!("unknown downsample filter: {}", value))?;
+				},
+				"fxaa" => options.fxaa = parse_bool(name, value)?,
+				"deferred" => options.deferred = parse_bool(name, value)?,
+				"dump-gbuffer" => options.dump_gbuffer = Some(value.into()),
+				"cull" => {
+					options.cull = pipeline::Cull::from_name(value)
+						.ok_or(format!("unknown cull mode: {}", value))?;
+				},
+				"fill" => {
+					options.fill = pipeline::FillMode::from_name(value)
+						.ok_or(format!("unknown fill mode: {}", value))?;
+				},
+				"wireframe" => options.wireframe = Some(parse_color(name, value)?),
+				"scissor" => options.scissor = Some(parse_rect(name, value)?),
+				"overlay" => {
+					options.overlay.push(overlay::Overlay::from_name(value)
+						.ok_or(format!("unknown overlay: {}", value))?);
+				},
+				"overlay-width" => options.overlay_width = parse_f64(name, value)?,
+				"label" => options.label = Some(value.into()),
+				"label-scale" => options.label_scale = parse_usize(name, value)?,
+				"depth-format" => {
+					options.depth_format = depth::Format::from_name(value)
+						.ok_or(format!("unknown depth format: {}", value))?;
+				},
+				"depth-compare" => {
+					options.depth_compare = depth::Compare::from_name(value)
+						.ok_or(format!("unknown depth compare: {}", value))?;
+				},
+				"depth-output" => options.depth_output = Some(value.into()),
+				"transparent" => options.transparent.push(value.into()),
+				"opacity" => options.opacity = parse_f32(name, value)?,
+				"blend" => {
+					options.blend = image::Blend::from_name(value)
+						.ok_or(format!("unknown blend mode: {}", value))?;
+				},
+				"transparency" => {
+					options.transparency = image::Transparency::from_name(value)
+						.ok_or(format!("unknown transparency: {}", value))?;
+				},
+				"two-sided" => options.two_sided = parse_bool(name, value)?,
+				"outline" => options.outline = Some(parse_color(name, value)?),
+				"outline-width" => options.outline_width = parse_f64(name, value)?,
+				"alpha-test" => {
+					// "--alpha-test" uses a threshold of 0.5
+					options.alpha_test = Some(if value.is_empty() { 0.5f32 } else { parse_f32(name, value)? });
+				},
+				"shading" => {
+					options.shading = Intensity::from_name(value)
+						.ok_or(format!("unknown shading: {}", value))?;
+				},
+				"debug" => {
+					options.debug = Some(debug::Mode::from_name(value)
+						.ok_or(format!("unknown debug mode: {}", value))?);
+				},
+				"light" => options.lights.push(light::Light::parse(value)?),
+				"ambient" => options.ambient = parse_f32(name, value)?,
+				"env" => options.env = Some(value.into()),
+				"env-intensity" => options.env_intensity = parse_f32(name, value)?,
+				"metallic" => options.metallic = parse_f32(name, value)?,
+				"roughness" => options.roughness = parse_f32(name, value)?,
+				"shadow" => options.shadow = parse_bool(name, value)?,
+				"shadow-size" => options.shadow_size = parse_usize(name, value)?,
+				"shadow-bias" => options.shadow_bias = parse_f64(name, value)?,
+				"shadow-slope-bias" => options.shadow_slope_bias = parse_f64(name, value)?,
+				"shadow-max-bias" => options.shadow_max_bias = parse_f64(name, value)?,
+				"shadow-pcf" => {
+					options.shadow_pcf = parse_usize(name, value)?;
+					if options.shadow_pcf % 2 == 0 {
+						return Err(format!("invalid {}: kernel width must be odd", name));
+					}
+				},
+				"shadow-darkness" => options.shadow_darkness = parse_f64(name, value)?,
+				"dump-shadow" => options.dump_shadow = Some(value.into()),
+				_ => return Err(format!("unknown option: {}", arg)),
+			}
+		}
+		if options.lights.is_empty() {
+			options.lights.push(light::Light::default());
+		}
+		if options.shadow && !matches!(options.lights[0].kind, light::Kind::Directional { .. }) {
+			return Err("shadows require the first light to be directional".into());
+		}
+		if options.deferred && !matches!(options.shading, Intensity::Pbr) {
+			return Err("deferred shading requires --shading=pbr".into());
+		}
+		if options.deferred && options.msaa != 1 {
+			return Err("deferred shading does not support msaa".into());
+		}
+		if options.deferred && options.fill != pipeline::FillMode::Fill {
+			return Err("deferred shading does not support fill modes".into());
+		}
+		Ok(options)
+	}
+}
+
+fn parse_f32(name: &str, value: &str) -> Result<f32, String> {
+	value.parse::<f32>().map_err(|err| format!("invalid {}: {}", name, err))
+}
+
+fn parse_f64(name: &str, value: &str) -> Result<f64, String> {
+	value.parse::<f64>().map_err(|err| format!("invalid {}: {}", name, err))
+}
+
+fn parse_usize(name: &str, value: &str) -> Result<usize, String> {
+	value.parse::<usize>().map_err(|err| format!("invalid {}: {}", name, err))
+}
+
+// Comma separated x, y, width and height
+fn parse_rect(name: &str, value: &str) -> Result<pipeline::Rect, String> {
+	let r = (value.split(',').map(|word| parse_usize(name, word)).collect::<Result<Vec<usize>, String>>())?;
+	if r.len() != 4 {
+		return Err(format!("invalid {}: {}", name, value));
+	}
+	Ok(pipeline::Rect {
+		x: r[0],
+		y: r[1],
+		width: r[2],
+		height: r[3],
+	})
+}
+
+// Comma separated red, green and blue
+fn parse_color(name: &str, value: &str) -> Result<image::ColorF, String> {
+	let c = (value.split(',').map(|word| parse_f32(name, word)).collect::<Result<Vec<f32>, String>>())?;
+	if c.len() != 3 {
+		return Err(format!("invalid {}: {}", name, value));
+	}
+	Ok(image::ColorF::new(c[0], c[1], c[2], 1f32))
+}
+
+// An empty value enables the option, so "--name" is the same as "--name=true"
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+	match value {
+		"" | "true" | "1" => Ok(true),
+		"false" | "0" => Ok(false),
+		_ => Err(format!("invalid {}: {}", name, value)),
+	}
+}
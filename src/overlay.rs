@@ -0,0 +1,131 @@
+use image;
+use model;
+use vec;
+
+// Length of the drawn normals, in model units
+const NORMAL_LENGTH: f64 = 0.1f64;
+
+// Debug lines drawn over the rendered image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overlay {
+	// the x, y and z axes from the origin, in red, green and blue
+	Axes,
+	// the bounding box of each model
+	Bounds,
+	// the average vertex normal at the center of each face
+	Normals,
+}
+
+impl Overlay {
+	pub fn from_name(name: &str) -> Option<Overlay> {
+		match name {
+			"axes" => Some(Overlay::Axes),
+			"bounds" => Some(Overlay::Bounds),
+			"normals" => Some(Overlay::Normals),
+			_ => None,
+		}
+	}
+}
+
+// Draw the overlay for the models, where the screen transform maps model space to the image.
+// Lines are the given width in pixels.
+pub fn draw(image: &mut image::Image, overlay: Overlay, models: &[&model::Model], screen: &vec::Transform4<f64>, width: f64) {
+	let mut lines = Lines {
+		image,
+		screen,
+	};
+	match overlay {
+		Overlay::Axes => {
+			let origin = &vec::Vec3::new(0f64, 0f64, 0f64);
+			let style = &image::LineStyle {
+				width: width * 2f64,
+				cap: image::LineCap::Round,
+				dashes: Vec::new(),
+			};
+			for i in 0 .. 3 {
+				let mut axis = vec::Vec3::new(0f64, 0f64, 0f64);
+				axis.0[i] = 1f64;
+				let mut color = [0f32; 3];
+				color[i] = 1f32;
+				let color = &image::ColorF::new(color[0], color[1], color[2], 1f32);
+				lines.draw(origin, &axis, color, style);
+			}
+		},
+		Overlay::Bounds => {
+			let identity = &vec::Transform4::default();
+			let style = &image::LineStyle {
+				width,
+				// so that the lines meet at the corners
+				cap: image::LineCap::Square,
+				dashes: vec![width * 6f64, width * 4f64],
+			};
+			let color = &image::ColorF::new(1f32, 1f32, 1f32, 1f32);
+			for model in models {
+				let (min, max) = match model.bounds(identity) {
+					Some(bounds) => bounds,
+					None => continue,
+				};
+				// each corner is selected by the bits of its index
+				let corner = |i: usize| vec::Vec3::new(
+					if i & 1 == 0 { min.0[0] } else { max.0[0] },
+					if i & 2 == 0 { min.0[1] } else { max.0[1] },
+					if i & 4 == 0 { min.0[2] } else { max.0[2] });
+				for i in 0 .. 8 {
+					for bit in &[1, 2, 4] {
+						if i & bit == 0 {
+							lines.draw(&corner(i), &corner(i | bit), color, style);
+						}
+					}
+				}
+			}
+		},
+		Overlay::Normals => {
+			let style = &image::LineStyle {
+				width,
+				..Default::default()
+			};
+			let color = &image::ColorF::new(1f32, 1f32, 0f32, 1f32);
+			for model in models {
+				for face in 0 .. model.nfaces() {
+					let mut center = vec::Vec3::new(0f64, 0f64, 0f64);
+					let mut normal = vec::Vec3::new(0f64, 0f64, 0f64);
+					for idx in 0 .. 3 {
+						center = center.add(model.vert(face, idx));
+						normal = normal.add(model.normal(face, idx));
+					}
+					let center = center.scale(1f64 / 3f64);
+					if normal.norm() == 0f64 {
+						continue;
+					}
+					let end = center.add(&normal.normalize().scale(NORMAL_LENGTH));
+					lines.draw(&center, &end, color, style);
+				}
+			}
+		},
+	}
+}
+
+// Draws lines between points in model space
+struct Lines<'a> {
+	image: &'a mut image::Image,
+	screen: &'a vec::Transform4<f64>,
+}
+
+impl<'a> Lines<'a> {
+	// Lines with an end behind the eye are not drawn
+	fn draw(&mut self, a: &vec::Vec3<f64>, b: &vec::Vec3<f64>, color: &image::ColorF, style: &image::LineStyle) {
+		let a = a.to_pt4().transform(self.screen);
+		let b = b.to_pt4().transform(self.screen);
+		if a.0[3] <= 0f64 || b.0[3] <= 0f64 {
+			return;
+		}
+		let (a, b) = (a.to_pt3(), b.to_pt3());
+		let a = &vec::Vec2::new(a.0[0], a.0[1]);
+		let b = &vec::Vec2::new(b.0[0], b.0[1]);
+		if style.width <= 1f64 && style.dashes.is_empty() {
+			self.image.line_aa(a, b, color);
+		} else {
+			self.image.stroke(a, b, color, style);
+		}
+	}
+}
@@ -0,0 +1,131 @@
+use environment;
+use image;
+use light;
+use vec;
+
+use std::f64;
+
+// Reflectance of dielectrics at normal incidence
+const DIELECTRIC_F0: f64 = 0.04f64;
+
+// Metallic-roughness material, as used by real-time engines and glTF.
+pub struct Material {
+	pub base_color: image::ColorF,
+	pub metallic: f64,
+	// perceptual roughness, which is squared to give the GGX alpha
+	pub roughness: f64,
+}
+
+impl Material {
+	// Cook-Torrance BRDF, multiplied by the cosine of the light angle.
+	// The normal n, direction to the viewer v, and direction to the light l must be normalized.
+	// The result multiplied by the light radiance gives the reflected radiance.
+	pub fn reflectance(&self, n: &vec::Vec3<f64>, v: &vec::Vec3<f64>, l: &vec::Vec3<f64>) -> image::ColorF {
+		let n_l = n.dot(l);
+		if n_l <= 0f64 {
+			return image::ColorF::new(0f32, 0f32, 0f32, self.base_color.a);
+		}
+		let n_v = n.dot(v).max(1e-4);
+		let h = v.add(l).normalize();
+		let n_h = n.dot(&h).max(0f64);
+		let h_v = h.dot(v).max(0f64);
+
+		let roughness = self.roughness.clamp(0.03f64, 1f64);
+		let metallic = self.metallic.clamp(0f64, 1f64);
+		let d = distribution_ggx(n_h, roughness * roughness);
+		let g = geometry_smith(n_v, n_l, roughness);
+
+		let channel = |base: f32| {
+			let base = base as f64;
+			let f0 = DIELECTRIC_F0 + (base - DIELECTRIC_F0) * metallic;
+			let f = fresnel_schlick(h_v, f0);
+			let specular = d * g * f / (4f64 * n_v * n_l);
+			let diffuse = (1f64 - f) * (1f64 - metallic) * base / f64::consts::PI;
+			((diffuse + specular) * n_l) as f32
+		};
+		let c = &self.base_color;
+		image::ColorF::new(channel(c.r), channel(c.g), channel(c.b), c.a)
+	}
+
+	// Reflected radiance from image based lighting, using the split sum approximation.
+	// The irradiance is for the normal, and the prefiltered radiance is for the reflection direction.
+	pub fn ambient(&self, n_v: f64, irradiance: &image::ColorF, prefiltered: &image::ColorF) -> image::ColorF {
+		let roughness = self.roughness.clamp(0.03f64, 1f64);
+		let metallic = self.metallic.clamp(0f64, 1f64);
+		let (scale, bias) = env_brdf(n_v.max(1e-4), roughness);
+		let channel = |base: f32, irradiance: f32, prefiltered: f32| {
+			let base = base as f64;
+			let f0 = DIELECTRIC_F0 + (base - DIELECTRIC_F0) * metallic;
+			let diffuse = (1f64 - metallic) * base / f64::consts::PI * irradiance as f64;
+			let specular = (f0 * scale + bias) * prefiltered as f64;
+			(diffuse + specular) as f32
+		};
+		let c = &self.base_color;
+		image::ColorF::new(
+			channel(c.r, irradiance.r, prefiltered.r),
+			channel(c.g, irradiance.g, prefiltered.g),
+			channel(c.b, irradiance.b, prefiltered.b),
+			c.a)
+	}
+
+	// Reflected radiance from the environment if there is one, otherwise from a constant ambient light
+	pub fn indirect(&self, environment: Option<&environment::Environment>, ambient: f32,
+			n: &vec::Vec3<f64>, v: &vec::Vec3<f64>) -> image::ColorF {
+		match environment {
+			Some(environment) => {
+				let n_v = n.dot(v);
+				let reflect = &n.scale(2f64 * n_v).sub(v);
+				let irradiance = environment.irradiance(n);
+				let prefiltered = environment.specular(reflect, self.roughness);
+				self.ambient(n_v, &irradiance, &prefiltered)
+			},
+			None => self.base_color.scale(ambient),
+		}
+	}
+
+	// Reflected radiance from the lights at the point p, with the first light scaled by the shadow
+	pub fn direct(&self, lights: &[light::Light], p: &vec::Vec3<f64>, n: &vec::Vec3<f64>, v: &vec::Vec3<f64>,
+		      shadow: f64) -> image::ColorF {
+		let mut radiance = image::ColorF::new(0f32, 0f32, 0f32, self.base_color.a);
+		for (i, light) in lights.iter().enumerate() {
+			let (l, light_radiance) = light.illuminate(p);
+			let shadow = if i == 0 { shadow } else { 1f64 };
+			let reflected = self.reflectance(n, v, &l).mul(&light_radiance);
+			radiance = radiance.add(&reflected.scale(shadow as f32));
+		}
+		radiance
+	}
+}
+
+// Scale and bias to F0 for the specular BRDF integrated over the hemisphere,
+// using the analytic fit from Karis, "Physically Based Shading on Mobile".
+fn env_brdf(n_v: f64, roughness: f64) -> (f64, f64) {
+	let c0 = [ -1f64, -0.0275f64, -0.572f64, 0.022f64 ];
+	let c1 = [ 1f64, 0.0425f64, 1.04f64, -0.04f64 ];
+	let r = [
+		roughness * c0[0] + c1[0],
+		roughness * c0[1] + c1[1],
+		roughness * c0[2] + c1[2],
+		roughness * c0[3] + c1[3],
+	];
+	let a004 = (r[0] * r[0]).min((-9.28f64 * n_v).exp2()) * r[0] + r[1];
+	(-1.04f64 * a004 + r[2], 1.04f64 * a004 + r[3])
+}
+
+// Trowbridge-Reitz normal distribution, for the GGX alpha which is the square of the roughness
+pub fn distribution_ggx(n_h: f64, alpha: f64) -> f64 {
+	let a2 = alpha * alpha;
+	let d = n_h * n_h * (a2 - 1f64) + 1f64;
+	a2 / (f64::consts::PI * d * d).max(1e-12)
+}
+
+// Smith masking-shadowing, with the Schlick-GGX approximation for direct lighting
+fn geometry_smith(n_v: f64, n_l: f64, roughness: f64) -> f64 {
+	let k = (roughness + 1f64).powi(2) / 8f64;
+	let g1 = |x: f64| { x / (x * (1f64 - k) + k) };
+	g1(n_v) * g1(n_l)
+}
+
+fn fresnel_schlick(cos: f64, f0: f64) -> f64 {
+	f0 + (1f64 - f0) * (1f64 - cos).powi(5)
+}
@@ -0,0 +1,261 @@
+use depth;
+use image;
+use model;
+use vec;
+
+use std::cmp;
+
+// Size and samples of a target, which don't depend on the color it accepts
+pub trait Surface {
+	fn get_width(&self) -> usize;
+	fn get_height(&self) -> usize;
+	// Positions of the samples relative to the pixel position
+	fn get_offsets(&self) -> &'static [(f64, f64)];
+}
+
+// Color targets which a pipeline draws to, accepting the output of the fragment shader
+pub trait Target<C>: Surface {
+	// Write a color to the samples of a pixel in the mask.
+	// The depth is normalized so that it is 0 at the near plane and 1 at the far plane.
+	fn write(&mut self, x: usize, y: usize, mask: u32, depth: f64, color: &C, blend: image::Blend);
+}
+
+// Rectangle of pixels, with the origin at the bottom left
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+	pub x: usize,
+	pub y: usize,
+	pub width: usize,
+	pub height: usize,
+}
+
+impl Rect {
+	pub fn intersect(&self, other: &Rect) -> Rect {
+		let x = cmp::max(self.x, other.x);
+		let y = cmp::max(self.y, other.y);
+		let right = cmp::min(self.x.saturating_add(self.width), other.x.saturating_add(other.width));
+		let top = cmp::min(self.y.saturating_add(self.height), other.y.saturating_add(other.height));
+		Rect {
+			x,
+			y,
+			width: right.saturating_sub(x),
+			height: top.saturating_sub(y),
+		}
+	}
+}
+
+// Which triangles are not drawn, by their orientation on the screen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cull {
+	None,
+	Back,
+	Front,
+}
+
+impl Cull {
+	pub fn from_name(name: &str) -> Option<Cull> {
+		match name {
+			"none" => Some(Cull::None),
+			"back" => Some(Cull::Back),
+			"front" => Some(Cull::Front),
+			_ => None,
+		}
+	}
+
+	pub fn culls(&self, front_facing: bool) -> bool {
+		match *self {
+			Cull::None => false,
+			Cull::Back => !front_facing,
+			Cull::Front => front_facing,
+		}
+	}
+}
+
+// How triangles are rasterized
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FillMode {
+	Fill,
+	// the edges of the triangles
+	Line,
+	// the vertices of the triangles
+	Point,
+}
+
+impl FillMode {
+	pub fn from_name(name: &str) -> Option<FillMode> {
+		match name {
+			"fill" => Some(FillMode::Fill),
+			"line" => Some(FillMode::Line),
+			"point" => Some(FillMode::Point),
+			_ => None,
+		}
+	}
+}
+
+// State for converting triangles to pixels
+#[derive(Clone, Copy, Debug)]
+pub struct Raster {
+	pub cull: Cull,
+	pub fill: FillMode,
+	// Added to the depth of fragments before the depth test, in the viewport depth range,
+	// so that positive values move them towards the eye
+	pub depth_bias: f64,
+	// additional bias per unit of depth slope of the triangle, in pixels
+	pub depth_slope_bias: f64,
+}
+
+impl Default for Raster {
+	fn default() -> Self {
+		Raster {
+			cull: Cull::None,
+			fill: FillMode::Fill,
+			depth_bias: 0f64,
+			depth_slope_bias: 0f64,
+		}
+	}
+}
+
+// The targets and fixed function state used for drawing.
+// The depth test, depth writes and stencil state are set on the depth buffer.
+pub struct Pipeline<'a, T: 'a> {
+	pub target: &'a mut T,
+	pub zbuffer: &'a mut depth::DepthBuffer,
+	pub viewport: &'a vec::Transform4<f64>,
+	// Only pixels inside this rectangle are drawn
+	pub scissor: Option<Rect>,
+	pub raster: Raster,
+	pub blend: image::Blend,
+	// Colors are only written if this is set, so that a pass can update just the depth and stencil
+	pub color_write: bool,
+	// Added to the face indices given to fragments, so that models drawn one after
+	// another can number their triangles in sequence
+	pub first_primitive: usize,
+}
+
+impl<'a, T: Surface + 'a> Pipeline<'a, T> {
+	// Draws all triangles filled, replacing the target color.
+	// The depth buffer must have a depth for each sample of the target.
+	pub fn new(target: &'a mut T, zbuffer: &'a mut depth::DepthBuffer, viewport: &'a vec::Transform4<f64>) -> Self {
+		assert_eq!(zbuffer.get_samples(), target.get_offsets().len(), "depth buffer samples must match the target");
+		Pipeline {
+			target,
+			zbuffer,
+			viewport,
+			scissor: None,
+			raster: Raster::default(),
+			blend: image::Blend::Replace,
+			color_write: true,
+			first_primitive: 0,
+		}
+	}
+
+	pub fn draw<S>(&mut self, model: &model::Model, shader: &S)
+			where S: image::Shader, T: Target<S::Output> {
+		for face in 0 .. model.nfaces() {
+			self.draw_face(model, shader, face);
+		}
+	}
+
+	pub fn draw_face<S>(&mut self, model: &model::Model, shader: &S, face: usize)
+			where S: image::Shader, T: Target<S::Output> {
+		let v0 = &shader.vertex(model.vert(face, 0), model.uv(face, 0), model.normal(face, 0));
+		let v1 = &shader.vertex(model.vert(face, 1), model.uv(face, 1), model.normal(face, 1));
+		let v2 = &shader.vertex(model.vert(face, 2), model.uv(face, 2), model.normal(face, 2));
+		let primitive = self.first_primitive + face;
+		self.draw_triangle(shader, primitive, v0, v1, v2);
+	}
+
+	// Draw a triangle with vertices which are already transformed by the shader.
+	// The primitive index is passed to the fragments.
+	pub fn draw_triangle<S>(&mut self, shader: &S, primitive: usize, v0: &image::Vertex<S::Varyings>,
+				v1: &image::Vertex<S::Varyings>, v2: &image::Vertex<S::Varyings>)
+			where S: image::Shader, T: Target<S::Output> {
+		let bounds = &self.bounds(self.target.get_width(), self.target.get_height());
+		let (raster, blend, viewport, color_write) = (&self.raster, self.blend, self.viewport, self.color_write);
+		// viewport depth scale and offset, to normalize the depth for the target
+		let (depth_scale, depth_offset) = (viewport.0[2][2], viewport.0[2][3]);
+		let target = &mut *self.target;
+		let zbuffer = &mut *self.zbuffer;
+		let offsets = target.get_offsets();
+		image::clip(v0, v1, v2, |v0, v1, v2, edges| {
+			image::rasterize(bounds, raster, viewport, offsets, &v0.0, &v1.0, &v2.0, edges, zbuffer, |pixel| {
+				let fragment = image::Fragment::new(v0, v1, v2, pixel, primitive);
+				match shader.fragment(&fragment) {
+					Some(color) => {
+						if color_write {
+							let depth = ((1f64 - (fragment.z - depth_offset) / depth_scale) / 2f64).clamp(0f64, 1f64);
+							target.write(pixel.x, pixel.y, pixel.mask, depth, &color, blend);
+						}
+						true
+					},
+					None => false,
+				}
+			});
+		});
+	}
+
+	// Pixels inside a target of the given size, the depth buffer and the scissor rectangle
+	fn bounds(&self, width: usize, height: usize) -> Rect {
+		let target = Rect {
+			x: 0,
+			y: 0,
+			width: cmp::min(width, self.zbuffer.get_width()),
+			height: cmp::min(height, self.zbuffer.get_height()),
+		};
+		match self.scissor {
+			Some(ref scissor) => target.intersect(scissor),
+			None => target,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct Red;
+
+	impl image::Shader for Red {
+		type Varyings = vec::Vec2<f64>;
+		type Output = image::ColorF;
+
+		fn vertex(&self, vert: &vec::Vec3<f64>, _uv: &vec::Vec3<f64>, _normal: &vec::Vec3<f64>) -> image::Vertex<vec::Vec2<f64>> {
+			(vert.to_pt4(), vec::Vec2::new(0f64, 0f64))
+		}
+
+		fn fragment(&self, _fragment: &image::Fragment<vec::Vec2<f64>>) -> Option<image::ColorF> {
+			Some(image::ColorF::new(1f32, 0f32, 0f32, 1f32))
+		}
+	}
+
+	// Draw a triangle covering the whole target, and return the color and whether the depth was written
+	fn draw(color_write: bool) -> (image::ColorF, bool) {
+		let mut target = image::Image::new(4, 4, image::Format::RgbaF32);
+		let mut zbuffer = depth::DepthBuffer::new(4, 4, 1, depth::Format::F32);
+		let viewport = vec::viewport(0f64, 0f64, 0f64, 4f64, 4f64, 2f64);
+		{
+			let mut pipeline = Pipeline::new(&mut target, &mut zbuffer, &viewport);
+			pipeline.color_write = color_write;
+			let vertex = |x: f64, y: f64| (vec::Vec4([x, y, 0f64, 1f64]), vec::Vec2::new(0f64, 0f64));
+			pipeline.draw_triangle(&Red, 0, &vertex(-1f64, -1f64), &vertex(3f64, -1f64), &vertex(-1f64, 3f64));
+		}
+		(target.get_f(1, 1), !zbuffer.is_clear(1, 1, 0))
+	}
+
+	#[test]
+	fn color_write() {
+		let (color, depth) = draw(true);
+		assert_eq!((color.r, depth), (1f32, true));
+		let (color, depth) = draw(false);
+		assert_eq!((color.r, depth), (0f32, true));
+	}
+
+	#[test]
+	#[should_panic(expected = "depth buffer samples must match the target")]
+	fn sample_mismatch() {
+		let mut target = image::Multisample::new(4, 4, 4).unwrap();
+		let mut zbuffer = depth::DepthBuffer::new(4, 4, 1, depth::Format::F32);
+		let viewport = vec::viewport(0f64, 0f64, 0f64, 4f64, 4f64, 2f64);
+		Pipeline::new(&mut target, &mut zbuffer, &viewport);
+	}
+}
@@ -0,0 +1,222 @@
+use image;
+
+use std::f64;
+
+// Filter for reducing a supersampled image to the output size
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Downsample {
+	Box,
+	Lanczos,
+}
+
+impl Downsample {
+	pub fn from_name(name: &str) -> Option<Downsample> {
+		match name {
+			"box" => Some(Downsample::Box),
+			"lanczos" => Some(Downsample::Lanczos),
+			_ => None,
+		}
+	}
+}
+
+// Lobes of the Lanczos window
+const LANCZOS_A: f64 = 3f64;
+
+// Reduce the size of the image by an integer factor, filtering linear colors.
+pub fn downsample(src: &image::Image, factor: usize, filter: Downsample) -> image::Image {
+	let width = src.get_width() / factor;
+	let height = src.get_height() / factor;
+	// Filter weights for each destination pixel, which are the same for x and y
+	let weights = |dst: usize, size: usize| -> Vec<(usize, f64)> {
+		// pixels are at integer positions, so the destination pixel is at the center of its source pixels
+		let center = (dst * factor) as f64 + (factor - 1) as f64 / 2f64;
+		let (start, end) = match filter {
+			Downsample::Box => (dst * factor, dst * factor + factor),
+			Downsample::Lanczos => {
+				let radius = LANCZOS_A * factor as f64;
+				((center - radius).ceil().max(0f64) as usize, ((center + radius).floor() as usize + 1).min(size))
+			},
+		};
+		let mut weights: Vec<(usize, f64)> = (start .. end).map(|i| {
+			let w = match filter {
+				Downsample::Box => 1f64,
+				Downsample::Lanczos => lanczos((i as f64 - center) / factor as f64),
+			};
+			(i, w)
+		}).collect();
+		let sum: f64 = weights.iter().map(|&(_, w)| w).sum();
+		for weight in &mut weights {
+			weight.1 /= sum;
+		}
+		weights
+	};
+
+	// Filter horizontally into floats, then vertically into the destination
+	let src_height = src.get_height();
+	let mut rows = vec![image::ColorF::default(); width * src_height];
+	for x in 0 .. width {
+		let weights = weights(x, src.get_width());
+		for y in 0 .. src_height {
+			rows[x + y * width] = weighted_sum(weights.iter().map(|&(i, w)| (src.get_f(i, y), w)));
+		}
+	}
+	let mut dst = image::Image::new(width, height, src.get_format());
+	dst.set_color_space(src.get_color_space());
+	for y in 0 .. height {
+		let weights = weights(y, src_height);
+		for x in 0 .. width {
+			let c = weighted_sum(weights.iter().map(|&(i, w)| (rows[x + i * width], w)));
+			// the negative lobes can overshoot
+			dst.set_f(x, y, &image::ColorF::new(c.r.max(0f32), c.g.max(0f32), c.b.max(0f32), c.a.clamp(0f32, 1f32)));
+		}
+	}
+	dst
+}
+
+fn lanczos(x: f64) -> f64 {
+	if x == 0f64 {
+		1f64
+	} else if x.abs() >= LANCZOS_A {
+		0f64
+	} else {
+		let px = f64::consts::PI * x;
+		LANCZOS_A * px.sin() * (px / LANCZOS_A).sin() / (px * px)
+	}
+}
+
+fn weighted_sum<I: Iterator<Item=(image::ColorF, f64)>>(colors: I) -> image::ColorF {
+	let mut sum = [0f64; 4];
+	for (c, w) in colors {
+		sum[0] += c.r as f64 * w;
+		sum[1] += c.g as f64 * w;
+		sum[2] += c.b as f64 * w;
+		sum[3] += c.a as f64 * w;
+	}
+	image::ColorF::new(sum[0] as f32, sum[1] as f32, sum[2] as f32, sum[3] as f32)
+}
+
+// FXAA tuning, as in the FXAA 3.11 quality preset
+const EDGE_THRESHOLD: f32 = 0.125f32;
+const EDGE_THRESHOLD_MIN: f32 = 0.0312f32;
+const SUBPIXEL_QUALITY: f32 = 0.75f32;
+const SEARCH_STEPS: [f32; 12] = [ 1f32, 1f32, 1f32, 1f32, 1f32, 1.5f32, 2f32, 2f32, 2f32, 2f32, 4f32, 8f32 ];
+
+// Fast approximate anti-aliasing, which blends across edges found from the luma of the image.
+pub fn fxaa(src: &image::Image) -> image::Image {
+	let width = src.get_width() as i64;
+	let height = src.get_height() as i64;
+	let get = |x: i64, y: i64| {
+		src.get_f(x.clamp(0, width - 1) as usize, y.clamp(0, height - 1) as usize)
+	};
+	// Bilinear sample, with pixels at integer positions
+	let sample = |x: f32, y: f32| {
+		let (x0, y0) = (x.floor(), y.floor());
+		let (fx, fy) = (x - x0, y - y0);
+		let (x0, y0) = (x0 as i64, y0 as i64);
+		let lerp = |c0: image::ColorF, c1: image::ColorF, t: f32| c0.scale(1f32 - t).add(&c1.scale(t));
+		lerp(lerp(get(x0, y0), get(x0 + 1, y0), fx), lerp(get(x0, y0 + 1), get(x0 + 1, y0 + 1), fx), fy)
+	};
+	let luma_at = |x: f32, y: f32| luma(&sample(x, y));
+	let lumas: Vec<f32> = (0 .. width * height).map(|i| luma(&get(i % width, i / width))).collect();
+	let l = |x: i64, y: i64| lumas[(x.clamp(0, width - 1) + y.clamp(0, height - 1) * width) as usize];
+
+	let mut dst = image::Image::new(src.get_width(), src.get_height(), src.get_format());
+	dst.set_color_space(src.get_color_space());
+	for y in 0 .. height {
+		for x in 0 .. width {
+			let m = l(x, y);
+			let (n, s, e, w) = (l(x, y + 1), l(x, y - 1), l(x + 1, y), l(x - 1, y));
+			let max = m.max(n).max(s).max(e).max(w);
+			let min = m.min(n).min(s).min(e).min(w);
+			let range = max - min;
+			if range < EDGE_THRESHOLD_MIN.max(max * EDGE_THRESHOLD) {
+				dst.set_f(x as usize, y as usize, &get(x, y));
+				continue;
+			}
+			let (ne, nw, se, sw) = (l(x + 1, y + 1), l(x - 1, y + 1), l(x + 1, y - 1), l(x - 1, y - 1));
+
+			// Is the edge horizontal or vertical?
+			let horizontal = (sw + se - 2f32 * s).abs() + 2f32 * (w + e - 2f32 * m).abs() + (nw + ne - 2f32 * n).abs();
+			let vertical = (sw + nw - 2f32 * w).abs() + 2f32 * (s + n - 2f32 * m).abs() + (se + ne - 2f32 * e).abs();
+			let is_horizontal = horizontal >= vertical;
+
+			// Which side of the pixel is the edge on?
+			let (l1, l2) = if is_horizontal { (s, n) } else { (w, e) };
+			let gradient1 = l1 - m;
+			let gradient2 = l2 - m;
+			let steepest1 = gradient1.abs() >= gradient2.abs();
+			let gradient_scaled = 0.25f32 * gradient1.abs().max(gradient2.abs());
+			let (step, local_average) = if steepest1 {
+				(-1f32, 0.5f32 * (l1 + m))
+			} else {
+				(1f32, 0.5f32 * (l2 + m))
+			};
+
+			// Search along the edge, half a pixel towards the edge, for its ends
+			let (mut ux, mut uy) = (x as f32, y as f32);
+			let (dx, dy) = if is_horizontal {
+				uy += step * 0.5f32;
+				(1f32, 0f32)
+			} else {
+				ux += step * 0.5f32;
+				(0f32, 1f32)
+			};
+			let (mut x1, mut y1) = (ux - dx, uy - dy);
+			let (mut x2, mut y2) = (ux + dx, uy + dy);
+			let mut end1 = luma_at(x1, y1) - local_average;
+			let mut end2 = luma_at(x2, y2) - local_average;
+			let mut done1 = end1.abs() >= gradient_scaled;
+			let mut done2 = end2.abs() >= gradient_scaled;
+			for &quality in &SEARCH_STEPS[1 ..] {
+				if done1 && done2 {
+					break;
+				}
+				if !done1 {
+					x1 -= dx * quality;
+					y1 -= dy * quality;
+					end1 = luma_at(x1, y1) - local_average;
+					done1 = end1.abs() >= gradient_scaled;
+				}
+				if !done2 {
+					x2 += dx * quality;
+					y2 += dy * quality;
+					end2 = luma_at(x2, y2) - local_average;
+					done2 = end2.abs() >= gradient_scaled;
+				}
+			}
+
+			// Offset towards the edge, if the nearest end is on the correct side
+			let distance1 = if is_horizontal { x as f32 - x1 } else { y as f32 - y1 };
+			let distance2 = if is_horizontal { x2 - x as f32 } else { y2 - y as f32 };
+			let (distance, end) = if distance1 < distance2 { (distance1, end1) } else { (distance2, end2) };
+			let edge_length = distance1 + distance2;
+			let center_smaller = m < local_average;
+			let edge_offset = if (end < 0f32) != center_smaller {
+				0.5f32 - distance / edge_length
+			} else {
+				0f32
+			};
+
+			// Subpixel offset for single pixel features
+			let average = (2f32 * (n + s + e + w) + ne + nw + se + sw) / 12f32;
+			let subpixel = ((average - m).abs() / range).clamp(0f32, 1f32);
+			let subpixel = (-2f32 * subpixel + 3f32) * subpixel * subpixel;
+			let subpixel = subpixel * subpixel * SUBPIXEL_QUALITY;
+
+			let offset = edge_offset.max(subpixel) * step;
+			let c = if is_horizontal {
+				sample(x as f32, y as f32 + offset)
+			} else {
+				sample(x as f32 + offset, y as f32)
+			};
+			dst.set_f(x as usize, y as usize, &c);
+		}
+	}
+	dst
+}
+
+// Perceptual luma, from linear colors
+fn luma(c: &image::ColorF) -> f32 {
+	let y = 0.299f32 * c.r + 0.587f32 * c.g + 0.114f32 * c.b;
+	image::linear_to_srgb(y.clamp(0f32, 1f32))
+}
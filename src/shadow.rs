@@ -0,0 +1,200 @@
+use depth;
+use image;
+use model;
+use pipeline;
+use texture;
+use vec;
+
+// Depth of the scene as seen from a directional light, using an orthographic
+// projection fitted to the bounds of the scene.
+pub struct ShadowMap {
+	// transform from world space to light clip space
+	pub transform: vec::Transform4<f64>,
+	viewport: vec::Transform4<f64>,
+	size: usize,
+	zbuffer: depth::DepthBuffer,
+
+	// depth bias, in the depth range [0, 1] of the shadow map
+	pub bias: f64,
+	// additional bias per unit of depth slope, in texels
+	pub slope_bias: f64,
+	// Limit of the total bias, for triangles which are nearly parallel to the light
+	pub max_bias: f64,
+	// width of the percentage closer filtering kernel, in texels, which must be odd
+	pub pcf: usize,
+}
+
+impl ShadowMap {
+	// Each model has an optional diffuse texture and threshold for alpha testing.
+	pub fn new(size: usize, light: &vec::Vec3<f64>, models: &[(&model::Model, Option<(&texture::Texture, f32)>)]) -> Self {
+		let center = &vec::Vec3::new(0f64, 0f64, 0f64);
+		let up = if light.0[0].abs() < 1e-6 && light.0[2].abs() < 1e-6 {
+			vec::Vec3::new(0f64, 0f64, 1f64)
+		} else {
+			vec::Vec3::new(0f64, 1f64, 0f64)
+		};
+		let view = vec::lookat(light, center, &up);
+
+		let mut min = vec::Vec3::new(-1f64, -1f64, -1f64);
+		let mut max = vec::Vec3::new(1f64, 1f64, 1f64);
+		let mut first = true;
+		for &(model, _) in models {
+			if let Some((model_min, model_max)) = model.bounds(&view) {
+				for i in 0..3 {
+					if first || model_min.0[i] < min.0[i] {
+						min.0[i] = model_min.0[i];
+					}
+					if first || model_max.0[i] > max.0[i] {
+						max.0[i] = model_max.0[i];
+					}
+				}
+				first = false;
+			}
+		}
+		// pad so that geometry on the bounds is not clipped
+		for i in 0..3 {
+			let pad = (max.0[i] - min.0[i]).max(1e-6) * 0.01f64;
+			min.0[i] -= pad;
+			max.0[i] += pad;
+		}
+
+		let mut shadow = ShadowMap {
+			transform: vec::orthographic(&min, &max).mul(&view),
+			viewport: vec::viewport(0f64, 0f64, 0f64, size as f64, size as f64, 1f64),
+			size,
+			zbuffer: depth::DepthBuffer::new(size, size, 1, depth::Format::F32),
+			bias: 0.002f64,
+			slope_bias: 1.5f64,
+			max_bias: 0.01f64,
+			pcf: 3,
+		};
+		for &(model, alpha_test) in models {
+			let mut shader = image::DepthShader::new(&shadow.transform);
+			shader.alpha_test = alpha_test;
+			pipeline::Pipeline::new(&mut image::DepthOnly, &mut shadow.zbuffer, &shadow.viewport).draw(model, &shader);
+		}
+		shadow
+	}
+
+	// Fraction of the light which reaches a point.
+	// The point and the vertices of its triangle are in light clip space.
+	pub fn visibility(&self, p: &vec::Vec3<f64>, triangle: &vec::Mat3<f64>) -> f64 {
+		let p = p.transform_pt(&self.viewport);
+
+		// depth slope of the triangle per texel
+		let t0 = vec::Vec3(triangle.0[0]).transform_pt(&self.viewport);
+		let t1 = vec::Vec3(triangle.0[1]).transform_pt(&self.viewport);
+		let t2 = vec::Vec3(triangle.0[2]).transform_pt(&self.viewport);
+		let n = t1.sub(&t0).cross(&t2.sub(&t0));
+		let slope = if n.0[2].abs() < 1e-12 {
+			f64::INFINITY
+		} else {
+			(n.0[0] / n.0[2]).abs().max((n.0[1] / n.0[2]).abs())
+		};
+		let z = p.0[2] + (self.bias + self.slope_bias * slope).min(self.max_bias);
+
+		// the kernel is centered on the texel containing the point, which is rasterized at integer coordinates
+		let r = (self.pcf / 2) as i64;
+		let x0 = p.0[0].round() as i64;
+		let y0 = p.0[1].round() as i64;
+		let size = self.size as i64;
+		let mut lit = 0;
+		let mut count = 0;
+		for y in y0 - r .. y0 + r + 1 {
+			for x in x0 - r .. x0 + r + 1 {
+				count += 1;
+				if x < 0 || y < 0 || x >= size || y >= size || self.zbuffer.get(x as usize, y as usize, 0) <= z {
+					lit += 1;
+				}
+			}
+		}
+		lit as f64 / count as f64
+	}
+
+	pub fn to_image(&self) -> image::Image {
+		let mut image = image::Image::new(self.size, self.size, image::Format::Gray);
+		image.set_color_space(image::ColorSpace::Linear);
+		for y in 0 .. self.size {
+			for x in 0 .. self.size {
+				let z = self.zbuffer.get(x, y, 0).max(0f64) as f32;
+				image.set_f(x, y, &image::ColorF::new(z, z, z, 1f32));
+			}
+		}
+		image
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{ env, fs, process };
+
+	// A square occluder at y = 1 over a larger square receiver at y = 0
+	fn scene() -> model::Model {
+		let path = env::temp_dir().join(format!("tinyrenderer-{}-shadow.obj", process::id()));
+		fs::write(&path, "\
+v -0.5 1 -0.5\nv 0.5 1 -0.5\nv 0.5 1 0.5\nv -0.5 1 0.5
+v -2 0 -2\nv 2 0 -2\nv 2 0 2\nv -2 0 2
+vt 0 0\nvn 0 1 0
+f 1/1/1 3/1/1 2/1/1\nf 1/1/1 4/1/1 3/1/1
+f 5/1/1 7/1/1 6/1/1\nf 5/1/1 8/1/1 7/1/1
+").unwrap();
+		let model = model::Model::read(&path);
+		fs::remove_file(&path).unwrap();
+		model.unwrap()
+	}
+
+	// The first receiver triangle, in light clip space
+	fn receiver(shadow: &ShadowMap) -> vec::Mat3<f64> {
+		let mut triangle = vec::Mat3::default();
+		for (i, v) in [(-2f64, -2f64), (2f64, 2f64), (2f64, -2f64)].iter().enumerate() {
+			triangle.set_row(i, &vec::Vec3::new(v.0, 0f64, v.1).transform_pt(&shadow.transform));
+		}
+		triangle
+	}
+
+	fn visibility(shadow: &ShadowMap, x: f64, z: f64) -> f64 {
+		let p = vec::Vec3::new(x, 0f64, z).transform_pt(&shadow.transform);
+		shadow.visibility(&p, &receiver(shadow))
+	}
+
+	#[test]
+	fn lit_and_shadowed() {
+		let model = scene();
+		let mut shadow = ShadowMap::new(64, &vec::Vec3::new(0f64, 1f64, 0f64), &[(&model, None)]);
+		shadow.pcf = 1;
+		assert_eq!(visibility(&shadow, 1.5f64, 1.5f64), 1f64);
+		assert_eq!(visibility(&shadow, 0f64, 0f64), 0f64);
+		assert_eq!(visibility(&shadow, 0.3f64, -0.2f64), 0f64);
+	}
+
+	#[test]
+	fn pcf_edge() {
+		let model = scene();
+		let shadow = ShadowMap::new(64, &vec::Vec3::new(0f64, 1f64, 0f64), &[(&model, None)]);
+		assert_eq!(shadow.pcf, 3);
+		// visibility increases across the edge of the occluder, with partial values in between
+		let values: Vec<f64> = (0 .. 100).map(|i| visibility(&shadow, 0.25f64 + i as f64 * 0.005f64, 0f64)).collect();
+		assert_eq!((values[0], values[99]), (0f64, 1f64));
+		assert!(values.windows(2).all(|w| w[0] <= w[1]));
+		assert!(values.iter().any(|&v| v > 0f64 && v < 1f64));
+	}
+
+	#[test]
+	fn nearest_texel() {
+		let model = scene();
+		let mut shadow = ShadowMap::new(64, &vec::Vec3::new(0f64, 1f64, 0f64), &[(&model, None)]);
+		shadow.pcf = 1;
+		// the first texel of the row through the center which sees the receiver
+		let y = 32;
+		let occluder = shadow.zbuffer.get(32, y, 0);
+		let x = (32 .. 64).find(|&x| shadow.zbuffer.get(x, y, 0) < occluder).unwrap();
+		let receiver_depth = shadow.zbuffer.get(x, y, 0);
+		let inverse = shadow.viewport.inverse();
+		let at = |x: f64| vec::Vec3::new(x, y as f64, receiver_depth).transform_pt(&inverse);
+		let triangle = receiver(&shadow);
+		// points are in the texel whose center is closest
+		assert_eq!(shadow.visibility(&at(x as f64 - 0.4f64), &triangle), 1f64);
+		assert_eq!(shadow.visibility(&at(x as f64 - 0.6f64), &triangle), 0f64);
+	}
+}
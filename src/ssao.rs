@@ -0,0 +1,208 @@
+use depth;
+use image;
+use vec;
+
+// Size of the tiled random rotations, which the blur should cover
+const NOISE_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+	// number of samples in the hemisphere
+	pub kernel: usize,
+	// radius of the hemisphere, in world units
+	pub radius: f64,
+	// width of the box blur, in pixels
+	pub blur: usize,
+}
+
+impl Default for Settings {
+	fn default() -> Self {
+		Settings {
+			kernel: 16,
+			radius: 0.2f64,
+			blur: NOISE_SIZE,
+		}
+	}
+}
+
+// Screen space ambient occlusion computed from a zbuffer.
+// Normals are reconstructed from the depth of neighbouring pixels.
+pub struct AmbientOcclusion {
+	width: usize,
+	height: usize,
+	// fraction of ambient light reaching each pixel
+	ao: Vec<f32>,
+}
+
+impl AmbientOcclusion {
+	// The screen transform maps world space to the zbuffer, and the eye is in world space.
+	pub fn new(settings: &Settings, zbuffer: &depth::DepthBuffer,
+		   screen: &vec::Transform4<f64>, eye: &vec::Vec3<f64>) -> Self {
+		let (width, height) = (zbuffer.get_width(), zbuffer.get_height());
+		let inverse = screen.inverse();
+		let positions: Vec<Option<vec::Vec3<f64>>> = (0 .. width * height).map(|i| {
+			let (x, y) = (i % width, i / width);
+			if zbuffer.is_clear(x, y, 0) {
+				return None;
+			}
+			Some(vec::Vec3::new(x as f64, y as f64, zbuffer.get(x, y, 0)).transform_pt(&inverse))
+		}).collect();
+		let position = |x: i64, y: i64| {
+			if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+				None
+			} else {
+				positions[x as usize + y as usize * width].as_ref()
+			}
+		};
+
+		let mut random = Random(0x2545f491);
+		let kernel: Vec<vec::Vec3<f64>> = (0 .. settings.kernel).map(|i| {
+			let mut v = vec::Vec3::new(random.next() * 2f64 - 1f64, random.next() * 2f64 - 1f64, random.next());
+			let v = v.normalize().scale(random.next());
+			// cluster samples near the center
+			let t = i as f64 / settings.kernel as f64;
+			v.scale(0.1f64 + 0.9f64 * t * t)
+		}).collect();
+		let noise: Vec<vec::Vec3<f64>> = (0 .. NOISE_SIZE * NOISE_SIZE).map(|_| {
+			vec::Vec3::new(random.next() * 2f64 - 1f64, random.next() * 2f64 - 1f64, 0f64)
+		}).collect();
+
+		let bias = settings.radius * 0.05f64;
+		let mut ao = vec![1f32; width * height];
+		for y in 0 .. height as i64 {
+			for x in 0 .. width as i64 {
+				let p = match position(x, y) {
+					Some(p) => p,
+					None => continue,
+				};
+
+				// Normal from the neighbours with the smallest depth difference, to avoid edges
+				let neighbour = |a: Option<&vec::Vec3<f64>>, b: Option<&vec::Vec3<f64>>| {
+					let da = a.map(|a| a.sub(p));
+					let db = b.map(|b| p.sub(b));
+					match (da, db) {
+						(Some(da), Some(db)) => Some(if da.norm() < db.norm() { da } else { db }),
+						(da, db) => da.or(db),
+					}
+				};
+				let n = match (neighbour(position(x + 1, y), position(x - 1, y)),
+					       neighbour(position(x, y + 1), position(x, y - 1))) {
+					(Some(dx), Some(dy)) => dx.cross(&dy),
+					_ => continue,
+				};
+				if n.norm() == 0f64 {
+					continue;
+				}
+				let view = &eye.sub(p);
+				let n = &if n.dot(view) < 0f64 { n.scale(-1f64) } else { n }.normalize();
+
+				// Basis for the hemisphere around the normal, randomly rotated
+				let r = &noise[(x as usize % NOISE_SIZE) + (y as usize % NOISE_SIZE) * NOISE_SIZE];
+				let t = &r.sub(&n.scale(r.dot(n)));
+				let t = &if t.norm() < 1e-6 { any_perpendicular(n) } else { vec::Vec3(t.0) }.normalize();
+				let b = &n.cross(t);
+
+				let mut occlusion = 0f64;
+				for k in &kernel {
+					let (kx, ky, kz) = k.as_tuple();
+					let s = p.add(&t.scale(kx * settings.radius))
+						.add(&b.scale(ky * settings.radius))
+						.add(&n.scale(kz * settings.radius));
+					let screen_s = s.transform_pt(screen);
+					let q = match position(screen_s.0[0].round() as i64, screen_s.0[1].round() as i64) {
+						Some(q) => q,
+						None => continue,
+					};
+					// occluded if the visible surface is in front of the sample
+					if q.sub(eye).norm() < s.sub(eye).norm() - bias {
+						let distance = q.sub(p).norm();
+						let range = (settings.radius / distance.max(1e-12)).min(1f64);
+						occlusion += range;
+					}
+				}
+				if !kernel.is_empty() {
+					ao[x as usize + y as usize * width] = (1f64 - occlusion / kernel.len() as f64) as f32;
+				}
+			}
+		}
+
+		let ao = blur(&ao, &positions, width, height, settings.blur);
+		AmbientOcclusion {
+			width,
+			height,
+			ao,
+		}
+	}
+
+	// Fraction of the ambient light reaching the pixel
+	pub fn get(&self, x: usize, y: usize) -> f32 {
+		if x < self.width && y < self.height {
+			self.ao[x + y * self.width]
+		} else {
+			1f32
+		}
+	}
+
+	pub fn to_image(&self) -> image::Image {
+		let mut image = image::Image::new(self.width, self.height, image::Format::Gray);
+		image.set_color_space(image::ColorSpace::Linear);
+		for y in 0 .. self.height {
+			for x in 0 .. self.width {
+				let ao = self.get(x, y);
+				image.set_f(x, y, &image::ColorF::new(ao, ao, ao, 1f32));
+			}
+		}
+		image
+	}
+}
+
+// Box blur which ignores pixels without geometry
+fn blur(ao: &[f32], positions: &[Option<vec::Vec3<f64>>], width: usize, height: usize, size: usize) -> Vec<f32> {
+	if size <= 1 {
+		return ao.to_vec();
+	}
+	let r0 = (size / 2) as i64;
+	let r1 = size as i64 - r0;
+	let mut result = ao.to_vec();
+	for y in 0 .. height as i64 {
+		for x in 0 .. width as i64 {
+			if positions[x as usize + y as usize * width].is_none() {
+				continue;
+			}
+			let mut sum = 0f32;
+			let mut count = 0;
+			for sy in (y - r0).max(0) .. (y + r1).min(height as i64) {
+				for sx in (x - r0).max(0) .. (x + r1).min(width as i64) {
+					let i = sx as usize + sy as usize * width;
+					if positions[i].is_some() {
+						sum += ao[i];
+						count += 1;
+					}
+				}
+			}
+			result[x as usize + y as usize * width] = sum / count as f32;
+		}
+	}
+	result
+}
+
+fn any_perpendicular(n: &vec::Vec3<f64>) -> vec::Vec3<f64> {
+	if n.0[0].abs() < 0.9f64 {
+		n.cross(&vec::Vec3::new(1f64, 0f64, 0f64))
+	} else {
+		n.cross(&vec::Vec3::new(0f64, 1f64, 0f64))
+	}
+}
+
+// Deterministic xorshift generator, so that renders are repeatable
+struct Random(u32);
+
+impl Random {
+	// Returns a value in [0, 1)
+	fn next(&mut self) -> f64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 17;
+		self.0 ^= self.0 << 5;
+		self.0 as f64 / 4294967296f64
+	}
+}
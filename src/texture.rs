@@ -0,0 +1,317 @@
+use image;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+	Nearest,
+	Bilinear,
+	Trilinear,
+}
+
+impl Filter {
+	pub fn from_name(name: &str) -> Option<Filter> {
+		match name {
+			"nearest" => Some(Filter::Nearest),
+			"bilinear" => Some(Filter::Bilinear),
+			"trilinear" => Some(Filter::Trilinear),
+			_ => None,
+		}
+	}
+}
+
+// How texel coordinates outside of the texture are addressed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wrap {
+	Repeat,
+	MirroredRepeat,
+	ClampToEdge,
+	ClampToBorder,
+}
+
+impl Wrap {
+	pub fn from_name(name: &str) -> Option<Wrap> {
+		match name {
+			"repeat" => Some(Wrap::Repeat),
+			"mirrored-repeat" => Some(Wrap::MirroredRepeat),
+			"clamp-to-edge" => Some(Wrap::ClampToEdge),
+			"clamp-to-border" => Some(Wrap::ClampToBorder),
+			_ => None,
+		}
+	}
+
+	// Returns None if the border should be used
+	fn apply(&self, x: i64, size: i64) -> Option<i64> {
+		match *self {
+			Wrap::Repeat => Some(x.rem_euclid(size)),
+			Wrap::MirroredRepeat => {
+				let x = x.rem_euclid(size * 2);
+				Some(if x < size { x } else { size * 2 - 1 - x })
+			},
+			Wrap::ClampToEdge => Some(x.max(0).min(size - 1)),
+			Wrap::ClampToBorder => if x >= 0 && x < size { Some(x) } else { None },
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sampler {
+	pub filter: Filter,
+	pub wrap_u: Wrap,
+	pub wrap_v: Wrap,
+	pub border: image::ColorF,
+}
+
+impl Default for Sampler {
+	fn default() -> Self {
+		Sampler {
+			filter: Filter::Trilinear,
+			wrap_u: Wrap::Repeat,
+			wrap_v: Wrap::Repeat,
+			border: image::ColorF::new(0f32, 0f32, 0f32, 0f32),
+		}
+	}
+}
+
+// An image and its mipmap chain.
+// Level 0 is the original image, and each level halves the size of the previous level.
+pub struct Texture {
+	levels: Vec<image::Image>,
+	pub sampler: Sampler,
+}
+
+impl Default for Texture {
+	fn default() -> Self {
+		Texture::new(image::Image::default())
+	}
+}
+
+impl Texture {
+	pub fn new(image: image::Image) -> Self {
+		let mut levels = vec![image];
+		loop {
+			let next = {
+				let prev = levels.last().unwrap();
+				// an empty image has no levels to filter
+				if prev.get_width() == 0 || prev.get_height() == 0
+						|| (prev.get_width() == 1 && prev.get_height() == 1) {
+					break;
+				}
+				downsample(prev)
+			};
+			levels.push(next);
+		}
+		Texture {
+			levels,
+			sampler: Sampler::default(),
+		}
+	}
+
+	pub fn get_width(&self) -> usize {
+		self.levels[0].get_width()
+	}
+
+	pub fn get_height(&self) -> usize {
+		self.levels[0].get_height()
+	}
+
+	// Level of detail for the given derivatives of the texture coordinates
+	// with respect to screen x and y.
+	pub fn lod(&self, du_dx: f64, dv_dx: f64, du_dy: f64, dv_dy: f64) -> f64 {
+		let width = self.get_width() as f64;
+		let height = self.get_height() as f64;
+		let rho_x = ((du_dx * width).powi(2) + (dv_dx * height).powi(2)).sqrt();
+		let rho_y = ((du_dy * width).powi(2) + (dv_dy * height).powi(2)).sqrt();
+		rho_x.max(rho_y).log2()
+	}
+
+	// Returns a linear color for the texture coordinates u and v,
+	// where [0, 1] covers the whole texture.
+	pub fn sample(&self, u: f64, v: f64, lod: f64) -> image::ColorF {
+		match self.sampler.filter {
+			Filter::Nearest => self.nearest(0, u, v),
+			Filter::Bilinear => self.bilinear(0, u, v),
+			Filter::Trilinear => {
+				let max_level = (self.levels.len() - 1) as f64;
+				let lod = if lod.is_nan() { 0f64 } else { lod.max(0f64).min(max_level) };
+				let level = lod.floor();
+				let c0 = self.bilinear(level as usize, u, v);
+				if level == lod {
+					return c0;
+				}
+				let c1 = self.bilinear(level as usize + 1, u, v);
+				lerp(&c0, &c1, (lod - level) as f32)
+			},
+		}
+	}
+
+	fn texel(&self, level: usize, x: i64, y: i64) -> image::ColorF {
+		let image = &self.levels[level];
+		let width = image.get_width() as i64;
+		let height = image.get_height() as i64;
+		if width == 0 || height == 0 {
+			return self.sampler.border;
+		}
+		match (self.sampler.wrap_u.apply(x, width), self.sampler.wrap_v.apply(y, height)) {
+			(Some(x), Some(y)) => image.get_f(x as usize, y as usize),
+			_ => self.sampler.border,
+		}
+	}
+
+	fn nearest(&self, level: usize, u: f64, v: f64) -> image::ColorF {
+		let image = &self.levels[level];
+		let x = (u * image.get_width() as f64).floor() as i64;
+		let y = (v * image.get_height() as f64).floor() as i64;
+		self.texel(level, x, y)
+	}
+
+	fn bilinear(&self, level: usize, u: f64, v: f64) -> image::ColorF {
+		let image = &self.levels[level];
+		// texel centers are at half integer coordinates
+		let x = u * image.get_width() as f64 - 0.5f64;
+		let y = v * image.get_height() as f64 - 0.5f64;
+		let x0 = x.floor();
+		let y0 = y.floor();
+		let fx = (x - x0) as f32;
+		let fy = (y - y0) as f32;
+		let x0 = x0 as i64;
+		let y0 = y0 as i64;
+		let c0 = lerp(&self.texel(level, x0, y0), &self.texel(level, x0 + 1, y0), fx);
+		let c1 = lerp(&self.texel(level, x0, y0 + 1), &self.texel(level, x0 + 1, y0 + 1), fx);
+		lerp(&c0, &c1, fy)
+	}
+}
+
+fn lerp(c0: &image::ColorF, c1: &image::ColorF, t: f32) -> image::ColorF {
+	image::ColorF::new(
+		c0.r + (c1.r - c0.r) * t,
+		c0.g + (c1.g - c0.g) * t,
+		c0.b + (c1.b - c0.b) * t,
+		c0.a + (c1.a - c0.a) * t)
+}
+
+// Box filter to half the size, averaging linear colors.
+// Each destination texel covers the same area of an odd sized source, so that
+// the last row and column contribute.
+fn downsample(src: &image::Image) -> image::Image {
+	let src_width = src.get_width();
+	let src_height = src.get_height();
+	let width = (src_width / 2).max(1);
+	let height = (src_height / 2).max(1);
+	let mut dst = image::Image::new(width, height, src.get_format());
+	dst.set_color_space(src.get_color_space());
+	for y in 0 .. height {
+		let taps_y = box_taps(src_height, y);
+		for x in 0 .. width {
+			let taps_x = box_taps(src_width, x);
+			let mut sum = image::ColorF::new(0f32, 0f32, 0f32, 0f32);
+			for &(sy, wy) in taps_y.iter().filter(|tap| tap.1 > 0f32) {
+				for &(sx, wx) in taps_x.iter().filter(|tap| tap.1 > 0f32) {
+					let c = src.get_f(sx, sy);
+					let w = wx * wy;
+					sum = image::ColorF::new(sum.r + c.r * w, sum.g + c.g * w, sum.b + c.b * w, sum.a + c.a * w);
+				}
+			}
+			dst.set_f(x, y, &sum);
+		}
+	}
+	dst
+}
+
+// Source texels and their weights for destination texel x, in one dimension.
+// Sizes of 2n + 1 are reduced to n with 3 taps at 2x, 2x + 1 and 2x + 2.
+fn box_taps(src_size: usize, x: usize) -> [(usize, f32); 3] {
+	if src_size == 1 {
+		return [(0, 1f32), (0, 0f32), (0, 0f32)];
+	}
+	if src_size.is_multiple_of(2) {
+		return [(x * 2, 0.5f32), (x * 2 + 1, 0.5f32), (0, 0f32)];
+	}
+	let n = (src_size / 2) as f32;
+	let size = src_size as f32;
+	let x0 = x as f32;
+	[(x * 2, (n - x0) / size), (x * 2 + 1, n / size), (x * 2 + 2, (x0 + 1f32) / size)]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn mean(image: &image::Image) -> f32 {
+		let mut sum = 0f32;
+		for y in 0 .. image.get_height() {
+			for x in 0 .. image.get_width() {
+				sum += image.get_f(x, y).r;
+			}
+		}
+		sum / (image.get_width() * image.get_height()) as f32
+	}
+
+	#[test]
+	fn odd_sizes_keep_the_mean() {
+		for &(width, height) in [(5, 5), (7, 4), (1, 9), (3, 1)].iter() {
+			let mut image = image::Image::new(width, height, image::Format::RgbaF32);
+			for y in 0 .. height {
+				for x in 0 .. width {
+					image.set_f(x, y, &image::ColorF::new((x * x + y * 3) as f32, 0f32, 0f32, 1f32));
+				}
+			}
+			let texture = Texture::new(image);
+			let expected = mean(&texture.levels[0]);
+			for level in &texture.levels[1 ..] {
+				assert!((mean(level) - expected).abs() < 1e-4f32 * expected.max(1f32));
+			}
+			let last = texture.levels.last().unwrap();
+			assert_eq!((last.get_width(), last.get_height()), (1, 1));
+		}
+	}
+
+	#[test]
+	fn wrap() {
+		let size = 4;
+		let xs = [-size - 1, -1, 0, size - 1, size, 2 * size];
+		let expected = [
+			(Wrap::Repeat, [Some(3), Some(3), Some(0), Some(3), Some(0), Some(0)]),
+			(Wrap::MirroredRepeat, [Some(3), Some(0), Some(0), Some(3), Some(3), Some(0)]),
+			(Wrap::ClampToEdge, [Some(0), Some(0), Some(0), Some(3), Some(3), Some(3)]),
+			(Wrap::ClampToBorder, [None, None, Some(0), Some(3), None, None]),
+		];
+		for &(wrap, ref expected) in expected.iter() {
+			for (x, e) in xs.iter().zip(expected.iter()) {
+				assert_eq!(wrap.apply(*x, size), *e, "{:?} at {}", wrap, x);
+			}
+		}
+	}
+
+	#[test]
+	fn clamp_to_border() {
+		let mut image = image::Image::new(2, 2, image::Format::RgbaF32);
+		for y in 0 .. 2 {
+			for x in 0 .. 2 {
+				image.set_f(x, y, &image::ColorF::new(1f32, 1f32, 1f32, 1f32));
+			}
+		}
+		let mut texture = Texture::new(image);
+		let border = image::ColorF::new(1f32, 0f32, 0f32, 0.5f32);
+		texture.sampler = Sampler {
+			filter: Filter::Nearest,
+			wrap_u: Wrap::ClampToBorder,
+			wrap_v: Wrap::ClampToBorder,
+			border,
+		};
+		assert_eq!(texture.sample(0.25f64, 0.75f64, 0f64), image::ColorF::new(1f32, 1f32, 1f32, 1f32));
+		for &(u, v) in [(-0.25f64, 0.5f64), (1.25f64, 0.5f64), (0.5f64, -0.01f64), (0.5f64, 1f64)].iter() {
+			assert_eq!(texture.sample(u, v, 0f64), border, "({}, {})", u, v);
+		}
+		// bilinear filtering blends the border into the edge texels
+		texture.sampler.filter = Filter::Bilinear;
+		let c = texture.sample(0f64, 0.5f64, 0f64);
+		assert_eq!((c.r, c.g, c.a), (1f32, 0.5f32, 0.75f32));
+	}
+
+	#[test]
+	fn empty() {
+		let texture = Texture::new(image::Image::new(0, 4, image::Format::RgbaF32));
+		assert_eq!(texture.levels.len(), 1);
+		let color = texture.sample(0.5f64, 0.5f64, 0f64);
+		assert!(!color.r.is_nan());
+	}
+}
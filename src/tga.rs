@@ -11,14 +11,14 @@ struct Le16 {
 
 impl From<Le16> for u16 {
 	fn from(le16: Le16) -> Self {
-		u16::from_le(unsafe { mem::transmute(le16.buf) })
+		u16::from_le_bytes(le16.buf)
 	}
 }
 
 impl From<u16> for Le16 {
 	fn from(n: u16) -> Self {
 		Le16 {
-			buf: unsafe { mem::transmute(n.to_le()) }
+			buf: n.to_le_bytes()
 		}
 	}
 }
@@ -60,12 +60,12 @@ impl TgaHeader {
 	}
 }
 
-const TGA_FOOTER: &'static [u8] = b"TRUEVISION-XFILE.\0";
+const TGA_FOOTER: &[u8] = b"TRUEVISION-XFILE.\0";
 
 pub fn read(path: &path::Path) -> io::Result<image::Image> {
-	let mut file = io::BufReader::new(try!(fs::File::open(path)));
+	let mut file = io::BufReader::new(fs::File::open(path)?);
 	let mut header = TgaHeader::default();
-	try!(file.read_exact(header.as_u8_slice_mut()));
+	file.read_exact(header.as_u8_slice_mut())?;
 	let width = u16::from(header.width) as usize;
 	let height = u16::from(header.height) as usize;
 	let (format, rle) = match (header.image_type, header.bits_per_pixel) {
@@ -75,16 +75,16 @@ pub fn read(path: &path::Path) -> io::Result<image::Image> {
 		(10, 24) => (image::Format::Rgb, true),
 		(10, 32) => (image::Format::Rgba, true),
 		(11, 8) => (image::Format::Gray, true),
-		_ => return Err(io::Error::new(io::ErrorKind::Other, "invalid image_type")),
+		_ => return Err(io::Error::other("invalid image_type")),
 	};
 
 	let nbytes = width * height * format.bytes_per_pixel();
 	// FIXME: avoid zero init of data
 	let mut data = vec![0; nbytes];
 	if rle {
-		try!(read_rle(&mut file, &mut data, format));
+		read_rle(&mut file, &mut data, format)?;
 	} else {
-		try!(file.read_exact(&mut data));
+		file.read_exact(&mut data)?;
 	}
 
 	let mut image = image::Image::from_data(width, height, format, data);
@@ -97,25 +97,25 @@ pub fn read(path: &path::Path) -> io::Result<image::Image> {
 	Ok(image)
 }
 
-fn read_rle(file: &mut io::Read, data: &mut Vec<u8>, format: image::Format) -> io::Result<()> {
+fn read_rle(file: &mut dyn io::Read, data: &mut [u8], format: image::Format) -> io::Result<()> {
 	let bytes_per_pixel = format.bytes_per_pixel();
 	let mut color = vec![0; bytes_per_pixel];
 	let num_pixels = data.len() / bytes_per_pixel;
 	let mut start_pixel = 0;
 	while start_pixel < num_pixels {
 		let mut code = [0; 1];
-		try!(file.read_exact(&mut code));
+		file.read_exact(&mut code)?;
 		let run_length = (code[0] & !0x80) as usize + 1;
 		let next_pixel = start_pixel + run_length;
 		if next_pixel > num_pixels {
-			return Err(io::Error::new(io::ErrorKind::Other, "invalid rle length"));
+			return Err(io::Error::other("invalid rle length"));
 		}
 		if code[0] & 0x80 == 0 {
-			try!(file.read_exact(&mut data[start_pixel * bytes_per_pixel..][..run_length * bytes_per_pixel]));
+			file.read_exact(&mut data[start_pixel * bytes_per_pixel..][..run_length * bytes_per_pixel])?;
 			start_pixel = next_pixel;
 		} else {
 			// FIXME: read directly into data
-			try!(file.read_exact(&mut color));
+			file.read_exact(&mut color)?;
 			while start_pixel < next_pixel {
 				data[start_pixel * bytes_per_pixel..][..bytes_per_pixel].clone_from_slice(&color);
 				start_pixel += 1;
@@ -126,10 +126,10 @@ fn read_rle(file: &mut io::Read, data: &mut Vec<u8>, format: image::Format) -> i
 }
 
 pub fn write(image: &image::Image, path: &path::Path, rle: bool) -> io::Result<()> {
-	let mut file = io::BufWriter::new(try!(fs::File::create(path)));
+	let mut file = io::BufWriter::new(fs::File::create(path)?);
 	let format = image.get_format();
 	let width = image.get_width();
-	let height = image.get_width();
+	let height = image.get_height();
 	let image_type = match (format, rle) {
 		(image::Format::Rgb, false) => 2,
 		(image::Format::Rgba, false) => 2,
@@ -137,31 +137,32 @@ pub fn write(image: &image::Image, path: &path::Path, rle: bool) -> io::Result<(
 		(image::Format::Rgb, true) => 10,
 		(image::Format::Rgba, true) => 10,
 		(image::Format::Gray, true) => 11,
+		(image::Format::RgbaF32, _) => return Err(io::Error::other("unsupported format")),
 	};
 
 	let header = TgaHeader {
-		image_type: image_type,
+		image_type,
 		width: From::from(width as u16),
 		height: From::from(height as u16),
 		bits_per_pixel: format.bytes_per_pixel() as u8 * 8,
 		image_descriptor: format.alpha_bytes_per_pixel() as u8 * 8,
 		.. Default::default()
 	};
-	try!(file.write_all(header.as_u8_slice()));
+	file.write_all(header.as_u8_slice())?;
 
 	if rle {
-		try!(write_rle(&mut file, image.get_data(), format));
+		write_rle(&mut file, image.get_data(), format)?;
 	} else {
-		try!(file.write_all(&image.get_data()));
+		file.write_all(image.get_data())?;
 	}
 
-	try!(file.write_all(&[0; 4])); // developer area offset
-	try!(file.write_all(&[0; 4])); // extension area offset
-	try!(file.write_all(TGA_FOOTER));
+	file.write_all(&[0; 4])?; // developer area offset
+	file.write_all(&[0; 4])?; // extension area offset
+	file.write_all(TGA_FOOTER)?;
 	Ok(())
 }
 
-fn write_rle(file: &mut io::Write, data: &[u8], format: image::Format) -> io::Result<()> {
+fn write_rle(file: &mut dyn io::Write, data: &[u8], format: image::Format) -> io::Result<()> {
 	let bytes_per_pixel = format.bytes_per_pixel();
 	let num_pixels = data.len() / bytes_per_pixel;
 	let mut start_pixel = 0;
@@ -188,11 +189,11 @@ fn write_rle(file: &mut io::Write, data: &[u8], format: image::Format) -> io::Re
 			cur_color = next_color;
 		}
 		if raw {
-			try!(file.write_all(&[(run_length-1) as u8]));
-			try!(file.write_all(&data[start_pixel * bytes_per_pixel..][..run_length * bytes_per_pixel]));
+			file.write_all(&[(run_length-1) as u8])?;
+			file.write_all(&data[start_pixel * bytes_per_pixel..][..run_length * bytes_per_pixel])?;
 		} else {
-			try!(file.write_all(&[(run_length+127) as u8]));
-			try!(file.write_all(cur_color));
+			file.write_all(&[(run_length+127) as u8])?;
+			file.write_all(cur_color)?;
 		}
 		start_pixel = next_pixel;
 	}
@@ -0,0 +1,119 @@
+use image;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+	Clamp,
+	Reinhard,
+	Aces,
+	Exposure,
+}
+
+impl Operator {
+	pub fn from_name(name: &str) -> Option<Operator> {
+		match name {
+			"clamp" => Some(Operator::Clamp),
+			"reinhard" => Some(Operator::Reinhard),
+			"aces" => Some(Operator::Aces),
+			"exposure" => Some(Operator::Exposure),
+			_ => None,
+		}
+	}
+
+	fn apply(&self, x: f32) -> f32 {
+		let x = x.max(0f32);
+		match *self {
+			Operator::Clamp => x,
+			Operator::Reinhard => x / (1f32 + x),
+			// Narkowicz's fit of the ACES filmic curve
+			Operator::Aces => (x * (2.51f32 * x + 0.03f32)) / (x * (2.43f32 * x + 0.59f32) + 0.14f32),
+			Operator::Exposure => 1f32 - (-x).exp(),
+		}
+	}
+}
+
+// Converts a HDR image to a displayable image.
+// The exposure scales the color before the operator is applied.
+#[derive(Clone, Copy, Debug)]
+pub struct ToneMap {
+	pub operator: Operator,
+	pub exposure: f32,
+}
+
+impl Default for ToneMap {
+	fn default() -> Self {
+		ToneMap {
+			operator: Operator::Clamp,
+			exposure: 1f32,
+		}
+	}
+}
+
+impl ToneMap {
+	pub fn map(&self, color: &image::ColorF) -> image::ColorF {
+		color.map(|x| { self.operator.apply(x * self.exposure) })
+	}
+
+	pub fn resolve(&self, src: &image::Image, format: image::Format) -> image::Image {
+		let width = src.get_width();
+		let height = src.get_height();
+		let mut dst = image::Image::new(width, height, format);
+		for y in 0 .. height {
+			for x in 0 .. width {
+				dst.set_f(x, y, &self.map(&src.get_f(x, y)));
+			}
+		}
+		dst
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn close(a: f32, b: f32) -> bool {
+		(a - b).abs() < 1e-4f32
+	}
+
+	#[test]
+	fn operators() {
+		for &operator in [Operator::Clamp, Operator::Reinhard, Operator::Aces, Operator::Exposure].iter() {
+			assert!(close(operator.apply(0f32), 0f32), "{:?}", operator);
+			// negative values are black
+			assert!(close(operator.apply(-1f32), 0f32), "{:?}", operator);
+		}
+		assert!(close(Operator::Clamp.apply(1f32), 1f32));
+		assert!(close(Operator::Reinhard.apply(1f32), 0.5f32));
+		assert!(close(Operator::Aces.apply(1f32), 2.54f32 / 3.16f32));
+		assert!(close(Operator::Exposure.apply(1f32), 1f32 - (-1f32).exp()));
+	}
+
+	#[test]
+	fn operators_at_large_values() {
+		// clamping is left to the output format
+		assert!(close(Operator::Clamp.apply(1e6f32), 1e6f32));
+		assert!(close(Operator::Reinhard.apply(1e6f32), 1f32));
+		// the ACES fit levels out slightly above one
+		assert!(close(Operator::Aces.apply(1e6f32), 2.51f32 / 2.43f32));
+		assert!(close(Operator::Exposure.apply(1e6f32), 1f32));
+	}
+
+	#[test]
+	fn exposure_scales_before_the_operator() {
+		let tonemap = ToneMap { operator: Operator::Reinhard, exposure: 2f32 };
+		let c = tonemap.map(&image::ColorF::new(0.5f32, 0f32, 1.5f32, 1f32));
+		assert!(close(c.r, 0.5f32) && close(c.g, 0f32) && close(c.b, 0.75f32));
+	}
+
+	#[test]
+	fn resolve_clamps_to_the_format() {
+		let mut src = image::Image::new(2, 1, image::Format::RgbaF32);
+		src.set_f(0, 0, &image::ColorF::new(8f32, 0.5f32, 0f32, 1f32));
+		src.set_f(1, 0, &image::ColorF::new(1f32, 1f32, 1f32, 1f32));
+		let dst = ToneMap::default().resolve(&src, image::Format::Rgb);
+		let c = dst.get_f(0, 0);
+		assert_eq!((c.r, c.b), (1f32, 0f32));
+		assert!((c.g - 0.5f32).abs() < 1f32 / 255f32);
+		let c = dst.get_f(1, 0);
+		assert_eq!((c.r, c.g, c.b), (1f32, 1f32, 1f32));
+	}
+}
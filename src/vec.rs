@@ -39,6 +39,10 @@ impl<T> Vec3<T> where T: Copy + ops::Add<T, Output = T> + ops::Sub<T, Output = T
 		Vec3(vecmath::vec3_scale(self.0, n))
 	}
 
+	pub fn add(&self, v: &Vec3<T>) -> Self {
+		Vec3(vecmath::vec3_add(self.0, v.0))
+	}
+
 	pub fn sub(&self, v: &Vec3<T>) -> Self {
 		Vec3(vecmath::vec3_sub(self.0, v.0))
 	}
@@ -136,10 +140,6 @@ impl Mat3<f64> {
 		self.0[i] = v.0;
 	}
 
-	pub fn interpolate(&self, v: &Vec3<f64>) -> Vec3<f64> {
-		Vec3(vecmath::col_mat3_transform(self.0, v.0))
-	}
-
 	pub fn inv(&self) -> Self {
 		Mat3(vecmath::mat3_inv(self.0))
 	}
@@ -155,6 +155,7 @@ impl Default for Transform3<f64> {
 }
 
 impl Transform3<f64> {
+	#[allow(clippy::needless_range_loop)]
 	pub fn rotate(x: &Vec3<f64>, y: &Vec3<f64>, z: &Vec3<f64>) -> Self {
 		let mut rotate = vecmath::mat3_id();
 		for i in 0..3 {
@@ -176,6 +177,7 @@ impl Default for Transform4<f64> {
 }
 
 impl Transform4<f64> {
+	#[allow(clippy::needless_range_loop)]
 	pub fn rotate(x: &Vec3<f64>, y: &Vec3<f64>, z: &Vec3<f64>) -> Self {
 		let mut rotate = vecmath::mat4_id();
 		for i in 0..3 {
@@ -190,6 +192,10 @@ impl Transform4<f64> {
 		Transform4(vecmath::row_mat4_mul(self.0, mat.0))
 	}
 
+	pub fn inverse(&self) -> Self {
+		Transform4(vecmath::mat4_inv(self.0))
+	}
+
 	pub fn inverse_transpose(&self) -> Self {
 		Transform4(vecmath::mat4_inv(vecmath::mat4_transposed(self.0)))
 	}
@@ -212,6 +218,18 @@ pub fn project(eye: &Vec3<f64>, center: &Vec3<f64>) -> Transform4<f64> {
 	Transform4(mat)
 }
 
+// Map the box from min to max onto [-1, 1] in each axis
+#[allow(clippy::needless_range_loop)]
+pub fn orthographic(min: &Vec3<f64>, max: &Vec3<f64>) -> Transform4<f64> {
+	let mut mat = vecmath::mat4_id();
+	for i in 0..3 {
+		let size = max.0[i] - min.0[i];
+		mat[i][i] = 2f64 / size;
+		mat[i][3] = -(max.0[i] + min.0[i]) / size;
+	}
+	Transform4(mat)
+}
+
 pub fn lookat(eye: &Vec3<f64>, center: &Vec3<f64>, up: &Vec3<f64>) -> Transform4<f64> {
 	let mut translate = vecmath::mat4_id();
 	translate[0][3] = -center.0[0];